@@ -0,0 +1,114 @@
+use std::fs;
+use std::io::{self, BufRead, Write};
+
+use glacier_vm::convert::IntoValue;
+use glacier_vm::datamodel::{NativeFunction, Value};
+use glacier_vm::machine::VmContext;
+use glacier_vm::native::NativeModule;
+use glacier_vm::VmError;
+
+/// Replaces the process' stdin and stdout for `print` and `read_line` when
+/// stored as host data on the `Vm`.
+pub struct Stdio {
+    pub input: Box<dyn BufRead>,
+    pub output: Box<dyn Write>,
+}
+
+pub fn module() -> NativeModule {
+    let mut m = NativeModule::new();
+    m.insert("print", Value::NativeFunction(NativeFunction::new(print)));
+    m.insert("read_line", Value::NativeFunction(NativeFunction::new(read_line)));
+    m.register("read_file", |path: &str| fs::read_to_string(path).map_err(error));
+    m.register("read_bytes", |path: &str| -> Result<Value, String> {
+        Ok(fs::read(path).map_err(error)?.as_slice().into_value())
+    });
+    m.register("write_file", |path: &str, contents: &str| fs::write(path, contents).map_err(error));
+    m.register("exists", |path: &str| fs::metadata(path).is_ok());
+    m
+}
+
+fn error(e: io::Error) -> String {
+    e.to_string()
+}
+
+fn print(ctx: &mut VmContext, args: &[Value]) -> Result<Value, VmError> {
+    if args.len() != 1 {
+        return Err(VmError::Arity(1, args.len()));
+    }
+    let result = match ctx.get_host_data_mut::<Stdio>() {
+        Some(stdio) => writeln!(stdio.output, "{}", args[0]),
+        None => writeln!(io::stdout(), "{}", args[0]),
+    };
+    result.map_err(error)?;
+    Ok(Value::None)
+}
+
+/// Returns the next line without its line ending, or none at end of input.
+fn read_line(ctx: &mut VmContext, args: &[Value]) -> Result<Value, VmError> {
+    if !args.is_empty() {
+        return Err(VmError::Arity(0, args.len()));
+    }
+    let mut line = String::new();
+    let read = match ctx.get_host_data_mut::<Stdio>() {
+        Some(stdio) => stdio.input.read_line(&mut line),
+        None => io::stdin().lock().read_line(&mut line),
+    };
+    if read.map_err(error)? == 0 {
+        return Ok(Value::None);
+    }
+    let len = line.trim_end_matches(&['\r', '\n'][..]).len();
+    line.truncate(len);
+    Ok(ctx.new_string(&line))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::env;
+    use std::io::Cursor;
+    use std::process;
+    use std::rc::Rc;
+
+    use glacier_vm::convert::IntoValue;
+    use glacier_vm::datamodel::{Bytes, Value};
+    use glacier_vm::machine::Vm;
+
+    use super::{module, Stdio};
+    use crate::test_util::{call, Shared};
+
+    #[test]
+    fn stdio() {
+        let m = module();
+        let output = Rc::new(RefCell::new(vec![]));
+        let mut vm = Vm::new();
+        vm.set_host_data(Stdio {
+            input: Box::new(Cursor::new("first\r\nsecond")),
+            output: Box::new(Shared(output.clone())),
+        });
+        let print = m.get_list().get(m.index_of("print").unwrap()).unwrap();
+        let read_line = m.get_list().get(m.index_of("read_line").unwrap()).unwrap();
+        for expected in &["first", "second"] {
+            let line = vm.call(&read_line, &[]).unwrap();
+            assert!(matches!(&line, Value::StringValue(s) if s.as_str() == *expected));
+            vm.call(&print, &[line]).unwrap();
+        }
+        assert!(matches!(vm.call(&read_line, &[]), Ok(Value::None)));
+        vm.call(&print, &[vec![1.5f64].into_value()]).unwrap();
+        assert_eq!(&output.borrow()[..], b"first\nsecond\n[1.5]\n");
+    }
+
+    #[test]
+    fn files() {
+        let m = module();
+        let file = env::temp_dir().join(format!("glacier-std-io-{}", process::id()));
+        let path = file.to_str().unwrap().into_value();
+        call(&m, "write_file", &[path.clone(), "héllo".into_value()]).unwrap();
+        assert!(matches!(call(&m, "exists", std::slice::from_ref(&path)), Ok(Value::Bool(true))));
+        let text = call(&m, "read_file", std::slice::from_ref(&path)).unwrap();
+        assert!(matches!(text, Value::StringValue(s) if s.as_str() == "héllo"));
+        let bytes = call(&m, "read_bytes", std::slice::from_ref(&path)).unwrap();
+        assert!(matches!(bytes, Value::Bytes(Bytes(b)) if b.len() == 6));
+        std::fs::remove_file(&file).unwrap();
+        assert!(call(&m, "read_file", &[path]).is_err());
+    }
+}
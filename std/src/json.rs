@@ -0,0 +1,43 @@
+use glacier_vm::datamodel::Value;
+use glacier_vm::json::{self, JsonFormat};
+use glacier_vm::native::NativeModule;
+use glacier_vm::VmError;
+
+pub fn module() -> NativeModule {
+    let mut m = NativeModule::new();
+    m.register("encode", |value: Value| json::encode(&value, &JsonFormat::default()));
+    m.register("encode_pretty", encode_pretty);
+    m.register("decode", |text: &str| json::decode(text));
+    m
+}
+
+/// Widest indent `encode_pretty` uses, so guest code cannot ask for
+/// gigabytes of spaces.
+const MAX_INDENT: i64 = 16;
+
+fn encode_pretty(value: Value, indent: i64) -> Result<String, VmError> {
+    let format = JsonFormat { indent: Some(indent.clamp(0, MAX_INDENT) as usize), ..JsonFormat::default() };
+    json::encode(&value, &format)
+}
+
+#[cfg(test)]
+mod tests {
+    use glacier_vm::datamodel::Value;
+    use glacier_vm::VmError;
+
+    use super::module;
+    use crate::test_util::{call, string};
+
+    #[test]
+    fn encode_and_decode() {
+        let m = module();
+        let value = call(&m, "decode", &[string(r#"{"a": [1, 2.5]}"#)]).unwrap();
+        assert_eq!(call(&m, "encode", std::slice::from_ref(&value)).unwrap().to_string(), r#"{"a":[1,2.5]}"#);
+        let pretty = call(&m, "encode_pretty", &[value, Value::Integer(1)]).unwrap();
+        assert_eq!(pretty.to_string(), "{\n \"a\": [\n  1,\n  2.5\n ]\n}");
+        let list = call(&m, "decode", &[string("[1]")]).unwrap();
+        let wide = call(&m, "encode_pretty", &[list, Value::Integer(i64::MAX)]).unwrap();
+        assert_eq!(wide.to_string(), format!("[\n{}1\n]", " ".repeat(16)));
+        assert!(matches!(call(&m, "decode", &[string("[1,,]")]), Err(VmError::Decode(3))));
+    }
+}
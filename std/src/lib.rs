@@ -0,0 +1,57 @@
+pub mod io;
+pub mod json;
+pub mod list;
+pub mod math;
+pub mod serial;
+pub mod string;
+#[cfg(test)]
+pub(crate) mod test_util;
+pub mod time;
+
+use glacier_vm::datamodel::Value;
+use glacier_vm::native::NativeModule;
+
+/// Installs every standard module into `root` as a nested module `List`
+/// named after it. Each module's `module()` gives the same slot layout, so
+/// it can be used to resolve names inside the installed lists.
+pub fn install(root: &mut NativeModule) {
+    root.insert("io", Value::List(io::module().get_list().clone()));
+    root.insert("json", Value::List(json::module().get_list().clone()));
+    root.insert("list", Value::List(list::module().get_list().clone()));
+    root.insert("math", Value::List(math::module().get_list().clone()));
+    root.insert("serial", Value::List(serial::module().get_list().clone()));
+    root.insert("string", Value::List(string::module().get_list().clone()));
+    root.insert("time", Value::List(time::module().get_list().clone()));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use glacier_vm::datamodel::{Bytes, Function, Value};
+    use glacier_vm::machine::Vm;
+    use glacier_vm::native::NativeModule;
+    use glacier_vm::operation::{assemble, Operation};
+
+    #[test]
+    fn guest_reaches_installed_module() {
+        let mut root = NativeModule::new();
+        super::install(&mut root);
+        let math = root.index_of("math").unwrap() as i64;
+        let max = super::math::module().index_of("max").unwrap() as i64;
+        let bytecode = assemble(&[
+            Operation::LiteralInteger(3),
+            Operation::LiteralInteger(7),
+            Operation::FrameLocalLoad(0),
+            Operation::LiteralInteger(math),
+            Operation::SeqGet,
+            Operation::LiteralInteger(max),
+            Operation::SeqGet,
+            Operation::Call(2),
+            Operation::Return,
+        ]).unwrap();
+        let main = Function::new(root.get_list().clone(), Bytes::from_vec(bytecode));
+        let out = Vm::new().call(&Value::Function(Rc::new(main)), &[]);
+        assert!(matches!(out, Ok(Value::Integer(7))));
+    }
+}
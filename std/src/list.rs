@@ -0,0 +1,142 @@
+use std::cmp::Ordering;
+
+use glacier_vm::convert::FromValue;
+use glacier_vm::datamodel::{List, NativeFunction, Value};
+use glacier_vm::machine::VmContext;
+use glacier_vm::native::NativeModule;
+use glacier_vm::VmError;
+
+pub fn module() -> NativeModule {
+    let mut m = NativeModule::new();
+    m.register("sort", sort);
+    m.register("reverse", |list: List| list.0.borrow_mut().reverse());
+    m.insert("map", Value::NativeFunction(NativeFunction::new(map)));
+    m.insert("filter", Value::NativeFunction(NativeFunction::new(filter)));
+    m
+}
+
+/// Sorts in place, keeping equal items in order. Fails on the first pair of
+/// values that cannot be compared, leaving the list as it was.
+fn sort(list: List) -> Result<(), VmError> {
+    let items = list.0.borrow().clone();
+    let sorted = merge_sort(items)?;
+    *list.0.borrow_mut() = sorted;
+    Ok(())
+}
+
+// `sort_by` needs a total order and panics on some inconsistent ones, which
+// incomparable values such as NaN would give it
+fn merge_sort(mut items: Vec<Value>) -> Result<Vec<Value>, VmError> {
+    if items.len() < 2 {
+        return Ok(items);
+    }
+    let right = merge_sort(items.split_off(items.len() / 2))?;
+    let left = merge_sort(items)?;
+    let mut out = Vec::with_capacity(left.len() + right.len());
+    let mut left = left.into_iter().peekable();
+    let mut right = right.into_iter().peekable();
+    while let (Some(a), Some(b)) = (left.peek(), right.peek()) {
+        match a.cmp(b) {
+            Some(Ordering::Greater) => out.push(right.next().unwrap()),
+            Some(_) => out.push(left.next().unwrap()),
+            None => return Err(VmError::Type(b.get_type(), 0)),
+        }
+    }
+    out.extend(left);
+    out.extend(right);
+    Ok(out)
+}
+
+// the callback may touch the list, so it runs over a copy of the items
+fn list_and_callback(args: &[Value]) -> Result<(Vec<Value>, &Value), VmError> {
+    if args.len() != 2 {
+        return Err(VmError::Arity(2, args.len()));
+    }
+    let list = List::from_value(&args[0]).map_err(|e| e.at(0))?;
+    let items = list.0.borrow().clone();
+    Ok((items, &args[1]))
+}
+
+fn map(ctx: &mut VmContext, args: &[Value]) -> Result<Value, VmError> {
+    let (items, f) = list_and_callback(args)?;
+    let mut out = Vec::with_capacity(items.len());
+    for item in items {
+        out.push(ctx.call(f, &[item])?);
+    }
+    Ok(ctx.new_list(out))
+}
+
+fn filter(ctx: &mut VmContext, args: &[Value]) -> Result<Value, VmError> {
+    let (items, f) = list_and_callback(args)?;
+    let mut out = vec![];
+    for item in items {
+        match ctx.call(f, std::slice::from_ref(&item))? {
+            Value::Bool(true) => out.push(item),
+            Value::Bool(false) => (),
+            e => return Err(VmError::Type(e.get_type(), 1)),
+        }
+    }
+    Ok(ctx.new_list(out))
+}
+
+#[cfg(test)]
+mod tests {
+    use glacier_vm::convert::{FromValue, IntoValue};
+    use glacier_vm::datamodel::{List, NativeFunction, Value};
+    use glacier_vm::native::IntoNative;
+    use glacier_vm::VmError;
+
+    use super::module;
+    use crate::test_util::call;
+
+    fn ints(v: &Value) -> Vec<i64> {
+        Vec::<i64>::from_value(v).unwrap()
+    }
+
+    #[test]
+    fn in_place() {
+        let m = module();
+        let list = vec![3, 1, 2].into_value();
+        call(&m, "sort", std::slice::from_ref(&list)).unwrap();
+        assert_eq!(ints(&list), [1, 2, 3]);
+        call(&m, "reverse", std::slice::from_ref(&list)).unwrap();
+        assert_eq!(ints(&list), [3, 2, 1]);
+
+        let mixed = Value::List(List::from_vec(vec![Value::Integer(1), Value::Bool(true)]));
+        assert!(matches!(call(&m, "sort", &[mixed]), Err(VmError::Type(..))));
+
+        // long enough for the std sort to notice a comparison that is not
+        // a total order
+        let mut reals: Vec<_> = (0..21).map(|i| Value::Real((i * 7 % 21) as f64)).collect();
+        reals[10] = Value::Real(f64::NAN);
+        let list = Value::List(List::from_vec(reals));
+        let before = list.to_string();
+        assert!(matches!(call(&m, "sort", std::slice::from_ref(&list)), Err(VmError::Type(..))));
+        assert_eq!(list.to_string(), before);
+    }
+
+    #[test]
+    fn callbacks() {
+        let m = module();
+        let list = vec![1, 2, 3, 4].into_value();
+        let square = Value::NativeFunction((|x: i64| x * x).into_native());
+        let out = call(&m, "map", &[list.clone(), square]).unwrap();
+        assert_eq!(ints(&out), [1, 4, 9, 16]);
+
+        let even = Value::NativeFunction((|x: i64| x % 2 == 0).into_native());
+        let out = call(&m, "filter", &[list.clone(), even]).unwrap();
+        assert_eq!(ints(&out), [2, 4]);
+
+        // callbacks may mutate the list being mapped
+        let target = match &list {
+            Value::List(l) => l.clone(),
+            _ => unreachable!(),
+        };
+        let push = Value::NativeFunction(NativeFunction::new(move |_, args| {
+            target.push(args[0].clone());
+            Ok(Value::None)
+        }));
+        call(&m, "map", &[list.clone(), push]).unwrap();
+        assert_eq!(ints(&list).len(), 8);
+    }
+}
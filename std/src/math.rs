@@ -0,0 +1,82 @@
+use std::cmp::Ordering;
+use std::f64::consts;
+
+use glacier_vm::datamodel::Value;
+use glacier_vm::native::NativeModule;
+use glacier_vm::VmError;
+
+pub fn module() -> NativeModule {
+    let mut m = NativeModule::new();
+    m.insert("pi", Value::Real(consts::PI));
+    m.insert("e", Value::Real(consts::E));
+    m.register("sqrt", f64::sqrt);
+    m.register("pow", f64::powf);
+    m.register("exp", f64::exp);
+    m.register("ln", f64::ln);
+    m.register("sin", f64::sin);
+    m.register("cos", f64::cos);
+    m.register("tan", f64::tan);
+    m.register("atan2", f64::atan2);
+    m.register("floor", f64::floor);
+    m.register("ceil", f64::ceil);
+    m.register("round", f64::round);
+    m.register("abs", abs);
+    m.register("min", min);
+    m.register("max", max);
+    m
+}
+
+fn abs(t: Value) -> Result<Value, VmError> {
+    match t {
+        Value::Integer(t) => Ok(Value::Integer(t.wrapping_abs())),
+        Value::Real(t) => Ok(Value::Real(t.abs())),
+        e => Err(VmError::Type(e.get_type(), 0)),
+    }
+}
+
+fn min(lhs: Value, rhs: Value) -> Result<Value, VmError> {
+    match lhs.cmp(&rhs) {
+        Some(Ordering::Greater) => Ok(rhs),
+        Some(_) => Ok(lhs),
+        None => Err(VmError::Type(rhs.get_type(), 1)),
+    }
+}
+
+fn max(lhs: Value, rhs: Value) -> Result<Value, VmError> {
+    match lhs.cmp(&rhs) {
+        Some(Ordering::Less) => Ok(rhs),
+        Some(_) => Ok(lhs),
+        None => Err(VmError::Type(rhs.get_type(), 1)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glacier_vm::datamodel::Value;
+    use glacier_vm::VmError;
+
+    use super::module;
+    use crate::test_util::call;
+
+    fn real(out: Result<Value, VmError>) -> f64 {
+        match out {
+            Ok(Value::Real(r)) => r,
+            _ => panic!("expected a real"),
+        }
+    }
+
+    #[test]
+    fn functions() {
+        let m = module();
+        assert_eq!(real(call(&m, "sqrt", &[Value::Real(9.0)])), 3.0);
+        assert_eq!(real(call(&m, "pow", &[Value::Real(2.0), Value::Real(10.0)])), 1024.0);
+        assert_eq!(real(call(&m, "floor", &[Value::Real(-1.5)])), -2.0);
+        assert_eq!(real(call(&m, "ceil", &[Value::Real(1.2)])), 2.0);
+        assert!(real(call(&m, "sin", &[Value::Real(0.0)])).abs() < 1e-12);
+        assert!(matches!(call(&m, "abs", &[Value::Integer(-4)]), Ok(Value::Integer(4))));
+        assert!(matches!(call(&m, "min", &[Value::Integer(3), Value::Integer(-1)]), Ok(Value::Integer(-1))));
+        assert!(matches!(call(&m, "max", &[Value::Real(0.5), Value::Real(2.5)]), Ok(Value::Real(r)) if r == 2.5));
+        assert!(matches!(call(&m, "max", &[Value::Integer(1), Value::Real(2.5)]), Err(VmError::Type(..))));
+        assert!(call(&m, "sqrt", &[Value::Integer(9)]).is_err());
+    }
+}
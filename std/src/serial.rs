@@ -0,0 +1,41 @@
+use glacier_vm::datamodel::{Bytes, Value};
+use glacier_vm::native::NativeModule;
+use glacier_vm::VmError;
+
+pub fn module() -> NativeModule {
+    let mut m = NativeModule::new();
+    m.register("encode", encode);
+    m.register("decode", decode);
+    m
+}
+
+/// Bytes for any value without functions, natives or host objects in it.
+fn encode(value: Value) -> Result<Value, VmError> {
+    Ok(Value::Bytes(Bytes::from_vec(value.encode()?)))
+}
+
+/// Takes `Bytes` or a `BytesBuffer` made by `encode`.
+fn decode(bytes: Bytes) -> Result<Value, VmError> {
+    Value::decode(&bytes.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use glacier_vm::datamodel::{List, Value, ValueType};
+    use glacier_vm::VmError;
+
+    use super::module;
+    use crate::test_util::{call, string};
+
+    #[test]
+    fn round_trip() {
+        let m = module();
+        let list = Value::List(List::from_vec(vec![string("a"), Value::Real(0.5)]));
+        let bytes = call(&m, "encode", &[list]).unwrap();
+        assert_eq!(call(&m, "decode", &[bytes]).unwrap().to_string(), "[\"a\", 0.5]");
+
+        let native = m.get_list().get(0).unwrap();
+        assert!(matches!(call(&m, "encode", &[native]), Err(VmError::Serialize(ValueType::NativeFunction))));
+        assert!(matches!(call(&m, "decode", &[Value::Integer(1)]), Err(VmError::Convert(_, 0))));
+    }
+}
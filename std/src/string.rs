@@ -0,0 +1,108 @@
+use glacier_vm::datamodel::{Bytes, Value};
+use glacier_vm::encoding::Encoding;
+use glacier_vm::native::NativeModule;
+use glacier_vm::unicode;
+use glacier_vm::VmError;
+
+pub fn module() -> NativeModule {
+    let mut m = NativeModule::new();
+    m.register("split", split);
+    m.register("join", join);
+    m.register("find", find);
+    m.register("replace", replace);
+    m.register("trim", trim);
+    m.register("starts_with", |s: &str, p: &str| s.starts_with(p));
+    m.register("ends_with", |s: &str, p: &str| s.ends_with(p));
+    m.register("to_upper", |s: &str| s.to_uppercase());
+    m.register("to_lower", |s: &str| s.to_lowercase());
+    m.register("char_count", |s: &str| s.chars().count() as i64);
+    m.register("graphemes", graphemes);
+    m.register("grapheme_count", |s: &str| unicode::graphemes(s).len() as i64);
+    m.register("decode", decode);
+    m.register("encode", encode);
+    m
+}
+
+fn split(s: &str, sep: &str) -> Vec<String> {
+    if sep.is_empty() {
+        return s.split_whitespace().map(str::to_string).collect();
+    }
+    s.split(sep).map(str::to_string).collect()
+}
+
+fn join(parts: Vec<String>, sep: &str) -> String {
+    parts.join(sep)
+}
+
+/// Byte offset of the first match, usable with `SEQ_GET` and `SEQ_GET_SLICE`.
+fn find(s: &str, pat: &str) -> Option<i64> {
+    s.find(pat).map(|i| i as i64)
+}
+
+fn graphemes(s: &str) -> Vec<String> {
+    unicode::graphemes(s).into_iter().map(str::to_string).collect()
+}
+
+fn encoding(name: &str) -> Result<Encoding, VmError> {
+    Encoding::from_name(name).ok_or_else(|| VmError::Native(format!("unknown encoding {:?}", name)))
+}
+
+/// Takes `Bytes` or a `BytesBuffer` and an encoding name such as `"utf-16le"`.
+fn decode(bytes: Bytes, name: &str) -> Result<String, VmError> {
+    encoding(name)?.decode(&bytes.0)
+}
+
+fn encode(s: String, name: &str) -> Result<Value, VmError> {
+    Ok(Value::Bytes(Bytes::from_vec(encoding(name)?.encode(&s)?)))
+}
+
+fn replace(s: &str, from: &str, to: &str) -> String {
+    s.replace(from, to)
+}
+
+fn trim(s: &str) -> String {
+    s.trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use glacier_vm::convert::{FromValue, IntoValue};
+    use glacier_vm::datamodel::Value;
+    use glacier_vm::VmError;
+
+    use super::module;
+    use crate::test_util::call;
+
+    fn text(out: Value) -> String {
+        String::from_value(&out).unwrap()
+    }
+
+    #[test]
+    fn functions() {
+        let m = module();
+        let parts = call(&m, "split", &["a,b,,c".into_value(), ",".into_value()]).unwrap();
+        assert_eq!(Vec::<String>::from_value(&parts).unwrap(), ["a", "b", "", "c"]);
+        let words = call(&m, "split", &[" x  y ".into_value(), "".into_value()]).unwrap();
+        assert_eq!(Vec::<String>::from_value(&words).unwrap(), ["x", "y"]);
+        assert_eq!(text(call(&m, "join", &[parts, "-".into_value()]).unwrap()), "a-b--c");
+        assert!(matches!(call(&m, "find", &["héllo".into_value(), "l".into_value()]), Ok(Value::Integer(3))));
+        assert!(matches!(call(&m, "find", &["abc".into_value(), "z".into_value()]), Ok(Value::None)));
+        let out = call(&m, "replace", &["aXbX".into_value(), "X".into_value(), "yy".into_value()]);
+        assert_eq!(text(out.unwrap()), "ayybyy");
+        assert_eq!(text(call(&m, "trim", &["  hi\n".into_value()]).unwrap()), "hi");
+        assert_eq!(text(call(&m, "to_upper", &["abc".into_value()]).unwrap()), "ABC");
+        assert_eq!(text(call(&m, "to_lower", &["ÀB".into_value()]).unwrap()), "àb");
+        assert!(matches!(call(&m, "starts_with", &["abc".into_value(), "ab".into_value()]), Ok(Value::Bool(true))));
+        let flag = "o\u{308}\u{1f1e9}\u{1f1ea}";
+        assert!(matches!(call(&m, "char_count", &[flag.into_value()]), Ok(Value::Integer(4))));
+        assert!(matches!(call(&m, "grapheme_count", &[flag.into_value()]), Ok(Value::Integer(2))));
+        let parts = call(&m, "graphemes", &[flag.into_value()]).unwrap();
+        assert_eq!(Vec::<String>::from_value(&parts).unwrap(), ["o\u{308}", "\u{1f1e9}\u{1f1ea}"]);
+
+        let bytes = call(&m, "encode", &["hé".into_value(), "UTF-16BE".into_value()]).unwrap();
+        assert_eq!(bytes.to_string(), "b\"\\x00h\\x00\\xe9\"");
+        assert_eq!(text(call(&m, "decode", &[bytes.clone(), "utf-16be".into_value()]).unwrap()), "hé");
+        assert!(matches!(call(&m, "decode", &[bytes.clone(), "utf-8".into_value()]), Err(VmError::Decode(3))));
+        assert!(matches!(call(&m, "decode", &[bytes, "ebcdic".into_value()]), Err(VmError::Native(_))));
+    }
+}
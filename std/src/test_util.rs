@@ -0,0 +1,34 @@
+//! Helpers shared by the unit tests of several modules.
+
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use glacier_vm::datamodel::{StringValue, Value};
+use glacier_vm::machine::Vm;
+use glacier_vm::native::NativeModule;
+use glacier_vm::VmError;
+
+/// Calls the function `name` of `module` on a fresh VM.
+pub(crate) fn call(module: &NativeModule, name: &str, args: &[Value]) -> Result<Value, VmError> {
+    let i = module.index_of(name).unwrap();
+    let f = module.get_list().get(i).unwrap();
+    Vm::new().call(&f, args)
+}
+
+pub(crate) fn string(s: &str) -> Value {
+    Value::StringValue(StringValue::from_string(s.to_string()))
+}
+
+/// Writer whose output stays readable through the shared buffer.
+pub(crate) struct Shared(pub Rc<RefCell<Vec<u8>>>);
+
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,53 @@
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use glacier_vm::native::NativeModule;
+
+pub fn module() -> NativeModule {
+    let start = Instant::now();
+    let mut m = NativeModule::new();
+    m.register("now", now);
+    m.register("monotonic", move || start.elapsed().as_secs_f64());
+    m.register("sleep", sleep);
+    m
+}
+
+/// Seconds since the Unix epoch.
+fn now() -> f64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(t) => t.as_secs_f64(),
+        Err(e) => -e.duration().as_secs_f64(),
+    }
+}
+
+fn sleep(seconds: f64) -> Result<(), String> {
+    let t = Duration::try_from_secs_f64(seconds).map_err(|e| e.to_string())?;
+    thread::sleep(t);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use glacier_vm::datamodel::Value;
+
+    use super::module;
+    use crate::test_util::call;
+
+    fn real(out: Value) -> f64 {
+        match out {
+            Value::Real(r) => r,
+            _ => panic!("expected a real"),
+        }
+    }
+
+    #[test]
+    fn clocks() {
+        let m = module();
+        assert!(real(call(&m, "now", &[]).unwrap()) > 1.5e9);
+        let a = real(call(&m, "monotonic", &[]).unwrap());
+        call(&m, "sleep", &[Value::Real(0.01)]).unwrap();
+        let b = real(call(&m, "monotonic", &[]).unwrap());
+        assert!(b - a >= 0.01);
+        assert!(call(&m, "sleep", &[Value::Real(-1.0)]).is_err());
+    }
+}
@@ -0,0 +1,146 @@
+//! Compares the dispatch loop of `CallStack::run` against executing one
+//! instruction per call with `CallStack::step`, which is how `run` used to
+//! work and how the debugger and profiler still drive the VM.
+//!
+//! Run with `cargo bench -p glacier-vm --bench dispatch`.
+
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use glacier_vm::datamodel::{Bytes, Function, List, Value};
+use glacier_vm::machine::{CallStack, Vm};
+use glacier_vm::operation::{assemble, Operation};
+use glacier_vm::optimize::optimize;
+
+fn function(module: List, ops: &[Operation]) -> Rc<Function> {
+    Rc::new(Function::new(module, Bytes::from_vec(assemble(ops).unwrap())))
+}
+
+/// Sums 0..n with arithmetic and jumps only.
+fn sum_loop() -> Vec<Operation> {
+    vec![
+        Operation::LiteralInteger(0),
+        Operation::FrameLocalStore(2),
+        Operation::LiteralInteger(0),
+        Operation::FrameLocalStore(3),
+        // loop: 4
+        Operation::FrameLocalLoad(2),
+        Operation::FrameLocalLoad(1),
+        Operation::Cmp,
+        Operation::JumpNeg(9),
+        Operation::Jump(18),
+        // body: 9
+        Operation::FrameLocalLoad(3),
+        Operation::FrameLocalLoad(2),
+        Operation::Add,
+        Operation::FrameLocalStore(3),
+        Operation::FrameLocalLoad(2),
+        Operation::LiteralInteger(1),
+        Operation::Add,
+        Operation::FrameLocalStore(2),
+        Operation::Jump(4),
+        // done: 18
+        Operation::FrameLocalLoad(3),
+        Operation::Return,
+    ]
+}
+
+/// Pushes 0..n onto a list and returns its length.
+fn list_loop() -> Vec<Operation> {
+    vec![
+        Operation::ListCreate,
+        Operation::FrameLocalStore(2),
+        Operation::LiteralInteger(0),
+        Operation::FrameLocalStore(3),
+        // loop: 4
+        Operation::FrameLocalLoad(3),
+        Operation::FrameLocalLoad(1),
+        Operation::Cmp,
+        Operation::JumpNeg(9),
+        Operation::Jump(17),
+        // body: 9
+        Operation::FrameLocalLoad(2),
+        Operation::FrameLocalLoad(3),
+        Operation::ListPush,
+        Operation::FrameLocalLoad(3),
+        Operation::LiteralInteger(1),
+        Operation::Add,
+        Operation::FrameLocalStore(3),
+        Operation::Jump(4),
+        // done: 17
+        Operation::FrameLocalLoad(2),
+        Operation::SeqLen,
+        Operation::Return,
+    ]
+}
+
+/// Naive recursive Fibonacci, dominated by calls and returns.
+fn fib() -> Rc<Function> {
+    let module = List::from_vec(vec![Value::None]);
+    let f = function(module.clone(), &[
+        Operation::FrameLocalLoad(1),
+        Operation::LiteralInteger(2),
+        Operation::Cmp,
+        Operation::JumpNeg(20),
+        Operation::FrameLocalLoad(1),
+        Operation::LiteralInteger(1),
+        Operation::Sub,
+        Operation::FrameLocalLoad(0),
+        Operation::LiteralInteger(0),
+        Operation::SeqGet,
+        Operation::Call(1),
+        Operation::FrameLocalLoad(1),
+        Operation::LiteralInteger(2),
+        Operation::Sub,
+        Operation::FrameLocalLoad(0),
+        Operation::LiteralInteger(0),
+        Operation::SeqGet,
+        Operation::Call(1),
+        Operation::Add,
+        Operation::Return,
+        // 20
+        Operation::FrameLocalLoad(1),
+        Operation::Return,
+    ]);
+    module.set(0, Value::Function(f.clone()));
+    f
+}
+
+/// Fastest of a few runs, either to completion or one step at a time.
+fn time(f: &Rc<Function>, n: i64, steps: bool) -> Duration {
+    let mut vm = Vm::new();
+    (0..5).map(|_| {
+        let mut stack = CallStack::new(f.clone(), vec![Value::Integer(n)]);
+        let start = Instant::now();
+        if steps {
+            while stack.step(&mut vm).unwrap().is_none() {}
+        } else {
+            stack.run(&mut vm).unwrap();
+        }
+        start.elapsed()
+    }).min().unwrap()
+}
+
+fn compare(name: &str, f: &Rc<Function>, n: i64) {
+    let before = time(f, n, true);
+    let after = time(f, n, false);
+    println!(
+        "{:<12} step: {:>8.2} ms  run: {:>8.2} ms  speedup: {:.2}x",
+        name,
+        before.as_secs_f64() * 1e3,
+        after.as_secs_f64() * 1e3,
+        before.as_secs_f64() / after.as_secs_f64(),
+    );
+}
+
+fn main() {
+    let mut fused = sum_loop();
+    optimize(&mut fused);
+    compare("sum", &function(List::from_vec(vec![]), &sum_loop()), 1_000_000);
+    compare("sum fused", &function(List::from_vec(vec![]), &fused), 1_000_000);
+    compare("list push", &function(List::from_vec(vec![]), &list_loop()), 1_000_000);
+    let fib = fib();
+    compare("fib", &fib, 25);
+    // break the module <-> function cycle
+    fib.module.set(0, Value::None);
+}
@@ -0,0 +1,68 @@
+//! Times a counting loop before and after `optimize` fuses its hot sequences.
+//!
+//! Run with `cargo bench -p glacier-vm --bench superinstructions`.
+
+use std::rc::Rc;
+use std::time::Instant;
+
+use glacier_vm::datamodel::{Bytes, Function, List, Value};
+use glacier_vm::machine::Vm;
+use glacier_vm::operation::{assemble, Operation};
+use glacier_vm::optimize::optimize;
+
+const N: i64 = 2_000_000;
+
+/// Sums 0..n with a `CMP; JUMP_NEG` test and two local updates per step.
+fn sum_loop() -> Vec<Operation> {
+    vec![
+        Operation::LiteralInteger(0),
+        Operation::FrameLocalStore(2),
+        Operation::LiteralInteger(0),
+        Operation::FrameLocalStore(3),
+        // loop: 4
+        Operation::FrameLocalLoad(2),
+        Operation::FrameLocalLoad(1),
+        Operation::Cmp,
+        Operation::JumpNeg(9),
+        Operation::Jump(18),
+        // body: 9
+        Operation::FrameLocalLoad(3),
+        Operation::FrameLocalLoad(2),
+        Operation::Add,
+        Operation::FrameLocalStore(3),
+        Operation::FrameLocalLoad(2),
+        Operation::LiteralInteger(1),
+        Operation::Add,
+        Operation::FrameLocalStore(2),
+        Operation::Jump(4),
+        // done: 18
+        Operation::FrameLocalLoad(3),
+        Operation::Return,
+    ]
+}
+
+fn time(ops: &[Operation]) -> f64 {
+    let code = Bytes::from_vec(assemble(ops).unwrap());
+    let function = Value::Function(Rc::new(Function::new(List::from_vec(vec![]), code)));
+    let start = Instant::now();
+    let out = Vm::new().call(&function, &[Value::Integer(N)]).unwrap();
+    let elapsed = start.elapsed();
+    assert!(matches!(out, Value::Integer(sum) if sum == N * (N - 1) / 2));
+    elapsed.as_nanos() as f64 / N as f64
+}
+
+fn main() {
+    let plain = sum_loop();
+    let mut fused = plain.clone();
+    optimize(&mut fused);
+    println!("{} ops before, {} after", plain.len(), fused.len());
+
+    let (mut before, mut after) = (f64::MAX, f64::MAX);
+    for _ in 0..5 {
+        before = before.min(time(&plain));
+        after = after.min(time(&fused));
+    }
+    println!("plain: {:.2} ns/iter", before);
+    println!("fused: {:.2} ns/iter", after);
+    println!("speedup: {:.2}x", before / after);
+}
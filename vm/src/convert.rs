@@ -0,0 +1,294 @@
+use std::convert::TryFrom;
+
+use crate::datamodel::{Bytes, List, StringValue, Value, ValueType};
+use crate::VmError;
+
+#[derive(Debug, PartialEq)]
+pub enum ConvertError {
+    Type { expected: ValueType, found: ValueType },
+    /// The integer does not fit the requested Rust type.
+    Range(i64),
+    /// A list converted to a tuple had the wrong number of elements.
+    Length { expected: usize, found: usize },
+}
+
+impl ConvertError {
+    fn mismatch(expected: ValueType, found: &Value) -> ConvertError {
+        ConvertError::Type { expected, found: found.get_type() }
+    }
+
+    /// Reports the error against argument `pos`.
+    pub fn at(self, pos: u8) -> VmError {
+        VmError::Convert(self, pos)
+    }
+}
+
+pub trait FromValue: Sized {
+    fn from_value(value: &Value) -> Result<Self, ConvertError>;
+}
+
+pub trait IntoValue {
+    fn into_value(self) -> Value;
+}
+
+impl FromValue for Value {
+    fn from_value(value: &Value) -> Result<Value, ConvertError> {
+        Ok(value.clone())
+    }
+}
+
+impl IntoValue for Value {
+    fn into_value(self) -> Value {
+        self
+    }
+}
+
+impl IntoValue for () {
+    fn into_value(self) -> Value {
+        Value::None
+    }
+}
+
+macro_rules! int_from_value {
+    ($($t:ty),*) => {
+        $(
+            impl FromValue for $t {
+                fn from_value(value: &Value) -> Result<$t, ConvertError> {
+                    match value {
+                        Value::Integer(i) => <$t>::try_from(*i)
+                            .map_err(|_| ConvertError::Range(*i)),
+                        _ => Err(ConvertError::mismatch(ValueType::Integer, value)),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+macro_rules! int_into_value {
+    ($($t:ty),*) => {
+        $(
+            impl IntoValue for $t {
+                fn into_value(self) -> Value {
+                    Value::Integer(self as i64)
+                }
+            }
+        )*
+    };
+}
+
+int_from_value!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+// u64 and usize may not fit an Integer, so only the lossless ones convert back
+int_into_value!(i8, i16, i32, i64, isize, u8, u16, u32);
+
+impl FromValue for f64 {
+    fn from_value(value: &Value) -> Result<f64, ConvertError> {
+        match value {
+            Value::Real(r) => Ok(*r),
+            _ => Err(ConvertError::mismatch(ValueType::Real, value)),
+        }
+    }
+}
+
+impl FromValue for f32 {
+    fn from_value(value: &Value) -> Result<f32, ConvertError> {
+        f64::from_value(value).map(|r| r as f32)
+    }
+}
+
+impl IntoValue for f64 {
+    fn into_value(self) -> Value {
+        Value::Real(self)
+    }
+}
+
+impl IntoValue for f32 {
+    fn into_value(self) -> Value {
+        Value::Real(self as f64)
+    }
+}
+
+impl FromValue for bool {
+    fn from_value(value: &Value) -> Result<bool, ConvertError> {
+        match value {
+            Value::Bool(t) => Ok(*t),
+            _ => Err(ConvertError::mismatch(ValueType::Bool, value)),
+        }
+    }
+}
+
+impl IntoValue for bool {
+    fn into_value(self) -> Value {
+        Value::Bool(self)
+    }
+}
+
+impl FromValue for char {
+    fn from_value(value: &Value) -> Result<char, ConvertError> {
+        match value {
+            Value::Char(c) => Ok(*c),
+            _ => Err(ConvertError::mismatch(ValueType::Char, value)),
+        }
+    }
+}
+
+impl IntoValue for char {
+    fn into_value(self) -> Value {
+        Value::Char(self)
+    }
+}
+
+/// Accepts both `StringValue` and `StringBuffer`.
+impl FromValue for String {
+    fn from_value(value: &Value) -> Result<String, ConvertError> {
+        match value {
+            Value::StringValue(s) => Ok(s.as_str().to_string()),
+            Value::StringBuffer(s) => Ok(s.0.borrow().clone()),
+            _ => Err(ConvertError::mismatch(ValueType::StringValue, value)),
+        }
+    }
+}
+
+impl IntoValue for String {
+    fn into_value(self) -> Value {
+        Value::StringValue(StringValue::from_string(self))
+    }
+}
+
+impl IntoValue for &str {
+    fn into_value(self) -> Value {
+        Value::StringValue(StringValue::from_string(self.to_string()))
+    }
+}
+
+impl FromValue for StringValue {
+    fn from_value(value: &Value) -> Result<StringValue, ConvertError> {
+        match value {
+            Value::StringValue(s) => Ok(s.clone()),
+            Value::StringBuffer(s) => Ok(StringValue::from_string(s.0.borrow().clone())),
+            _ => Err(ConvertError::mismatch(ValueType::StringValue, value)),
+        }
+    }
+}
+
+/// Accepts both `Bytes` and `BytesBuffer`, copying the buffer's contents.
+impl FromValue for Bytes {
+    fn from_value(value: &Value) -> Result<Bytes, ConvertError> {
+        match value {
+            Value::Bytes(b) => Ok(b.clone()),
+            Value::BytesBuffer(b) => Ok(Bytes::from_vec(b.0.borrow().clone())),
+            _ => Err(ConvertError::mismatch(ValueType::Bytes, value)),
+        }
+    }
+}
+
+impl IntoValue for &[u8] {
+    fn into_value(self) -> Value {
+        Value::Bytes(Bytes::from_vec(self.to_vec()))
+    }
+}
+
+impl FromValue for List {
+    fn from_value(value: &Value) -> Result<List, ConvertError> {
+        match value {
+            Value::List(l) => Ok(l.clone()),
+            _ => Err(ConvertError::mismatch(ValueType::List, value)),
+        }
+    }
+}
+
+impl<T: FromValue> FromValue for Vec<T> {
+    fn from_value(value: &Value) -> Result<Vec<T>, ConvertError> {
+        let list = List::from_value(value)?;
+        let items = list.0.borrow();
+        items.iter().map(T::from_value).collect()
+    }
+}
+
+impl<T: IntoValue> IntoValue for Vec<T> {
+    fn into_value(self) -> Value {
+        let items = self.into_iter().map(IntoValue::into_value).collect();
+        Value::List(List::from_vec(items))
+    }
+}
+
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value(value: &Value) -> Result<Option<T>, ConvertError> {
+        match value {
+            Value::None => Ok(None),
+            _ => T::from_value(value).map(Some),
+        }
+    }
+}
+
+impl<T: IntoValue> IntoValue for Option<T> {
+    fn into_value(self) -> Value {
+        match self {
+            Some(t) => t.into_value(),
+            None => Value::None,
+        }
+    }
+}
+
+macro_rules! tuple_value {
+    ($len:expr, $($t:ident $i:tt),*) => {
+        /// Tuples are lists with exactly one element per field.
+        impl<$($t: FromValue),*> FromValue for ($($t,)*) {
+            fn from_value(value: &Value) -> Result<($($t,)*), ConvertError> {
+                let list = List::from_value(value)?;
+                let items = list.0.borrow();
+                if items.len() != $len {
+                    return Err(ConvertError::Length { expected: $len, found: items.len() });
+                }
+                Ok(($($t::from_value(&items[$i])?,)*))
+            }
+        }
+
+        impl<$($t: IntoValue),*> IntoValue for ($($t,)*) {
+            fn into_value(self) -> Value {
+                Value::List(List::from_vec(vec![$(self.$i.into_value()),*]))
+            }
+        }
+    };
+}
+
+tuple_value!(1, A 0);
+tuple_value!(2, A 0, B 1);
+tuple_value!(3, A 0, B 1, C 2);
+tuple_value!(4, A 0, B 1, C 2, D 3);
+
+#[cfg(test)]
+mod tests {
+    use super::{ConvertError, FromValue, IntoValue};
+    use crate::datamodel::{Bytes, Value, ValueType};
+
+    #[test]
+    fn scalars() {
+        assert_eq!(i32::from_value(&7i32.into_value()), Ok(7));
+        assert_eq!(u8::from_value(&Value::Integer(256)), Err(ConvertError::Range(256)));
+        assert_eq!(f64::from_value(&1.5f32.into_value()), Ok(1.5));
+        assert_eq!(char::from_value(&'x'.into_value()), Ok('x'));
+        assert_eq!(bool::from_value(&Value::Integer(1)), Err(ConvertError::Type {
+            expected: ValueType::Bool,
+            found: ValueType::Integer,
+        }));
+        assert_eq!(String::from_value(&"hi".into_value()), Ok("hi".to_string()));
+    }
+
+    #[test]
+    fn containers() {
+        let v = vec![Some(1i64), None, Some(3)].into_value();
+        assert_eq!(Vec::<Option<i64>>::from_value(&v), Ok(vec![Some(1), None, Some(3)]));
+        assert!(Vec::<i64>::from_value(&v).is_err());
+
+        let t = (1u8, "two", 3.0f64).into_value();
+        assert_eq!(<(u8, String, f64)>::from_value(&t), Ok((1, "two".to_string(), 3.0)));
+        assert_eq!(<(u8, String)>::from_value(&t), Err(ConvertError::Length {
+            expected: 2,
+            found: 3,
+        }));
+
+        let b = Bytes::from_value(&b"abc"[..].into_value()).unwrap();
+        assert_eq!(&b.0[..], b"abc");
+    }
+}
@@ -0,0 +1,202 @@
+use std::cell::{Cell, Ref, RefCell};
+use std::rc::Rc;
+
+use crate::datamodel::{Function, Value};
+use crate::machine::CallStack;
+
+/// A function call that can suspend itself with `YIELD` and be continued
+/// with `RESUME`, keeping its frames between resumptions.
+///
+/// Functions called from the coroutine run on its stack, so they can yield
+/// on its behalf; guest code reached through a native function cannot.
+#[derive(Clone)]
+pub struct Coroutine(pub Rc<CoroutineState>);
+
+pub struct CoroutineState {
+    /// Taken out while running and dropped once finished or failed.
+    stack: RefCell<Option<CallStack>>,
+    started: Cell<bool>,
+}
+
+/// Outcome of resuming a coroutine.
+pub enum Resumed {
+    Yield(Value),
+    Return(Value),
+}
+
+impl Coroutine {
+    /// Nothing runs until the first resume.
+    pub fn new(function: Rc<Function>, args: Vec<Value>) -> Coroutine {
+        let stack = CallStack::new_coroutine(function, args);
+        Coroutine(Rc::new(CoroutineState {
+            stack: RefCell::new(Some(stack)),
+            started: Cell::new(false),
+        }))
+    }
+
+    /// True once the coroutine has returned or failed.
+    pub fn is_done(&self) -> bool {
+        self.0.started.get() && self.0.stack.borrow().is_none()
+    }
+
+    /// A coroutine with no stack yet, for restoring snapshots.
+    pub(crate) fn empty(started: bool) -> Coroutine {
+        Coroutine(Rc::new(CoroutineState {
+            stack: RefCell::new(None),
+            started: Cell::new(started),
+        }))
+    }
+
+    pub(crate) fn is_started(&self) -> bool {
+        self.0.started.get()
+    }
+
+    pub(crate) fn get_stack(&self) -> Ref<'_, Option<CallStack>> {
+        self.0.stack.borrow()
+    }
+
+    pub(crate) fn take_stack(&self) -> Option<CallStack> {
+        self.0.stack.borrow_mut().take()
+    }
+
+    pub(crate) fn put_stack(&self, stack: CallStack) {
+        *self.0.stack.borrow_mut() = Some(stack);
+    }
+
+    /// Marks the coroutine started, returning whether it already was.
+    pub(crate) fn start(&self) -> bool {
+        self.0.started.replace(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::{Coroutine, Resumed};
+    use crate::datamodel::{Function, List, Value};
+    use crate::machine::Vm;
+    use crate::operation::Operation;
+    use crate::test_util::function;
+    use crate::VmError;
+
+    /// Yields 0 to n - 1 through a helper function, then returns n.
+    fn counter() -> Rc<Function> {
+        let emit = function(List::from_vec(vec![]), &[
+            Operation::FrameLocalLoad(1),
+            Operation::Yield,
+            Operation::Return,
+        ]);
+        function(List::from_vec(vec![Value::Function(emit)]), &[
+            Operation::LiteralInteger(0),
+            Operation::FrameLocalStore(2),
+            // loop: 2
+            Operation::FrameLocalLoad(2),
+            Operation::FrameLocalLoad(1),
+            Operation::Cmp,
+            Operation::JumpNeg(7),
+            Operation::Jump(18),
+            // body: 7
+            Operation::FrameLocalLoad(2),
+            Operation::FrameLocalLoad(0),
+            Operation::LiteralInteger(0),
+            Operation::SeqGet,
+            Operation::Call(1),
+            Operation::FrameStackPop,
+            Operation::FrameLocalLoad(2),
+            Operation::LiteralInteger(1),
+            Operation::Add,
+            Operation::FrameLocalStore(2),
+            Operation::Jump(2),
+            // done: 18
+            Operation::FrameLocalLoad(1),
+            Operation::Return,
+        ])
+    }
+
+    #[test]
+    fn resume_from_host() {
+        let co = Coroutine::new(counter(), vec![Value::Integer(2)]);
+        let mut vm = Vm::new();
+        assert!(matches!(vm.resume(&co, Value::None), Ok(Resumed::Yield(Value::Integer(0)))));
+        assert!(matches!(vm.resume(&co, Value::None), Ok(Resumed::Yield(Value::Integer(1)))));
+        assert!(!co.is_done());
+        assert!(matches!(vm.resume(&co, Value::None), Ok(Resumed::Return(Value::Integer(2)))));
+        assert!(co.is_done());
+        assert!(matches!(vm.resume(&co, Value::None), Err(VmError::Resume)));
+
+        let bad = function(List::from_vec(vec![]), &[Operation::LiteralNone, Operation::Yield]);
+        assert!(matches!(vm.call(&Value::Function(bad), &[]), Err(VmError::Yield)));
+    }
+
+    #[test]
+    fn generator_loop() {
+        // sums what the generator yields with FOR_ITER
+        let main = function(List::from_vec(vec![Value::Function(counter())]), &[
+            Operation::LiteralInteger(0),
+            Operation::FrameLocalStore(2),
+            Operation::FrameLocalLoad(1),
+            Operation::FrameLocalLoad(0),
+            Operation::LiteralInteger(0),
+            Operation::SeqGet,
+            Operation::CoroutineNew(1),
+            Operation::IterNew,
+            // loop: 8
+            Operation::ForIter(13),
+            Operation::FrameLocalLoad(2),
+            Operation::Add,
+            Operation::FrameLocalStore(2),
+            Operation::Jump(8),
+            // done: 13
+            Operation::FrameLocalLoad(2),
+            Operation::Return,
+        ]);
+        let out = Vm::new().call(&Value::Function(main), &[Value::Integer(5)]);
+        assert!(matches!(out, Ok(Value::Integer(10))));
+    }
+
+    #[test]
+    fn resume_passes_input() {
+        // running total of the values sent in, yielded back after each one
+        let total = function(List::from_vec(vec![]), &[
+            Operation::LiteralInteger(0),
+            // loop: 1
+            Operation::FrameStackCopy,
+            Operation::Yield,
+            Operation::Add,
+            Operation::Jump(1),
+        ]);
+        let main = function(List::from_vec(vec![]), &[
+            Operation::FrameLocalLoad(1),
+            Operation::CoroutineNew(0),
+            Operation::FrameLocalStore(2),
+            Operation::FrameLocalLoad(2),
+            Operation::LiteralNone,
+            Operation::Resume,
+            Operation::FrameStackPop,
+            Operation::FrameStackPop,
+            Operation::FrameLocalLoad(2),
+            Operation::LiteralInteger(5),
+            Operation::Resume,
+            Operation::FrameStackPop,
+            Operation::FrameStackPop,
+            Operation::FrameLocalLoad(2),
+            Operation::LiteralInteger(7),
+            Operation::Resume,
+            Operation::ListCreate,
+            Operation::FrameLocalStore(3),
+            Operation::FrameLocalStore(4),
+            Operation::FrameLocalLoad(3),
+            Operation::FrameLocalLoad(4),
+            Operation::ListPush,
+            Operation::FrameLocalStore(4),
+            Operation::FrameLocalLoad(3),
+            Operation::FrameLocalLoad(4),
+            Operation::ListPush,
+            Operation::FrameLocalLoad(3),
+            Operation::Return,
+        ]);
+        let out = Vm::new().call(&Value::Function(main), &[Value::Function(total)]).unwrap();
+        assert_eq!(out.to_string(), "[false, 12]");
+    }
+}
@@ -1,13 +1,21 @@
 use std::{mem, str};
 use std::rc::{Rc, Weak};
-use std::cell::RefCell;
-use std::any::Any;
+use std::cell::{Cell, RefCell};
 use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 
 use crate::VmError;
+use crate::coroutine::Coroutine;
+use crate::host::HostObject;
+use crate::machine::VmContext;
+use crate::operation::verify;
+use crate::unicode;
 
 pub type NativeFn = fn(Vec<Value>) -> Result<Value, VmError>;
+pub type NativeClosure = dyn Fn(&mut VmContext, &[Value]) -> Result<Value, VmError>;
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ValueType {
     None,
     Bool,
@@ -20,8 +28,12 @@ pub enum ValueType {
     BytesBuffer,
     StringValue,
     StringBuffer,
+    Range,
+    Iter,
+    Coroutine,
     Function,
     NativeFn,
+    NativeFunction,
     Unknown
 }
 
@@ -38,9 +50,13 @@ pub enum Value {
     BytesBuffer(BytesBuffer),
     StringValue(StringValue),
     StringBuffer(StringBuffer),
+    Range(Range),
+    Iter(Iter),
+    Coroutine(Coroutine),
     Function(Rc<Function>),
     NativeFn(NativeFn),
-    Unknown(Rc<dyn Any>),
+    NativeFunction(NativeFunction),
+    Unknown(Rc<dyn HostObject>),
 }
 
 impl Value {
@@ -57,20 +73,23 @@ impl Value {
             Value::BytesBuffer(_) => ValueType::BytesBuffer,
             Value::StringValue(_) => ValueType::StringValue,
             Value::StringBuffer(_) => ValueType::StringBuffer,
+            Value::Range(_) => ValueType::Range,
+            Value::Iter(_) => ValueType::Iter,
+            Value::Coroutine(_) => ValueType::Coroutine,
             Value::Function(_) => ValueType::Function,
             Value::NativeFn(_) => ValueType::NativeFn,
+            Value::NativeFunction(_) => ValueType::NativeFunction,
             Value::Unknown(_) => ValueType::Unknown,
         }
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn cmp(&self, other: &Value) -> Option<Ordering> {
         let mut lhs = self;
         let mut rhs = other;
         let mut reverse = false;
         if lhs.get_type() as usize > rhs.get_type() as usize {
-            let t = lhs;
-            lhs = rhs;
-            rhs = t;
+            mem::swap(&mut lhs, &mut rhs);
             reverse = true;
         }
         let result = pure_value_cmp(lhs, rhs);
@@ -80,6 +99,37 @@ impl Value {
             result
         }
     }
+
+    /// Hash that agrees with `cmp`: values that compare equal hash the same.
+    /// `None` for NaN and for host objects without `HostObject::host_hash`,
+    /// which cannot be keys.
+    pub fn hash_code(&self) -> Option<u64> {
+        let mut h = DefaultHasher::new();
+        match self {
+            Value::None => (),
+            Value::Bool(b) => b.hash(&mut h),
+            Value::Integer(i) => i.hash(&mut h),
+            Value::Real(r) if r.is_nan() => return None,
+            // 0.0 and -0.0 compare equal
+            Value::Real(r) => (r + 0.0).to_bits().hash(&mut h),
+            Value::Char(c) => c.hash(&mut h),
+            Value::Bytes(b) => b.0[..].hash(&mut h),
+            Value::BytesBuffer(b) => b.0.borrow()[..].hash(&mut h),
+            Value::StringValue(s) => s.as_str().hash(&mut h),
+            Value::StringBuffer(s) => s.0.borrow().as_str().hash(&mut h),
+            Value::Range(r) => r.hash(&mut h),
+            Value::NativeFn(f) => (*f as usize).hash(&mut h),
+            // the rest compare by identity
+            Value::List(l) => Rc::as_ptr(&l.0).hash(&mut h),
+            Value::ListWeak(l) => l.0.as_ptr().hash(&mut h),
+            Value::Iter(t) => Rc::as_ptr(&t.0).hash(&mut h),
+            Value::Coroutine(co) => Rc::as_ptr(&co.0).hash(&mut h),
+            Value::Function(f) => Rc::as_ptr(f).hash(&mut h),
+            Value::NativeFunction(f) => (Rc::as_ptr(&f.0) as *const () as usize).hash(&mut h),
+            Value::Unknown(t) => return t.host_hash(),
+        }
+        Some(h.finish())
+    }
 }
 
 #[inline]
@@ -131,7 +181,7 @@ fn pure_value_cmp(lhs: &Value, rhs: &Value) -> Option<Ordering> {
                 if Rc::ptr_eq(&lhs.0.0, &rhs.0.0) {
                     return Some(Ordering::Equal);
                 }
-                return Some(lhs.as_str().cmp(&rhs.as_str()))
+                return Some(lhs.as_str().cmp(rhs.as_str()))
             },
             Value::StringBuffer(rhs) => return Some(lhs.as_str().cmp(&rhs.0.borrow())),
             _ => ()
@@ -142,26 +192,97 @@ fn pure_value_cmp(lhs: &Value, rhs: &Value) -> Option<Ordering> {
             }
             return Some(lhs.0.borrow().cmp(&rhs.0.borrow()));
         },
+        Value::Range(lhs) => if let Value::Range(rhs) = rhs {
+            if lhs == rhs {
+                return Some(Ordering::Equal);
+            }
+        },
+        Value::Iter(lhs) => if let Value::Iter(rhs) = rhs {
+            if Rc::ptr_eq(&lhs.0, &rhs.0) {
+                return Some(Ordering::Equal);
+            }
+        },
+        Value::Coroutine(lhs) => if let Value::Coroutine(rhs) = rhs {
+            if Rc::ptr_eq(&lhs.0, &rhs.0) {
+                return Some(Ordering::Equal);
+            }
+        },
         Value::Function(lhs) => if let Value::Function(rhs) = rhs {
             if Rc::ptr_eq(lhs, rhs) {
                 return Some(Ordering::Equal);
             }
         },
         Value::NativeFn(lhs) => if let Value::NativeFn(rhs) = rhs {
-            return Some(lhs.cmp(rhs));
+            return Some((*lhs as usize).cmp(&(*rhs as usize)));
+        },
+        Value::NativeFunction(lhs) => if let Value::NativeFunction(rhs) = rhs {
+            if Rc::ptr_eq(&lhs.0, &rhs.0) {
+                return Some(Ordering::Equal);
+            }
         },
         Value::Unknown(lhs) => if let Value::Unknown(rhs) = rhs {
             if Rc::ptr_eq(lhs, rhs) {
                 return Some(Ordering::Equal);
             }
+            return lhs.host_cmp(&**rhs);
         },
     }
-    return None;
+    None
 }
 
 pub struct Function {
+    pub name: Option<String>,
     pub module: List,
     pub bytecode: Bytes,
+    /// `(offset, line)` pairs sorted by offset, each line covering the
+    /// bytecode up to the next entry.
+    pub lines: Vec<(usize, u32)>,
+    /// Result of `operation::verify`, once the function has first run.
+    verified: Cell<Option<bool>>,
+}
+
+impl Function {
+    pub fn new(module: List, bytecode: Bytes) -> Function {
+        Function { name: None, module, bytecode, lines: vec![], verified: Cell::new(None) }
+    }
+
+    /// Whether the bytecode passes `operation::verify`, which lets it run
+    /// through the faster `operation::run_many` loop. Verified code is
+    /// bounds checked all the same.
+    pub fn is_verified(&self) -> bool {
+        match self.verified.get() {
+            Some(verified) => verified,
+            None => {
+                let verified = verify(&self.bytecode.0);
+                self.verified.set(Some(verified));
+                verified
+            },
+        }
+    }
+
+    pub fn get_line_at(&self, offset: usize) -> Option<u32> {
+        let i = match self.lines.binary_search_by_key(&offset, |&(o, _)| o) {
+            Ok(i) => i,
+            Err(0) => return None,
+            Err(i) => i - 1,
+        };
+        Some(self.lines[i].1)
+    }
+
+    pub fn get_line_offset(&self, line: u32) -> Option<usize> {
+        self.lines.iter().find(|&&(_, l)| l == line).map(|&(o, _)| o)
+    }
+}
+
+/// A host function that receives a context for calling back into the VM.
+#[derive(Clone)]
+pub struct NativeFunction(pub Rc<NativeClosure>);
+
+impl NativeFunction {
+    pub fn new<F>(f: F) -> NativeFunction
+    where F: Fn(&mut VmContext, &[Value]) -> Result<Value, VmError> + 'static {
+        NativeFunction(Rc::new(f))
+    }
 }
 
 #[derive(Clone)]
@@ -176,6 +297,10 @@ impl List {
         self.0.borrow().len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.0.borrow().is_empty()
+    }
+
     pub fn resize(&self, len: usize) {
         let mut items = self.0.borrow_mut();
         items.resize_with(len, || Value::None)
@@ -236,10 +361,18 @@ impl ListWeak {
 pub struct Bytes(pub Rc<Vec<u8>>);
 
 impl Bytes {
+    pub fn from_vec(vec: Vec<u8>) -> Bytes {
+        Bytes(Rc::new(vec))
+    }
+
     pub fn len(&self) -> usize {
         self.0.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
     pub fn get(&self, index: usize) -> Option<Value> {
         Some(Value::Integer(*self.0.get(index)? as i64))
     }
@@ -261,6 +394,10 @@ impl BytesBuffer {
         self.0.borrow().len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.0.borrow().is_empty()
+    }
+
     pub fn resize(&self, len: usize) {
         let mut bytes = self.0.borrow_mut();
         bytes.resize(len, 0);
@@ -307,27 +444,40 @@ pub struct StringValue(Bytes);
 
 impl StringValue {
     pub fn from_bytes(bytes: Bytes) -> Result<StringValue, str::Utf8Error> {
-        str::from_utf8(&**bytes.0)?;
+        str::from_utf8(&bytes.0)?;
         Ok(StringValue(bytes))
     }
 
+    pub fn from_string(string: String) -> StringValue {
+        StringValue(Bytes::from_vec(string.into_bytes()))
+    }
+
     pub fn as_str(&self) -> &str {
         // should be safe since constructor guarantees Bytes is valid utf8
-        unsafe { str::from_utf8_unchecked(&**self.0.0) }
+        unsafe { str::from_utf8_unchecked(&self.0.0) }
     }
 
     pub fn as_bytes(&self) -> &Bytes {
         &self.0
     }
 
-    pub fn get_char_at(&self, index: usize) -> Option<char> {
-        self.as_str().get(index..)?.chars().next()
+    /// The char at byte offset `index`, see `unicode::char_at`.
+    pub fn get_char_at(&self, index: i64) -> Result<char, VmError> {
+        unicode::char_at(self.as_str(), index)
     }
 
     pub fn get_chars(&self) -> List {
-        let vec = self.as_str().chars().map(|c| Value::Char(c)).collect();
+        let vec = self.as_str().chars().map(Value::Char).collect();
         List::from_vec(vec)
     }
+
+    pub fn char_count(&self) -> usize {
+        self.as_str().chars().count()
+    }
+
+    pub fn grapheme_count(&self) -> usize {
+        unicode::graphemes(self.as_str()).len()
+    }
 }
 
 #[derive(Clone)]
@@ -346,16 +496,302 @@ impl StringBuffer {
         self.0.borrow().len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.0.borrow().is_empty()
+    }
+
     pub fn append(&self, t: &str) {
         self.0.borrow_mut().push_str(t)
     }
 
-    pub fn get_char_at(&self, index: usize) -> Option<char> {
-        self.0.borrow().get(index..)?.chars().next()
+    /// The char at byte offset `index`, see `unicode::char_at`.
+    pub fn get_char_at(&self, index: i64) -> Result<char, VmError> {
+        unicode::char_at(&self.0.borrow(), index)
     }
 
     pub fn get_chars(&self) -> List {
-        let vec = self.0.borrow().chars().map(|c| Value::Char(c)).collect();
+        let vec = self.0.borrow().chars().map(Value::Char).collect();
         List::from_vec(vec)
     }
+
+    pub fn char_count(&self) -> usize {
+        self.0.borrow().chars().count()
+    }
+
+    pub fn grapheme_count(&self) -> usize {
+        unicode::graphemes(&self.0.borrow()).len()
+    }
+}
+
+/// Arithmetic progression from `start` up to but excluding `end`, stored
+/// without a backing list.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Range {
+    pub start: i64,
+    pub end: i64,
+    /// Never zero; negative steps count down.
+    pub step: i64,
+}
+
+impl Range {
+    /// `None` if `step` is zero.
+    pub fn new(start: i64, end: i64, step: i64) -> Option<Range> {
+        if step == 0 {
+            return None;
+        }
+        Some(Range { start, end, step })
+    }
+
+    pub fn len(&self) -> usize {
+        let (start, end, step) = (self.start as i128, self.end as i128, self.step as i128);
+        let n = if step > 0 {
+            (end - start + step - 1) / step
+        } else {
+            (start - end - step - 1) / -step
+        };
+        n.max(0) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, index: usize) -> Option<i64> {
+        if index >= self.len() {
+            return None;
+        }
+        Some((self.start as i128 + index as i128 * self.step as i128) as i64)
+    }
+
+    /// The positions this range selects from a sequence of `len` items, or
+    /// `None` if any of them is out of bounds.
+    pub fn indices(&self, len: usize) -> Option<impl Iterator<Item = usize>> {
+        let n = self.len();
+        if n > 0 {
+            let last = self.get(n - 1).unwrap();
+            if self.start < 0 || last < 0 || self.start as usize >= len || last as usize >= len {
+                return None;
+            }
+        }
+        let range = *self;
+        Some((0..n).map(move |i| range.get(i).unwrap() as usize))
+    }
+
+    /// The items of this range at the positions `other` selects.
+    pub fn get_range(&self, other: &Range) -> Option<Range> {
+        let n = other.len();
+        if n == 0 {
+            return Some(Range { start: 0, end: 0, step: 1 });
+        }
+        let first = other.indices(self.len())?.next()?;
+        let start = self.get(first)?;
+        let step = self.step.checked_mul(other.step)?;
+        let last = self.get(other.get(n - 1)? as usize)?;
+        // end just past the last item, in the direction of the step
+        let end = last.checked_add(step.signum())?;
+        Range::new(start, end, step)
+    }
+}
+
+/// Cursor over a sequence, created by `ITER_NEW` and advanced by `FOR_ITER`.
+///
+/// The iterator shares its source rather than copying it, and reads the item
+/// at its position on each step: items appended to a list or buffer while
+/// iterating are visited, and shrinking it below the position ends the
+/// iteration. A string buffer edited so that the position no longer falls on
+/// a char boundary makes the next step fail with `CharBoundary`.
+#[derive(Clone)]
+pub struct Iter(pub Rc<RefCell<IterState>>);
+
+pub struct IterState {
+    source: Value,
+    /// Item index, or byte offset for strings.
+    position: usize,
+}
+
+impl Iter {
+    /// `None` if `source` cannot be iterated. Iterating an iterator returns
+    /// the same iterator.
+    pub fn new(source: Value) -> Option<Iter> {
+        match source {
+            Value::Iter(t) => Some(t),
+            Value::List(_)
+            | Value::Range(_)
+            | Value::Bytes(_)
+            | Value::BytesBuffer(_)
+            | Value::StringValue(_)
+            | Value::StringBuffer(_) => {
+                Some(Iter(Rc::new(RefCell::new(IterState { source, position: 0 }))))
+            },
+            _ => None,
+        }
+    }
+
+    pub(crate) fn from_state(source: Value, position: usize) -> Iter {
+        Iter(Rc::new(RefCell::new(IterState { source, position })))
+    }
+
+    pub(crate) fn get_state(&self) -> (Value, usize) {
+        let state = self.0.borrow();
+        (state.source.clone(), state.position)
+    }
+
+    pub fn next(&self) -> Result<Option<Value>, VmError> {
+        let mut state = self.0.borrow_mut();
+        let i = state.position;
+        let (item, next) = match &state.source {
+            Value::List(l) => match l.0.borrow().get(i) {
+                Some(t) => (t.clone(), i + 1),
+                None => return Ok(None),
+            },
+            Value::Range(r) => match r.get(i) {
+                Some(t) => (Value::Integer(t), i + 1),
+                None => return Ok(None),
+            },
+            Value::Bytes(b) => match b.0.get(i) {
+                Some(t) => (Value::Integer(*t as i64), i + 1),
+                None => return Ok(None),
+            },
+            Value::BytesBuffer(b) => match b.0.borrow().get(i) {
+                Some(t) => (Value::Integer(*t as i64), i + 1),
+                None => return Ok(None),
+            },
+            Value::StringValue(s) => match unicode::next_char(s.as_str(), i as i64)? {
+                Some((c, next)) => (Value::Char(c), next),
+                None => return Ok(None),
+            },
+            Value::StringBuffer(s) => {
+                let s = s.0.borrow();
+                if i > s.len() {
+                    return Ok(None);
+                }
+                match unicode::next_char(&s, i as i64)? {
+                    Some((c, next)) => (Value::Char(c), next),
+                    None => return Ok(None),
+                }
+            },
+            _ => unreachable!(),
+        };
+        state.position = next;
+        Ok(Some(item))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Bytes, Iter, List, Range, StringBuffer, Value};
+    use crate::machine::Vm;
+    use crate::operation::Operation;
+    use crate::test_util::function;
+    use crate::VmError;
+
+    fn next(iter: &Iter) -> Option<String> {
+        iter.next().unwrap().map(|t| t.to_string())
+    }
+
+    #[test]
+    fn iteration_sees_mutation() {
+        let list = List::from_vec(vec![Value::Integer(1), Value::Integer(2)]);
+        let iter = Iter::new(Value::List(list.clone())).unwrap();
+        assert_eq!(next(&iter).as_deref(), Some("1"));
+        list.push(Value::Integer(3));
+        assert_eq!(next(&iter).as_deref(), Some("2"));
+        list.resize(1);
+        assert_eq!(next(&iter), None);
+
+        let buffer = StringBuffer::from_string("aé".to_string());
+        let iter = Iter::new(Value::StringBuffer(buffer.clone())).unwrap();
+        assert_eq!(next(&iter).as_deref(), Some("a"));
+        buffer.clear();
+        buffer.append("éé");
+        assert!(matches!(iter.next(), Err(VmError::CharBoundary(1))));
+        assert!(Iter::new(Value::Integer(1)).is_none());
+    }
+
+    #[test]
+    fn for_iter_loop() {
+        let f = Value::Function(function(List::from_vec(vec![]), &[
+            Operation::ListCreate,
+            Operation::FrameLocalStore(2),
+            Operation::FrameLocalLoad(1),
+            Operation::IterNew,
+            // loop: 4
+            Operation::ForIter(10),
+            Operation::FrameLocalStore(3),
+            Operation::FrameLocalLoad(2),
+            Operation::FrameLocalLoad(3),
+            Operation::ListPush,
+            Operation::Jump(4),
+            // done: 10
+            Operation::FrameLocalLoad(2),
+            Operation::Return,
+        ]));
+        let mut vm = Vm::new();
+        let out = vm.call(&f, &[Value::Bytes(Bytes::from_vec(vec![7, 8]))]).unwrap();
+        assert_eq!(out.to_string(), "[7, 8]");
+        let s = Value::StringBuffer(StringBuffer::from_string("hé".to_string()));
+        assert_eq!(vm.call(&f, &[s]).unwrap().to_string(), "['h', 'é']");
+        assert!(matches!(vm.call(&f, &[Value::None]), Err(VmError::Type(_, 0))));
+    }
+
+    #[test]
+    fn ranges() {
+        let r = Range::new(10, 0, -3).unwrap();
+        assert_eq!(r.len(), 4);
+        assert_eq!(r.get(3), Some(1));
+        assert_eq!(Range::new(0, 10, 3).unwrap().len(), 4);
+        assert!(Range::new(5, 2, 1).unwrap().is_empty());
+        assert_eq!(Range::new(i64::MIN, i64::MAX, 1).unwrap().len(), usize::MAX);
+        assert!(Range::new(0, 1, 0).is_none());
+        // every other item, reversed
+        let sub = Range::new(3, -1, -2).unwrap();
+        assert_eq!(Range::new(0, 40, 10).unwrap().get_range(&sub), Range::new(30, 9, -20));
+        assert!(r.indices(3).is_none());
+        let iter = Iter::new(Value::Range(r)).unwrap();
+        assert_eq!(next(&iter).as_deref(), Some("10"));
+    }
+
+    #[test]
+    fn range_opcodes() {
+        // builds range(1, 8, 2) and slices local 1 with it
+        let f = Value::Function(function(List::from_vec(vec![]), &[
+            Operation::FrameLocalLoad(1),
+            Operation::LiteralInteger(1),
+            Operation::LiteralInteger(8),
+            Operation::LiteralInteger(2),
+            Operation::RangeNew,
+            Operation::FrameStackCopy,
+            Operation::FrameLocalStore(2),
+            Operation::SeqGetSlice,
+            Operation::FrameLocalLoad(2),
+            Operation::SeqLen,
+            Operation::FrameLocalLoad(2),
+            Operation::LiteralInteger(3),
+            Operation::SeqGet,
+            Operation::ListCreate,
+            Operation::FrameLocalStore(3),
+            Operation::FrameLocalStore(4),
+            Operation::FrameLocalLoad(3),
+            Operation::FrameLocalLoad(4),
+            Operation::ListPush,
+            Operation::FrameLocalStore(4),
+            Operation::FrameLocalLoad(3),
+            Operation::FrameLocalLoad(4),
+            Operation::ListPush,
+            Operation::FrameLocalStore(4),
+            Operation::FrameLocalLoad(3),
+            Operation::FrameLocalLoad(4),
+            Operation::ListPush,
+            Operation::FrameLocalLoad(3),
+            Operation::Return,
+        ]));
+        let mut vm = Vm::new();
+        let items = List::from_vec((0..9).map(Value::Integer).collect());
+        let out = vm.call(&f, &[Value::List(items)]).unwrap();
+        assert_eq!(out.to_string(), "[7, 4, [1, 3, 5, 7]]");
+        let out = vm.call(&f, &[Value::Range(Range::new(0, 90, 10).unwrap())]).unwrap();
+        assert_eq!(out.to_string(), "[7, 4, range(10, 71, 20)]");
+        assert!(matches!(vm.call(&f, &[Value::Bytes(Bytes::from_vec(vec![0; 4]))]),
+            Err(VmError::SliceRead(1, 8))));
+    }
 }
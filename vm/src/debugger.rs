@@ -0,0 +1,245 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+use crate::datamodel::{Function, Value};
+use crate::machine::{CallStack, Vm};
+use crate::VmError;
+
+pub enum DebugEvent {
+    /// Paused before an instruction carrying a breakpoint.
+    Breakpoint,
+    /// Paused at the end of a step.
+    Step,
+    /// The outermost frame returned.
+    Finished(Value),
+}
+
+/// Drives a `CallStack` one instruction at a time, pausing on breakpoints.
+///
+/// Breakpoints are checked before every instruction, including the first
+/// one of a fresh stack, except the one the debugger last paused on, so
+/// resuming from a breakpoint always makes progress.
+pub struct Debugger {
+    breakpoints: Vec<(Rc<Function>, usize)>,
+    // function, depth and cursor of the last pause
+    paused: Cell<Option<(Rc<Function>, usize, usize)>>,
+}
+
+impl Default for Debugger {
+    fn default() -> Debugger {
+        Debugger::new()
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger { breakpoints: vec![], paused: Cell::new(None) }
+    }
+
+    pub fn set_breakpoint(&mut self, function: &Rc<Function>, offset: usize) {
+        if !self.has_breakpoint(function, offset) {
+            self.breakpoints.push((function.clone(), offset));
+        }
+    }
+
+    pub fn clear_breakpoint(&mut self, function: &Rc<Function>, offset: usize) -> bool {
+        let len = self.breakpoints.len();
+        self.breakpoints.retain(|(f, o)| !(Rc::ptr_eq(f, function) && *o == offset));
+        self.breakpoints.len() != len
+    }
+
+    /// Sets a breakpoint on the first instruction of `line`, returning its
+    /// offset, or `None` if the function has no code on that line.
+    pub fn set_line_breakpoint(&mut self, function: &Rc<Function>, line: u32) -> Option<usize> {
+        let offset = function.get_line_offset(line)?;
+        self.set_breakpoint(function, offset);
+        Some(offset)
+    }
+
+    pub fn clear_line_breakpoint(&mut self, function: &Rc<Function>, line: u32) -> bool {
+        match function.get_line_offset(line) {
+            Some(offset) => self.clear_breakpoint(function, offset),
+            None => false,
+        }
+    }
+
+    pub fn has_breakpoint(&self, function: &Rc<Function>, offset: usize) -> bool {
+        self.breakpoints.iter().any(|(f, o)| Rc::ptr_eq(f, function) && *o == offset)
+    }
+
+    /// Whether the next instruction of the innermost frame has a breakpoint.
+    pub fn is_at_breakpoint(&self, stack: &CallStack) -> bool {
+        match stack.get_frames().last() {
+            Some(frame) => self.has_breakpoint(frame.get_function(), frame.get_cursor()),
+            None => false,
+        }
+    }
+
+    /// Runs until a breakpoint is hit or the stack finishes.
+    pub fn resume(&self, vm: &mut Vm, stack: &mut CallStack) -> Result<DebugEvent, VmError> {
+        self.run_until(vm, stack, |_| false)
+    }
+
+    /// Executes one instruction, following calls into the new frame.
+    pub fn step_into(&self, vm: &mut Vm, stack: &mut CallStack) -> Result<DebugEvent, VmError> {
+        self.run_until(vm, stack, |_| true)
+    }
+
+    /// Executes one instruction, running any call it makes to completion.
+    pub fn step_over(&self, vm: &mut Vm, stack: &mut CallStack) -> Result<DebugEvent, VmError> {
+        let depth = stack.depth();
+        self.run_until(vm, stack, |d| d <= depth)
+    }
+
+    /// Runs until the current frame returns to its caller.
+    pub fn step_out(&self, vm: &mut Vm, stack: &mut CallStack) -> Result<DebugEvent, VmError> {
+        let depth = stack.depth();
+        self.run_until(vm, stack, |d| d < depth)
+    }
+
+    fn run_until<F>(&self, vm: &mut Vm, stack: &mut CallStack, done: F) -> Result<DebugEvent, VmError>
+    where F: Fn(usize) -> bool {
+        let resumed = match (self.paused.take(), position(stack)) {
+            (Some((f, depth, cursor)), Some((g, d, c))) => Rc::ptr_eq(&f, &g) && depth == d && cursor == c,
+            _ => false,
+        };
+        if !resumed && self.is_at_breakpoint(stack) {
+            return Ok(self.pause(stack, DebugEvent::Breakpoint));
+        }
+        loop {
+            if let Some(out) = stack.step(vm)? {
+                return Ok(DebugEvent::Finished(out));
+            }
+            if done(stack.depth()) {
+                return Ok(self.pause(stack, DebugEvent::Step));
+            }
+            if self.is_at_breakpoint(stack) {
+                return Ok(self.pause(stack, DebugEvent::Breakpoint));
+            }
+        }
+    }
+
+    fn pause(&self, stack: &CallStack, event: DebugEvent) -> DebugEvent {
+        self.paused.set(position(stack));
+        event
+    }
+}
+
+fn position(stack: &CallStack) -> Option<(Rc<Function>, usize, usize)> {
+    let frame = stack.get_frames().last()?;
+    Some((frame.get_function().clone(), stack.depth(), frame.get_cursor()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::{DebugEvent, Debugger};
+    use crate::datamodel::{Bytes, Function, List, Value};
+    use crate::machine::{CallStack, Vm};
+    use crate::operation::{assemble, Operation};
+    use crate::test_util::function;
+
+    // offset of `Call(1)` in `main`
+    const CALL_OFFSET: usize = 21;
+
+    fn program() -> (Rc<Function>, Rc<Function>) {
+        let module = List::from_vec(vec![]);
+        let double = function(module.clone(), &[
+            Operation::FrameLocalLoad(1),
+            Operation::LiteralInteger(2),
+            Operation::Mul,
+            Operation::Return,
+        ]);
+        module.push(Value::Function(double.clone()));
+        let main = assemble(&[
+            Operation::LiteralInteger(20),
+            Operation::FrameLocalLoad(0),
+            Operation::LiteralInteger(0),
+            Operation::SeqGet,
+            Operation::Call(1),
+            Operation::LiteralInteger(2),
+            Operation::Add,
+            Operation::Return,
+        ]).unwrap();
+        let mut main = Function::new(module, Bytes::from_vec(main));
+        main.lines = vec![(0, 1), (CALL_OFFSET, 2), (CALL_OFFSET + 2, 3)];
+        (Rc::new(main), double)
+    }
+
+    fn top(stack: &CallStack) -> Option<i64> {
+        match stack.get_frames().last()?.get_stack().last()? {
+            Value::Integer(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn breakpoint_in_callee() {
+        let (main, double) = program();
+        let mut vm = Vm::new();
+        let mut stack = CallStack::new(main, vec![]);
+        let mut debugger = Debugger::new();
+        debugger.set_breakpoint(&double, 0);
+        assert!(matches!(debugger.resume(&mut vm, &mut stack).unwrap(), DebugEvent::Breakpoint));
+        assert_eq!(stack.depth(), 2);
+        let frame = stack.get_frame(1).unwrap();
+        assert!(Rc::ptr_eq(frame.get_function(), &double));
+        assert!(matches!(frame.get_locals()[1], Value::Integer(20)));
+        assert!(matches!(debugger.resume(&mut vm, &mut stack).unwrap(), DebugEvent::Finished(Value::Integer(42))));
+    }
+
+    #[test]
+    fn step_modes() {
+        let (main, _) = program();
+        let mut vm = Vm::new();
+        let mut stack = CallStack::new(main.clone(), vec![]);
+        let mut debugger = Debugger::new();
+        assert_eq!(debugger.set_line_breakpoint(&main, 2), Some(CALL_OFFSET));
+        assert!(matches!(debugger.resume(&mut vm, &mut stack).unwrap(), DebugEvent::Breakpoint));
+        assert_eq!(stack.get_frame(0).unwrap().get_cursor(), CALL_OFFSET);
+
+        assert!(matches!(debugger.step_into(&mut vm, &mut stack).unwrap(), DebugEvent::Step));
+        assert_eq!(stack.depth(), 2);
+        assert!(matches!(debugger.step_into(&mut vm, &mut stack).unwrap(), DebugEvent::Step));
+        assert_eq!(top(&stack), Some(20));
+        assert!(matches!(debugger.step_out(&mut vm, &mut stack).unwrap(), DebugEvent::Step));
+        assert_eq!(stack.depth(), 1);
+        assert_eq!(top(&stack), Some(40));
+        assert_eq!(main.get_line_at(stack.get_frame(0).unwrap().get_cursor()), Some(3));
+
+        assert!(debugger.clear_line_breakpoint(&main, 2));
+        assert!(matches!(debugger.step_over(&mut vm, &mut stack).unwrap(), DebugEvent::Step));
+        assert_eq!(top(&stack), Some(2));
+    }
+
+    #[test]
+    fn step_over_call() {
+        let (main, _) = program();
+        let mut vm = Vm::new();
+        let mut stack = CallStack::new(main.clone(), vec![]);
+        let mut debugger = Debugger::new();
+        debugger.set_breakpoint(&main, CALL_OFFSET);
+        debugger.resume(&mut vm, &mut stack).unwrap();
+        assert!(matches!(debugger.step_over(&mut vm, &mut stack).unwrap(), DebugEvent::Step));
+        assert_eq!(stack.depth(), 1);
+        assert_eq!(top(&stack), Some(40));
+    }
+
+    #[test]
+    fn breakpoint_at_start() {
+        let (main, _) = program();
+        let mut vm = Vm::new();
+        let mut stack = CallStack::new(main.clone(), vec![]);
+        let mut debugger = Debugger::new();
+        debugger.set_breakpoint(&main, 0);
+        assert!(matches!(debugger.resume(&mut vm, &mut stack).unwrap(), DebugEvent::Breakpoint));
+        assert_eq!(stack.get_frame(0).unwrap().get_cursor(), 0);
+        assert!(top(&stack).is_none());
+        assert!(matches!(debugger.resume(&mut vm, &mut stack).unwrap(), DebugEvent::Finished(Value::Integer(42))));
+
+        // a new stack stops there again
+        let mut stack = CallStack::new(main, vec![]);
+        assert!(matches!(debugger.step_over(&mut vm, &mut stack).unwrap(), DebugEvent::Breakpoint));
+    }
+}
@@ -0,0 +1,379 @@
+use std::fmt::{self, Write};
+use std::rc::Rc;
+
+use crate::datamodel::Value;
+use crate::VmError;
+
+/// Literal form of a value, see `Value::repr`.
+pub struct Repr<'a>(&'a Value);
+
+impl Value {
+    /// Formats the value as literal syntax: strings and chars quoted and
+    /// escaped, bytes as `b"..."` and reals always distinguishable from
+    /// integers. Lists met again while formatting themselves print `[...]`.
+    pub fn repr(&self) -> Repr<'_> {
+        Repr(self)
+    }
+}
+
+/// User-facing form: strings and chars print bare at the top level, while
+/// the elements of lists use their literal form.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Char(c) => f.write_char(*c),
+            Value::StringValue(s) => f.write_str(s.as_str()),
+            Value::StringBuffer(s) => f.write_str(&s.0.borrow()),
+            _ => write_value(f, self, &mut vec![]),
+        }
+    }
+}
+
+impl fmt::Display for Repr<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_value(f, self.0, &mut vec![])
+    }
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_value(f, self, &mut vec![])
+    }
+}
+
+// `path` holds the lists currently being written, to spot cycles
+fn write_value(f: &mut fmt::Formatter, value: &Value, path: &mut Vec<*const ()>) -> fmt::Result {
+    match value {
+        Value::None => f.write_str("none"),
+        Value::Bool(t) => write!(f, "{}", t),
+        Value::Integer(t) => write!(f, "{}", t),
+        Value::Real(t) => write_real(f, *t),
+        Value::Char(t) => write!(f, "{:?}", t),
+        Value::List(l) => {
+            let ptr = Rc::as_ptr(&l.0) as *const ();
+            if path.contains(&ptr) {
+                return f.write_str("[...]");
+            }
+            path.push(ptr);
+            f.write_char('[')?;
+            // clone so elements can be formatted without holding the borrow
+            let items = l.0.borrow().clone();
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    f.write_str(", ")?;
+                }
+                write_value(f, item, path)?;
+            }
+            path.pop();
+            f.write_char(']')
+        },
+        Value::ListWeak(w) => match w.upgrade() {
+            Some(l) => {
+                f.write_str("<weak ")?;
+                write_value(f, &Value::List(l), path)?;
+                f.write_char('>')
+            },
+            None => f.write_str("<weak>"),
+        },
+        Value::Bytes(b) => write_bytes(f, &b.0),
+        Value::BytesBuffer(b) => write_bytes(f, &b.0.borrow()),
+        Value::StringValue(s) => write!(f, "{:?}", s.as_str()),
+        Value::StringBuffer(s) => write!(f, "{:?}", s.0.borrow().as_str()),
+        Value::Range(r) if r.step == 1 => write!(f, "range({}, {})", r.start, r.end),
+        Value::Range(r) => write!(f, "range({}, {}, {})", r.start, r.end, r.step),
+        Value::Iter(_) => f.write_str("<iter>"),
+        Value::Coroutine(_) => f.write_str("<coroutine>"),
+        Value::Function(t) => match &t.name {
+            Some(name) => write!(f, "<fn {}>", name),
+            None => f.write_str("<fn>"),
+        },
+        Value::NativeFn(_) | Value::NativeFunction(_) => f.write_str("<native fn>"),
+        Value::Unknown(t) => match t.display() {
+            Some(s) => f.write_str(&s),
+            None => write!(f, "<{}>", t.type_name()),
+        },
+    }
+}
+
+// shortest representation that parses back to the same f64
+fn write_real(f: &mut fmt::Formatter, t: f64) -> fmt::Result {
+    if t.is_nan() {
+        f.write_str("nan")
+    } else if t.is_infinite() {
+        f.write_str(if t > 0.0 { "inf" } else { "-inf" })
+    } else {
+        write!(f, "{:?}", t)
+    }
+}
+
+fn write_bytes(f: &mut fmt::Formatter, bytes: &[u8]) -> fmt::Result {
+    f.write_str("b\"")?;
+    for &b in bytes {
+        match b {
+            b'"' => f.write_str("\\\"")?,
+            b'\\' => f.write_str("\\\\")?,
+            b'\n' => f.write_str("\\n")?,
+            b'\r' => f.write_str("\\r")?,
+            b'\t' => f.write_str("\\t")?,
+            0x20..=0x7e => f.write_char(b as char)?,
+            _ => write!(f, "\\x{:02x}", b)?,
+        }
+    }
+    f.write_char('"')
+}
+
+/// Expands the placeholders in `template` with `args`, as `STR_FORMAT` does.
+///
+/// A placeholder is `{}` or `{:spec}` where spec is
+/// `[align][0][width][.precision][type]`: align is one of `<`, `^`, `>`, type
+/// is `x`, `X`, `o` or `b` for integers or `?` for the literal form. `{{` and
+/// `}}` stand for single braces. Every argument must be used. Width and
+/// precision go up to `MAX_WIDTH`.
+pub fn format(template: &str, args: &[Value]) -> Result<String, VmError> {
+    let mut out = String::new();
+    let mut next = 0;
+    let mut chars = template.char_indices().peekable();
+    while let Some((pos, c)) = chars.next() {
+        match c {
+            '{' if chars.peek().map(|t| t.1) == Some('{') => {
+                chars.next();
+                out.push('{');
+            },
+            '}' if chars.peek().map(|t| t.1) == Some('}') => {
+                chars.next();
+                out.push('}');
+            },
+            '{' => {
+                let close = template[pos..].find('}').ok_or(VmError::Format(pos))?;
+                let spec = &template[pos + 1..pos + close];
+                let spec = match spec.strip_prefix(':') {
+                    Some(t) => Spec::parse(t).ok_or(VmError::Format(pos))?,
+                    None if spec.is_empty() => Spec::default(),
+                    None => return Err(VmError::Format(pos)),
+                };
+                let arg = args.get(next).ok_or(VmError::Format(pos))?;
+                // type errors count stack positions from the top, like opcodes do
+                let arg_pos = (args.len() - 1 - next) as u8;
+                spec.write(&mut out, arg, arg_pos)?;
+                next += 1;
+                while chars.peek().is_some_and(|t| t.0 <= pos + close) {
+                    chars.next();
+                }
+            },
+            '}' => return Err(VmError::Format(pos)),
+            c => out.push(c),
+        }
+    }
+    if next != args.len() {
+        return Err(VmError::Arity(next, args.len()));
+    }
+    Ok(out)
+}
+
+/// Largest width or precision a placeholder may ask for, since guest code
+/// writes the templates.
+pub const MAX_WIDTH: usize = 1024;
+
+#[derive(Default)]
+struct Spec {
+    align: Option<char>,
+    zero: bool,
+    width: usize,
+    precision: Option<usize>,
+    kind: Option<char>,
+}
+
+impl Spec {
+    fn parse(spec: &str) -> Option<Spec> {
+        let mut out = Spec::default();
+        let mut rest = spec;
+        if let Some(c) = rest.chars().next().filter(|c| "<^>".contains(*c)) {
+            out.align = Some(c);
+            rest = &rest[1..];
+        }
+        if let Some(t) = rest.strip_prefix('0') {
+            out.zero = true;
+            rest = t;
+        }
+        let (width, t) = take_number(rest)?;
+        out.width = width.unwrap_or(0);
+        rest = t;
+        if let Some(t) = rest.strip_prefix('.') {
+            let (precision, t) = take_number(t)?;
+            out.precision = Some(precision?);
+            rest = t;
+        }
+        match rest {
+            "" => (),
+            "x" | "X" | "o" | "b" | "?" => out.kind = rest.chars().next(),
+            _ => return None,
+        }
+        Some(out)
+    }
+
+    fn write(&self, out: &mut String, arg: &Value, pos: u8) -> Result<(), VmError> {
+        let body = match (self.kind, arg) {
+            (Some('?'), _) => arg.repr().to_string(),
+            (Some(kind), Value::Integer(i)) => {
+                let sign = if *i < 0 { "-" } else { "" };
+                let n = i.unsigned_abs();
+                match kind {
+                    'x' => format!("{}{:x}", sign, n),
+                    'X' => format!("{}{:X}", sign, n),
+                    'o' => format!("{}{:o}", sign, n),
+                    _ => format!("{}{:b}", sign, n),
+                }
+            },
+            (Some(_), e) => return Err(VmError::Type(e.get_type(), pos)),
+            (None, Value::Real(r)) => match self.precision {
+                Some(p) => format!("{:.*}", p, r),
+                None => arg.to_string(),
+            },
+            (None, Value::StringValue(_)) | (None, Value::StringBuffer(_)) => {
+                let s = arg.to_string();
+                match self.precision {
+                    Some(p) => s.chars().take(p).collect(),
+                    None => s,
+                }
+            },
+            (None, _) => arg.to_string(),
+        };
+        let numeric = matches!(arg, Value::Integer(_) | Value::Real(_));
+        let pad = self.width.saturating_sub(body.chars().count());
+        if self.zero && numeric {
+            // zeros go between the sign and the digits
+            let (sign, digits) = body.split_at(if body.starts_with('-') { 1 } else { 0 });
+            out.push_str(sign);
+            out.extend(std::iter::repeat_n('0', pad));
+            out.push_str(digits);
+            return Ok(());
+        }
+        let align = self.align.unwrap_or(if numeric { '>' } else { '<' });
+        let (left, right) = match align {
+            '<' => (0, pad),
+            '^' => (pad / 2, pad - pad / 2),
+            _ => (pad, 0),
+        };
+        out.extend(std::iter::repeat_n(' ', left));
+        out.push_str(&body);
+        out.extend(std::iter::repeat_n(' ', right));
+        Ok(())
+    }
+}
+
+/// The number `s` starts with, if any, and the rest of `s`. `None` if the
+/// number is larger than `MAX_WIDTH`.
+fn take_number(s: &str) -> Option<(Option<usize>, &str)> {
+    let end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    if end == 0 {
+        return Some((None, s));
+    }
+    let n = s[..end].parse().ok().filter(|&n| n <= MAX_WIDTH)?;
+    Some((Some(n), &s[end..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::format;
+    use crate::datamodel::{Bytes, Function, List, Value};
+    use crate::machine::Vm;
+    use crate::operation::{assemble, Operation};
+    use crate::test_util::{function, string};
+    use crate::VmError;
+
+    #[test]
+    fn display_and_repr() {
+        let cases = [
+            (Value::None, "none", "none"),
+            (Value::Integer(-3), "-3", "-3"),
+            (Value::Real(1.0), "1.0", "1.0"),
+            (Value::Real(0.1 + 0.2), "0.30000000000000004", "0.30000000000000004"),
+            (Value::Real(f64::NEG_INFINITY), "-inf", "-inf"),
+            (Value::Char('\''), "'", "'\\''"),
+            (string("a\"b\n"), "a\"b\n", "\"a\\\"b\\n\""),
+            (Value::Bytes(Bytes::from_vec(b"x\0\"".to_vec())), "b\"x\\x00\\\"\"", "b\"x\\x00\\\"\""),
+        ];
+        for (value, display, repr) in cases.iter() {
+            assert_eq!(value.to_string(), *display);
+            assert_eq!(value.repr().to_string(), *repr);
+        }
+    }
+
+    #[test]
+    fn nested_and_cyclic_lists() {
+        let inner = List::from_vec(vec![string("s"), Value::Char('c')]);
+        let outer = List::from_vec(vec![Value::Integer(1), Value::List(inner.clone())]);
+        outer.push(Value::List(inner));
+        assert_eq!(Value::List(outer.clone()).to_string(), "[1, [\"s\", 'c'], [\"s\", 'c']]");
+
+        outer.push(Value::List(outer.clone()));
+        outer.push(Value::ListWeak(outer.downgrade()));
+        let text = format!("{:?}", Value::List(outer));
+        assert_eq!(text, "[1, [\"s\", 'c'], [\"s\", 'c'], [...], <weak [...]>]");
+    }
+
+    #[test]
+    fn to_string_opcode() {
+        let bytecode = assemble(&[
+            Operation::FrameLocalLoad(1),
+            Operation::ToString,
+            Operation::Return,
+        ]).unwrap();
+        let mut f = Function::new(List::from_vec(vec![]), Bytes::from_vec(bytecode));
+        f.name = Some("show".to_string());
+        let f = Value::Function(Rc::new(f));
+        let list = Value::List(List::from_vec(vec![Value::Real(2.5), f.clone()]));
+        match Vm::new().call(&f, &[list]) {
+            Ok(Value::StringValue(s)) => assert_eq!(s.as_str(), "[2.5, <fn show>]"),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn format_placeholders() {
+        let args = [Value::Integer(255), Value::Real(1.23456), string("héllo"), Value::Integer(-7)];
+        let out = format("{:x}|{:8.2}|{:^7.3}|{:04}|{{}}", &args).unwrap();
+        assert_eq!(out, "ff|    1.23|  hél  |-007|{}");
+        assert_eq!(format("{:?} {}", &[string("a"), Value::None]).unwrap(), "\"a\" none");
+
+        assert!(matches!(format("a {} {}", &[Value::None]), Err(VmError::Format(5))));
+        assert!(matches!(format("{:q}", &[Value::None]), Err(VmError::Format(0))));
+        assert!(matches!(format("{}", &[Value::None, Value::None]), Err(VmError::Arity(1, 2))));
+        assert!(matches!(format("{:x}", &[Value::Real(1.0)]), Err(VmError::Type(_, 0))));
+
+        // width and precision come from guest code
+        assert_eq!(format("{:.1024}", &[Value::Real(0.5)]).unwrap().len(), 1026);
+        assert!(matches!(format("x{:.70000}", &[Value::Real(0.5)]), Err(VmError::Format(1))));
+        assert!(matches!(format("{:100000000000000}", &[Value::None]), Err(VmError::Format(0))));
+        assert!(matches!(format("{:99999999999999999999999}", &[Value::None]), Err(VmError::Format(0))));
+    }
+
+    #[test]
+    fn string_builder_opcodes() {
+        let module = List::from_vec(vec![string("{} = {:.1}")]);
+        let f = Value::Function(function(module, &[
+            Operation::StringBufferCreate,
+            Operation::FrameStackCopy,
+            Operation::LiteralInteger(1),
+            Operation::StringAppend,
+            Operation::FrameStackCopy,
+            Operation::LiteralTrue,
+            Operation::StringAppend,
+            Operation::StringFreeze,
+            Operation::FrameLocalLoad(0),
+            Operation::LiteralInteger(0),
+            Operation::SeqGet,
+            Operation::FrameLocalLoad(1),
+            Operation::LiteralReal(0.25),
+            Operation::StringFormat(2),
+            Operation::StringConcat,
+            Operation::Return,
+        ]));
+        match Vm::new().call(&f, &[string("x")]) {
+            Ok(Value::StringValue(s)) => assert_eq!(s.as_str(), "1truex = 0.2"),
+            _ => panic!(),
+        }
+    }
+}
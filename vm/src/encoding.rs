@@ -0,0 +1,131 @@
+use std::convert::TryFrom;
+use std::str;
+
+use crate::VmError;
+
+/// Text encodings understood by `BYTES_DECODE` and `STR_ENCODE`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Utf8,
+    /// Replaces invalid sequences with U+FFFD when decoding; encodes as UTF-8.
+    Utf8Lossy,
+    Latin1,
+    Utf16Le,
+    Utf16Be,
+}
+
+impl Encoding {
+    pub fn from_u8(t: u8) -> Option<Encoding> {
+        match t {
+            0 => Some(Encoding::Utf8),
+            1 => Some(Encoding::Utf8Lossy),
+            2 => Some(Encoding::Latin1),
+            3 => Some(Encoding::Utf16Le),
+            4 => Some(Encoding::Utf16Be),
+            _ => None,
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_name(name: &str) -> Option<Encoding> {
+        match name.to_ascii_lowercase().as_str() {
+            "utf-8" | "utf8" => Some(Encoding::Utf8),
+            "utf-8-lossy" | "utf8-lossy" => Some(Encoding::Utf8Lossy),
+            "latin-1" | "latin1" | "iso-8859-1" => Some(Encoding::Latin1),
+            "utf-16le" | "utf16le" => Some(Encoding::Utf16Le),
+            "utf-16be" | "utf16be" => Some(Encoding::Utf16Be),
+            _ => None,
+        }
+    }
+
+    /// Fails with `VmError::Decode` at the byte offset of the first invalid
+    /// sequence.
+    pub fn decode(self, bytes: &[u8]) -> Result<String, VmError> {
+        match self {
+            Encoding::Utf8 => str::from_utf8(bytes)
+                .map(str::to_string)
+                .map_err(|e| VmError::Decode(e.valid_up_to())),
+            Encoding::Utf8Lossy => Ok(String::from_utf8_lossy(bytes).into_owned()),
+            Encoding::Latin1 => Ok(bytes.iter().map(|&b| b as char).collect()),
+            Encoding::Utf16Le => decode_utf16(bytes, u16::from_le_bytes),
+            Encoding::Utf16Be => decode_utf16(bytes, u16::from_be_bytes),
+        }
+    }
+
+    /// Fails with `VmError::Encode` at the byte offset in `s` of the first
+    /// char the encoding cannot represent.
+    pub fn encode(self, s: &str) -> Result<Vec<u8>, VmError> {
+        match self {
+            Encoding::Utf8 | Encoding::Utf8Lossy => Ok(s.as_bytes().to_vec()),
+            Encoding::Latin1 => s.char_indices()
+                .map(|(i, c)| u8::try_from(c).map_err(|_| VmError::Encode(i)))
+                .collect(),
+            Encoding::Utf16Le => Ok(s.encode_utf16().flat_map(u16::to_le_bytes).collect()),
+            Encoding::Utf16Be => Ok(s.encode_utf16().flat_map(u16::to_be_bytes).collect()),
+        }
+    }
+}
+
+fn decode_utf16(bytes: &[u8], unit: fn([u8; 2]) -> u16) -> Result<String, VmError> {
+    if !bytes.len().is_multiple_of(2) {
+        return Err(VmError::Decode(bytes.len() - 1));
+    }
+    let units = bytes.chunks(2).map(|t| unit([t[0], t[1]]));
+    let mut out = String::with_capacity(bytes.len() / 2);
+    let mut offset = 0;
+    for c in char::decode_utf16(units) {
+        let c = c.map_err(|_| VmError::Decode(offset))?;
+        offset += c.len_utf16() * 2;
+        out.push(c);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Encoding;
+    use crate::datamodel::{List, Value};
+    use crate::machine::Vm;
+    use crate::operation::Operation;
+    use crate::test_util::{function, string};
+    use crate::VmError;
+
+    #[test]
+    fn decode_and_encode() {
+        let text = "aé\u{1f600}";
+        for e in [Encoding::Utf8, Encoding::Utf16Le, Encoding::Utf16Be].iter() {
+            let bytes = e.encode(text).unwrap();
+            assert_eq!(e.decode(&bytes).unwrap(), text);
+        }
+        assert_eq!(Encoding::Utf16Be.encode("a").unwrap(), [0, 0x61]);
+        assert_eq!(Encoding::Latin1.decode(&[0x61, 0xe9]).unwrap(), "aé");
+        assert_eq!(Encoding::Latin1.encode("aé").unwrap(), [0x61, 0xe9]);
+        assert_eq!(Encoding::Utf8Lossy.decode(b"a\xffb").unwrap(), "a\u{fffd}b");
+
+        assert!(matches!(Encoding::Utf8.decode(b"ab\xc3"), Err(VmError::Decode(2))));
+        assert!(matches!(Encoding::Utf16Le.decode(&[0x61, 0, 0x00, 0xd8, 0x61, 0]), Err(VmError::Decode(2))));
+        assert!(matches!(Encoding::Utf16Le.decode(&[0x61]), Err(VmError::Decode(0))));
+        assert!(matches!(Encoding::Latin1.encode("a\u{1f600}"), Err(VmError::Encode(1))));
+    }
+
+    #[test]
+    fn encoding_opcodes() {
+        let f = Value::Function(function(List::from_vec(vec![]), &[
+            Operation::FrameLocalLoad(1),
+            Operation::StringEncode(Encoding::Utf16Le),
+            Operation::BytesDecode(Encoding::Utf16Le),
+            Operation::StringEncode(Encoding::Latin1),
+            Operation::BytesDecode(Encoding::Utf8),
+            Operation::Return,
+        ]));
+        let mut vm = Vm::new();
+        let ascii = string("plain");
+        assert_eq!(vm.call(&f, &[ascii]).unwrap().to_string(), "plain");
+        // é survives UTF-16 but its Latin-1 byte is not valid UTF-8
+        let accented = string("né");
+        assert!(matches!(vm.call(&f, &[accented]), Err(VmError::Decode(1))));
+    }
+}
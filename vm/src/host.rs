@@ -0,0 +1,181 @@
+use std::any::Any;
+use std::cmp::Ordering;
+use std::rc::Rc;
+
+use crate::convert::{ConvertError, FromValue};
+use crate::datamodel::{Value, ValueType};
+use crate::machine::VmContext;
+use crate::VmError;
+
+/// A host type exposed to guest code as `Value::Unknown`.
+///
+/// Only `type_name` is required; objects without methods can still be
+/// passed around and handed back to natives.
+pub trait HostObject: Any {
+    fn type_name(&self) -> &str;
+
+    /// Invoked by `CALL_METHOD`.
+    fn call_method(&self, ctx: &mut VmContext, name: &str, args: &[Value]) -> Result<Value, VmError> {
+        let _ = (ctx, args);
+        Err(VmError::NoMethod(self.type_name().to_string(), name.to_string()))
+    }
+
+    fn display(&self) -> Option<String> {
+        None
+    }
+
+    /// Ordering against another host object, consulted when the two are not
+    /// the same object.
+    fn host_cmp(&self, other: &dyn HostObject) -> Option<Ordering> {
+        let _ = other;
+        None
+    }
+
+    /// Hash for `Value::hash_code`, which must agree with `host_cmp`.
+    /// Without it, host objects cannot be hashed at all.
+    fn host_hash(&self) -> Option<u64> {
+        None
+    }
+}
+
+impl dyn HostObject {
+    pub fn downcast_ref<T: HostObject>(&self) -> Option<&T> {
+        let any: &dyn Any = self;
+        any.downcast_ref()
+    }
+}
+
+impl Value {
+    pub fn from_host<T: HostObject>(object: T) -> Value {
+        Value::Unknown(Rc::new(object))
+    }
+
+    pub fn as_host<T: HostObject>(&self) -> Option<&T> {
+        match self {
+            Value::Unknown(t) => t.downcast_ref(),
+            _ => None,
+        }
+    }
+
+    pub fn to_host<T: HostObject>(&self) -> Option<Rc<T>> {
+        match self {
+            Value::Unknown(t) => {
+                let any: Rc<dyn Any> = t.clone();
+                any.downcast().ok()
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Lets natives take host objects as typed `Rc<T>` parameters.
+impl<T: HostObject> FromValue for Rc<T> {
+    fn from_value(value: &Value) -> Result<Rc<T>, ConvertError> {
+        value.to_host().ok_or(ConvertError::Type {
+            expected: ValueType::Unknown,
+            found: value.get_type(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::cmp::Ordering;
+    use std::rc::Rc;
+
+    use super::HostObject;
+    use crate::convert::ConvertError;
+    use crate::datamodel::{List, StringBuffer, Value};
+    use crate::machine::{Vm, VmContext};
+    use crate::native::IntoNative;
+    use crate::operation::Operation;
+    use crate::test_util::{function, string};
+    use crate::VmError;
+
+    struct Counter(Cell<i64>);
+
+    impl HostObject for Counter {
+        fn type_name(&self) -> &str {
+            "Counter"
+        }
+
+        fn call_method(&self, _: &mut VmContext, name: &str, args: &[Value]) -> Result<Value, VmError> {
+            match (name, args) {
+                ("add", [Value::Integer(n)]) => self.0.set(self.0.get() + n),
+                ("get", []) => (),
+                _ => return Err(VmError::NoMethod("Counter".to_string(), name.to_string())),
+            }
+            Ok(Value::Integer(self.0.get()))
+        }
+
+        fn host_cmp(&self, other: &dyn HostObject) -> Option<Ordering> {
+            Some(self.0.get().cmp(&other.downcast_ref::<Counter>()?.0.get()))
+        }
+
+        fn host_hash(&self) -> Option<u64> {
+            Some(self.0.get() as u64)
+        }
+    }
+
+    struct Opaque;
+
+    impl HostObject for Opaque {
+        fn type_name(&self) -> &str {
+            "Opaque"
+        }
+    }
+
+    #[test]
+    fn call_method_opcode() {
+        let module = List::from_vec(vec![string("add"), string("get")]);
+        let main = Value::Function(function(module, &[
+            Operation::LiteralInteger(5),
+            Operation::FrameLocalLoad(0),
+            Operation::LiteralInteger(0),
+            Operation::SeqGet,
+            Operation::FrameLocalLoad(1),
+            Operation::CallMethod(1),
+            Operation::FrameStackPop,
+            Operation::FrameLocalLoad(0),
+            Operation::LiteralInteger(1),
+            Operation::SeqGet,
+            Operation::FrameLocalLoad(1),
+            Operation::CallMethod(0),
+            Operation::Return,
+        ]));
+        let counter = Value::from_host(Counter(Cell::new(1)));
+        let mut vm = Vm::new();
+        assert!(matches!(vm.call(&main, std::slice::from_ref(&counter)), Ok(Value::Integer(6))));
+        assert_eq!(counter.as_host::<Counter>().unwrap().0.get(), 6);
+
+        let opaque = Value::from_host(Opaque);
+        assert!(matches!(vm.call(&main, &[opaque]), Err(VmError::NoMethod(..))));
+    }
+
+    #[test]
+    fn downcast_and_compare() {
+        let a = Value::from_host(Counter(Cell::new(1)));
+        let b = Value::from_host(Counter(Cell::new(2)));
+        assert_eq!(a.cmp(&b), Some(Ordering::Less));
+        assert_eq!(a.cmp(&a.clone()), Some(Ordering::Equal));
+        assert_eq!(a.cmp(&Value::from_host(Opaque)), None);
+        assert!(a.as_host::<Opaque>().is_none());
+
+        let c = Value::from_host(Counter(Cell::new(2)));
+        assert_eq!(b.cmp(&c), Some(Ordering::Equal));
+        assert_eq!(b.hash_code(), c.hash_code());
+        assert_ne!(a.hash_code(), b.hash_code());
+        assert_eq!(Value::from_host(Opaque).hash_code(), None);
+        assert_eq!(string("ab").hash_code(), Value::StringBuffer(StringBuffer::from_string("ab".to_string())).hash_code());
+        assert_eq!(Value::Real(0.0).hash_code(), Value::Real(-0.0).hash_code());
+        assert_eq!(Value::Real(f64::NAN).hash_code(), None);
+
+        let get = (|c: Rc<Counter>| c.0.get()).into_native();
+        let mut vm = Vm::new();
+        assert!(matches!(vm.call(&Value::NativeFunction(get.clone()), &[b]), Ok(Value::Integer(2))));
+        assert!(matches!(
+            vm.call(&Value::NativeFunction(get), &[Value::from_host(Opaque)]),
+            Err(VmError::Convert(ConvertError::Type { .. }, 0))));
+    }
+}
@@ -0,0 +1,482 @@
+use std::fmt::Write;
+
+use crate::datamodel::{List, StringValue, Value};
+use crate::VmError;
+
+/// Arrays and objects nested deeper than this fail to parse, and lists
+/// nested deeper fail to encode, since both recurse on the host stack.
+pub const MAX_DEPTH: usize = 512;
+
+/// How `encode` lays out its output.
+#[derive(Clone, Debug)]
+pub struct JsonFormat {
+    /// Spaces per nesting level, with one item per line; `None` writes
+    /// everything on one line without spaces.
+    pub indent: Option<usize>,
+    /// Writes a non-empty list whose items are all `[key, value]` lists
+    /// with string keys as an object, which is what `decode` turns objects
+    /// into. Without it, every list is an array.
+    pub pairs_as_objects: bool,
+}
+
+impl Default for JsonFormat {
+    fn default() -> JsonFormat {
+        JsonFormat { indent: None, pairs_as_objects: true }
+    }
+}
+
+/// Writes `value` as JSON. Lists become arrays or objects, strings, string
+/// buffers and chars become strings, and `None` becomes `null`. Other types
+/// and reals that are not finite fail with `VmError::Serialize`, a list
+/// that contains itself with `VmError::Cycle`, and lists nested deeper than
+/// `MAX_DEPTH` with `VmError::StackOverflow`.
+pub fn encode(value: &Value, format: &JsonFormat) -> Result<String, VmError> {
+    let mut out = String::new();
+    Writer { format, out: &mut out, path: vec![] }.value(value, 0)?;
+    Ok(out)
+}
+
+/// Parses JSON text. Arrays become lists, objects become lists of
+/// `[key, value]` lists in document order, and numbers become integers when
+/// they are written without a fraction or exponent and fit, reals otherwise.
+/// Invalid text fails with `VmError::Decode` at the byte offset where it
+/// stops being valid; `position` turns that into a line and column.
+pub fn decode(text: &str) -> Result<Value, VmError> {
+    let mut parser = Parser { text: text.as_bytes(), pos: 0 };
+    let out = parser.value(0)?;
+    parser.skip_whitespace();
+    if parser.pos != text.len() {
+        return Err(parser.error());
+    }
+    Ok(out)
+}
+
+/// One-based line and column, in chars, of a byte offset into `text`. An
+/// offset inside a char counts as the start of that char.
+pub fn position(text: &str, offset: usize) -> (usize, usize) {
+    let mut end = offset.min(text.len());
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    let before = &text[..end];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    let line = before.matches('\n').count() + 1;
+    (line, before[line_start..].chars().count() + 1)
+}
+
+struct Writer<'a> {
+    format: &'a JsonFormat,
+    out: &'a mut String,
+    /// Lists being written, to catch cycles.
+    path: Vec<*const ()>,
+}
+
+impl<'a> Writer<'a> {
+    fn value(&mut self, value: &Value, depth: usize) -> Result<(), VmError> {
+        match value {
+            Value::None => self.out.push_str("null"),
+            Value::Bool(b) => write!(self.out, "{}", b).unwrap(),
+            Value::Integer(i) => write!(self.out, "{}", i).unwrap(),
+            Value::Real(r) if r.is_finite() => write!(self.out, "{:?}", r).unwrap(),
+            Value::Char(c) => self.string(c.encode_utf8(&mut [0; 4])),
+            Value::StringValue(s) => self.string(s.as_str()),
+            Value::StringBuffer(s) => self.string(&s.0.borrow()),
+            Value::List(l) => {
+                let ptr = l.0.as_ptr() as *const ();
+                if self.path.contains(&ptr) {
+                    return Err(VmError::Cycle);
+                }
+                if depth == MAX_DEPTH {
+                    return Err(VmError::StackOverflow);
+                }
+                self.path.push(ptr);
+                let items = l.0.borrow();
+                match self.pairs(&items) {
+                    Some(pairs) => self.object(&pairs, depth)?,
+                    None => self.array(&items, depth)?,
+                }
+                self.path.pop();
+            },
+            _ => return Err(VmError::Serialize(value.get_type())),
+        }
+        Ok(())
+    }
+
+    /// The key and value of each item, if the list should be an object.
+    fn pairs(&self, items: &[Value]) -> Option<Vec<(StringValue, Value)>> {
+        if !self.format.pairs_as_objects || items.is_empty() {
+            return None;
+        }
+        items.iter().map(|t| match t {
+            Value::List(pair) => match pair.0.borrow().as_slice() {
+                [Value::StringValue(k), v] => Some((k.clone(), v.clone())),
+                _ => None,
+            },
+            _ => None,
+        }).collect()
+    }
+
+    fn array(&mut self, items: &[Value], depth: usize) -> Result<(), VmError> {
+        self.out.push('[');
+        for (i, t) in items.iter().enumerate() {
+            self.separator(i, depth + 1);
+            self.value(t, depth + 1)?;
+        }
+        self.close(items.len(), depth, ']');
+        Ok(())
+    }
+
+    fn object(&mut self, pairs: &[(StringValue, Value)], depth: usize) -> Result<(), VmError> {
+        self.out.push('{');
+        for (i, (k, v)) in pairs.iter().enumerate() {
+            self.separator(i, depth + 1);
+            self.string(k.as_str());
+            self.out.push_str(if self.format.indent.is_some() { ": " } else { ":" });
+            self.value(v, depth + 1)?;
+        }
+        self.close(pairs.len(), depth, '}');
+        Ok(())
+    }
+
+    fn separator(&mut self, i: usize, depth: usize) {
+        if i > 0 {
+            self.out.push(',');
+        }
+        self.newline(depth);
+    }
+
+    fn close(&mut self, len: usize, depth: usize, bracket: char) {
+        if len > 0 {
+            self.newline(depth);
+        }
+        self.out.push(bracket);
+    }
+
+    fn newline(&mut self, depth: usize) {
+        if let Some(indent) = self.format.indent {
+            self.out.push('\n');
+            self.out.extend(std::iter::repeat_n(' ', indent * depth));
+        }
+    }
+
+    fn string(&mut self, s: &str) {
+        self.out.push('"');
+        for c in s.chars() {
+            match c {
+                '"' => self.out.push_str("\\\""),
+                '\\' => self.out.push_str("\\\\"),
+                '\n' => self.out.push_str("\\n"),
+                '\r' => self.out.push_str("\\r"),
+                '\t' => self.out.push_str("\\t"),
+                '\u{8}' => self.out.push_str("\\b"),
+                '\u{c}' => self.out.push_str("\\f"),
+                c if c < ' ' => write!(self.out, "\\u{:04x}", c as u32).unwrap(),
+                c => self.out.push(c),
+            }
+        }
+        self.out.push('"');
+    }
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self) -> VmError {
+        VmError::Decode(self.pos)
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.text.get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, b: u8) -> Result<(), VmError> {
+        if self.peek() != Some(b) {
+            return Err(self.error());
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value, VmError> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'{') | Some(b'[') if depth == MAX_DEPTH => Err(self.error()),
+            Some(b'{') => self.object(depth + 1),
+            Some(b'[') => self.array(depth + 1),
+            Some(b'"') => Ok(Value::StringValue(StringValue::from_string(self.string()?))),
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            _ => {
+                for (word, value) in [("null", Value::None), ("true", Value::Bool(true)), ("false", Value::Bool(false))] {
+                    if self.text[self.pos..].starts_with(word.as_bytes()) {
+                        self.pos += word.len();
+                        return Ok(value);
+                    }
+                }
+                Err(self.error())
+            },
+        }
+    }
+
+    /// Calls `item` for each comma separated item up to `close`.
+    fn items<F>(&mut self, close: u8, mut item: F) -> Result<(), VmError>
+    where F: FnMut(&mut Parser<'a>) -> Result<(), VmError> {
+        self.pos += 1;
+        self.skip_whitespace();
+        if self.peek() == Some(close) {
+            self.pos += 1;
+            return Ok(());
+        }
+        loop {
+            item(self)?;
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b) if b == close => {
+                    self.pos += 1;
+                    return Ok(());
+                },
+                _ => return Err(self.error()),
+            }
+        }
+    }
+
+    fn array(&mut self, depth: usize) -> Result<Value, VmError> {
+        let mut out = vec![];
+        self.items(b']', |p| {
+            out.push(p.value(depth)?);
+            Ok(())
+        })?;
+        Ok(Value::List(List::from_vec(out)))
+    }
+
+    fn object(&mut self, depth: usize) -> Result<Value, VmError> {
+        let mut out = vec![];
+        self.items(b'}', |p| {
+            p.skip_whitespace();
+            if p.peek() != Some(b'"') {
+                return Err(p.error());
+            }
+            let key = Value::StringValue(StringValue::from_string(p.string()?));
+            p.skip_whitespace();
+            p.expect(b':')?;
+            let value = p.value(depth)?;
+            out.push(Value::List(List::from_vec(vec![key, value])));
+            Ok(())
+        })?;
+        Ok(Value::List(List::from_vec(out)))
+    }
+
+    fn string(&mut self) -> Result<String, VmError> {
+        self.pos += 1;
+        let mut out = String::new();
+        loop {
+            let start = self.pos;
+            while let Some(b) = self.peek() {
+                if b == b'"' || b == b'\\' || b < 0x20 {
+                    break;
+                }
+                self.pos += 1;
+            }
+            // runs between quotes and escapes start and end on char
+            // boundaries of the original str
+            out.push_str(std::str::from_utf8(&self.text[start..self.pos]).unwrap());
+            match self.peek() {
+                Some(b'"') => {
+                    self.pos += 1;
+                    return Ok(out);
+                },
+                Some(b'\\') => {
+                    self.pos += 1;
+                    let c = match self.peek() {
+                        Some(b'"') => '"',
+                        Some(b'\\') => '\\',
+                        Some(b'/') => '/',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{c}',
+                        Some(b'n') => '\n',
+                        Some(b'r') => '\r',
+                        Some(b't') => '\t',
+                        Some(b'u') => {
+                            out.push(self.unicode_escape()?);
+                            continue;
+                        },
+                        _ => return Err(self.error()),
+                    };
+                    self.pos += 1;
+                    out.push(c);
+                },
+                _ => return Err(self.error()),
+            }
+        }
+    }
+
+    /// Reads the `uXXXX` after a backslash, and the low surrogate escape
+    /// following a high one.
+    fn unicode_escape(&mut self) -> Result<char, VmError> {
+        let start = self.pos - 1;
+        let high = self.hex4()?;
+        let code = match high {
+            0xd800..=0xdbff => {
+                if !self.text[self.pos..].starts_with(b"\\u") {
+                    return Err(VmError::Decode(start));
+                }
+                self.pos += 1;
+                let low = self.hex4()?;
+                if !(0xdc00..=0xdfff).contains(&low) {
+                    return Err(VmError::Decode(self.pos - 6));
+                }
+                0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+            },
+            0xdc00..=0xdfff => return Err(VmError::Decode(start)),
+            t => t,
+        };
+        Ok(char::from_u32(code).unwrap())
+    }
+
+    fn hex4(&mut self) -> Result<u32, VmError> {
+        self.pos += 1;
+        let digits = self.text.get(self.pos..self.pos + 4).ok_or_else(|| self.error())?;
+        let mut out = 0;
+        for (i, &d) in digits.iter().enumerate() {
+            let d = (d as char).to_digit(16).ok_or(VmError::Decode(self.pos + i))?;
+            out = out * 16 + d;
+        }
+        self.pos += 4;
+        Ok(out)
+    }
+
+    fn digits(&mut self) -> usize {
+        let start = self.pos;
+        while let Some(b'0'..=b'9') = self.peek() {
+            self.pos += 1;
+        }
+        self.pos - start
+    }
+
+    fn number(&mut self) -> Result<Value, VmError> {
+        let start = self.pos;
+        if self.peek() == Some(b'-') {
+            self.pos += 1;
+        }
+        match self.peek() {
+            Some(b'0') => self.pos += 1,
+            Some(b'1'..=b'9') => {
+                self.digits();
+            },
+            _ => return Err(self.error()),
+        }
+        let mut integral = true;
+        if self.peek() == Some(b'.') {
+            integral = false;
+            self.pos += 1;
+            if self.digits() == 0 {
+                return Err(self.error());
+            }
+        }
+        if let Some(b'e' | b'E') = self.peek() {
+            integral = false;
+            self.pos += 1;
+            if let Some(b'+' | b'-') = self.peek() {
+                self.pos += 1;
+            }
+            if self.digits() == 0 {
+                return Err(self.error());
+            }
+        }
+        let t = std::str::from_utf8(&self.text[start..self.pos]).unwrap();
+        if integral {
+            if let Ok(i) = t.parse() {
+                return Ok(Value::Integer(i));
+            }
+        }
+        Ok(Value::Real(t.parse().unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode, position, JsonFormat, MAX_DEPTH};
+    use crate::datamodel::{List, Value, ValueType};
+    use crate::VmError;
+
+    fn round_trip(text: &str) -> String {
+        encode(&decode(text).unwrap(), &JsonFormat::default()).unwrap()
+    }
+
+    #[test]
+    fn parse_and_write() {
+        let text = r#" {"a": [1, -2.5, 1e3, 99999999999999999999], "b\n\u00e9\ud83d\ude00": {"c": null},
+            "d": [true, false, [], {}, "\\\"/"]} "#;
+        assert_eq!(round_trip(text),
+            r#"{"a":[1,-2.5,1000.0,1e20],"b\né😀":{"c":null},"d":[true,false,[],[],"\\\"/"]}"#);
+        assert_eq!(decode(r#"{"k": [0]}"#).unwrap().to_string(), r#"[["k", [0]]]"#);
+        assert_eq!(round_trip("\"\\u0001\\t\""), "\"\\u0001\\t\"");
+
+        let pretty = JsonFormat { indent: Some(2), ..JsonFormat::default() };
+        let out = encode(&decode(r#"{"a":[1,{"b":[]}]}"#).unwrap(), &pretty).unwrap();
+        assert_eq!(out, "{\n  \"a\": [\n    1,\n    {\n      \"b\": []\n    }\n  ]\n}");
+        let arrays = JsonFormat { pairs_as_objects: false, ..JsonFormat::default() };
+        assert_eq!(encode(&decode(r#"{"a":1}"#).unwrap(), &arrays).unwrap(), r#"[["a",1]]"#);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let cases: &[(&str, usize)] = &[
+            ("", 0),
+            ("[1,]", 3),
+            ("[1 2]", 3),
+            ("{\"a\" 1}", 5),
+            ("{1: 2}", 1),
+            ("01", 1),
+            ("1.", 2),
+            ("-", 1),
+            ("\"abc", 4),
+            ("\"a\nb\"", 2),
+            ("\"\\x\"", 2),
+            ("\"\\ud800\"", 1),
+            ("\"\\ud800\\u0041\"", 7),
+            ("\"\\u12g4\"", 5),
+            ("nul", 0),
+            ("[1] x", 4),
+        ];
+        for &(text, offset) in cases {
+            match decode(text) {
+                Err(VmError::Decode(t)) => assert_eq!(t, offset, "{:?}", text),
+                _ => panic!("{:?} parsed", text),
+            }
+        }
+        let deep = "[".repeat(MAX_DEPTH + 1);
+        assert!(matches!(decode(&deep), Err(VmError::Decode(t)) if t == MAX_DEPTH));
+        assert_eq!(position("[1,\n  é x]", 9), (2, 5));
+        assert_eq!(position("[1,\n  é x]", 7), (2, 3));
+        assert_eq!(position("é", 99), (1, 2));
+    }
+
+    #[test]
+    fn encode_errors() {
+        let list = List::from_vec(vec![Value::Integer(1)]);
+        let shared = Value::List(List::from_vec(vec![Value::List(list.clone()), Value::List(list.clone())]));
+        assert_eq!(encode(&shared, &JsonFormat::default()).unwrap(), "[[1],[1]]");
+        list.push(Value::List(list.clone()));
+        assert!(matches!(encode(&shared, &JsonFormat::default()), Err(VmError::Cycle)));
+        list.resize(0);
+
+        let nan = Value::List(List::from_vec(vec![Value::Real(f64::NAN)]));
+        assert!(matches!(encode(&nan, &JsonFormat::default()), Err(VmError::Serialize(ValueType::Real))));
+        let range = Value::Range(crate::datamodel::Range::new(0, 1, 1).unwrap());
+        assert!(matches!(encode(&range, &JsonFormat::default()), Err(VmError::Serialize(ValueType::Range))));
+
+        let nest = |n| (0..n).fold(Value::None, |t, _| Value::List(List::from_vec(vec![t])));
+        assert!(encode(&nest(MAX_DEPTH), &JsonFormat::default()).is_ok());
+        assert!(matches!(encode(&nest(MAX_DEPTH + 1), &JsonFormat::default()), Err(VmError::StackOverflow)));
+    }
+}
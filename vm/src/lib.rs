@@ -1,19 +1,49 @@
+pub mod convert;
+pub mod coroutine;
 pub mod datamodel;
+pub mod debugger;
+pub mod display;
+pub mod encoding;
+pub mod host;
+pub mod json;
 pub mod machine;
+pub mod native;
 pub mod operation;
+pub mod optimize;
+pub mod pack;
+pub mod profiler;
+pub mod snapshot;
+pub mod suspend;
+#[cfg(test)]
+pub(crate) mod test_util;
+#[cfg(feature = "trace")]
+pub mod trace;
+pub mod unicode;
 
 use std::rc::Rc;
 
-use datamodel::{Function, NativeFn, Value, ValueType};
+use convert::ConvertError;
+use coroutine::Coroutine;
+use datamodel::{Function, NativeFn, NativeFunction, StringValue, Value, ValueType};
+use host::HostObject;
 
 pub enum VmAction {
     None,
     Jump(i32),
     Call(Rc<Function>, Vec<Value>),
     CallNative(NativeFn, Vec<Value>),
+    CallNativeFunction(NativeFunction, Vec<Value>),
+    CallMethod(Rc<dyn HostObject>, StringValue, Vec<Value>),
     Return(Value),
+    /// Call that replaces the current frame instead of pushing one.
+    TailCall(Value, Vec<Value>),
+    Yield(Value),
+    Resume(Coroutine, Value),
+    /// `FOR_ITER` over a coroutine, with the jump taken once it finishes.
+    ResumeNext(Coroutine, i32),
 }
 
+#[derive(Debug)]
 pub enum VmError {
     StackEmpty,
     DivByZero,
@@ -23,6 +53,48 @@ pub enum VmError {
     SliceRead(i64, i64),
     BytecodeRead(usize),
     Type(ValueType, u8),
+    StackOverflow,
+    Convert(ConvertError, u8),
+    /// Expected and actual number of arguments.
+    Arity(usize, usize),
+    /// Error raised by host code in a native function.
+    Native(String),
+    /// Host object type name and the method it lacks.
+    NoMethod(String, String),
+    /// Malformed placeholder, or one without an argument, at this byte
+    /// offset of a `STR_FORMAT` template.
+    Format(usize),
+    /// String byte offset that does not fall on a char boundary.
+    CharBoundary(i64),
+    /// Integer that is not a Unicode scalar value.
+    CodePoint(i64),
+    /// Byte offset of the first invalid sequence in decoded bytes.
+    Decode(usize),
+    /// Byte offset of the first char the target encoding cannot represent.
+    Encode(usize),
+    /// `RANGE_NEW` with a step of zero.
+    ZeroStep,
+    /// `YIELD` outside a coroutine.
+    Yield,
+    /// Resumed a coroutine that has finished, failed or is already running.
+    Resume,
+    /// Returned by a native function whose result is not ready yet, with a
+    /// value telling the host what it waits on. See `CallStack::run_async`.
+    Pending(Value),
+    /// `Pending` from a native that host code called, where the call cannot
+    /// be suspended.
+    Suspend,
+    /// Value of a type that cannot be serialized, or a native missing from
+    /// the table given to `snapshot::save`.
+    Serialize(ValueType),
+    /// List that contains itself, in a format that cannot refer back.
+    Cycle,
+}
+
+impl From<String> for VmError {
+    fn from(message: String) -> VmError {
+        VmError::Native(message)
+    }
 }
 
 #[cfg(test)]
@@ -1,44 +1,505 @@
 use std::mem;
+use std::rc::Rc;
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::convert::TryFrom;
 
-use crate::datamodel::{Bytes, Function, Value};
-use crate::VmError;
+use crate::coroutine::{Coroutine, Resumed};
+use crate::datamodel::{BytesBuffer, Function, List, StringBuffer, StringValue, Value};
+use crate::operation::{parse_and_run, run_many};
+use crate::profiler::Profiler;
+use crate::suspend::{Poll, Suspended};
+#[cfg(feature = "trace")]
+use crate::operation::decode;
+#[cfg(feature = "trace")]
+use crate::trace::{TraceEvent, Tracer};
+use crate::{VmAction, VmError};
+
+/// Nested host calls allowed before `VmError::StackOverflow`, since each
+/// level of native reentrancy recurses on the host stack.
+pub const MAX_NESTING: usize = 128;
+
+pub struct Vm {
+    nesting: usize,
+    host_data: HashMap<TypeId, Box<dyn Any>>,
+    allocations: usize,
+    profiler: Option<Box<Profiler>>,
+    #[cfg(feature = "trace")]
+    tracer: Option<Box<dyn Tracer>>,
+    /// Frames of the traced stacks a nested stack runs on top of.
+    #[cfg(feature = "trace")]
+    trace_depth: usize,
+}
+
+impl Default for Vm {
+    fn default() -> Vm {
+        Vm::new()
+    }
+}
+
+impl Vm {
+    pub fn new() -> Vm {
+        Vm {
+            nesting: 0,
+            host_data: HashMap::new(),
+            allocations: 0,
+            profiler: None,
+            #[cfg(feature = "trace")]
+            tracer: None,
+            #[cfg(feature = "trace")]
+            trace_depth: 0,
+        }
+    }
+
+    /// Traces every instruction of the stacks this VM runs, including the
+    /// ones natives call back into.
+    #[cfg(feature = "trace")]
+    pub fn set_tracer(&mut self, tracer: Option<Box<dyn Tracer>>) {
+        self.tracer = tracer;
+    }
+
+    /// Values natives have created through `VmContext::new_*` so far.
+    pub fn get_allocations(&self) -> usize {
+        self.allocations
+    }
+
+    fn alloc(&mut self, value: Value) -> Value {
+        self.allocations += 1;
+        value
+    }
+
+    /// Stores one value per type for native functions to reach through
+    /// their `VmContext`, replacing any previous value of that type.
+    pub fn set_host_data<T: Any>(&mut self, data: T) {
+        self.host_data.insert(TypeId::of::<T>(), Box::new(data));
+    }
+
+    pub fn get_host_data<T: Any>(&self) -> Option<&T> {
+        self.host_data.get(&TypeId::of::<T>())?.downcast_ref()
+    }
+
+    pub fn get_host_data_mut<T: Any>(&mut self) -> Option<&mut T> {
+        self.host_data.get_mut(&TypeId::of::<T>())?.downcast_mut()
+    }
+
+    /// Calls a `Function`, `NativeFn` or `NativeFunction` to completion.
+    /// Closures are functions whose module holds their captured values.
+    pub fn call(&mut self, target: &Value, args: &[Value]) -> Result<Value, VmError> {
+        if self.nesting >= MAX_NESTING {
+            return Err(VmError::StackOverflow);
+        }
+        self.nesting += 1;
+        let out = match target {
+            Value::Function(f) => {
+                let mut stack = CallStack::new(f.clone(), args.to_vec());
+                self.run_nested(&mut stack, true, CallStack::run).map_err(unsuspendable)
+            },
+            Value::NativeFn(f) => f(args.to_vec()),
+            Value::NativeFunction(f) => (f.0)(&mut VmContext { vm: self }, args),
+            _ => Err(VmError::Type(target.get_type(), 0)),
+        };
+        self.nesting -= 1;
+        out
+    }
+
+    /// Continues a coroutine until it yields or returns. The first resume
+    /// starts it and discards `input`; later ones make `input` the result of
+    /// the `YIELD` it is suspended at.
+    pub fn resume(&mut self, co: &Coroutine, input: Value) -> Result<Resumed, VmError> {
+        if self.nesting >= MAX_NESTING {
+            return Err(VmError::StackOverflow);
+        }
+        let mut stack = co.take_stack().ok_or(VmError::Resume)?;
+        let started = co.start();
+        if started {
+            stack.frames.last_mut().ok_or(VmError::StackEmpty)?.push(input);
+        }
+        self.nesting += 1;
+        let out = self.run_nested(&mut stack, !started, CallStack::run_until_yield)
+            .map_err(unsuspendable);
+        self.nesting -= 1;
+        if let Ok(Resumed::Yield(_)) = out {
+            co.put_stack(stack);
+        }
+        out
+    }
+
+    pub(crate) fn replace_profiler(&mut self, profiler: Option<Box<Profiler>>) -> Option<Box<Profiler>> {
+        mem::replace(&mut self.profiler, profiler)
+    }
+
+    /// Runs a stack started by the host, profiling it on top of the stacks
+    /// below if a profiler is running.
+    fn run_nested<T>(
+        &mut self,
+        stack: &mut CallStack,
+        fresh: bool,
+        run: fn(&mut CallStack, &mut Vm) -> Result<T, VmError>,
+    ) -> Result<T, VmError> {
+        let base = match &mut self.profiler {
+            Some(profiler) => profiler.enter_frames(stack, fresh),
+            None => return run(stack, self),
+        };
+        let out = run(stack, self);
+        if let Some(profiler) = &mut self.profiler {
+            profiler.leave_frames(base);
+        }
+        out
+    }
+}
+
+/// Handle passed to native functions for calling back into the VM.
+pub struct VmContext<'a> {
+    vm: &'a mut Vm,
+}
+
+impl<'a> VmContext<'a> {
+    pub fn new(vm: &'a mut Vm) -> VmContext<'a> {
+        VmContext { vm }
+    }
+
+    pub fn call(&mut self, target: &Value, args: &[Value]) -> Result<Value, VmError> {
+        self.vm.call(target, args).map_err(unsuspendable)
+    }
+
+    pub fn get_vm(&mut self) -> &mut Vm {
+        self.vm
+    }
+
+    pub fn get_host_data<T: Any>(&self) -> Option<&T> {
+        self.vm.get_host_data()
+    }
+
+    pub fn get_host_data_mut<T: Any>(&mut self) -> Option<&mut T> {
+        self.vm.get_host_data_mut()
+    }
+
+    pub fn new_list(&mut self, items: Vec<Value>) -> Value {
+        self.vm.alloc(Value::List(List::from_vec(items)))
+    }
+
+    pub fn new_string(&mut self, string: &str) -> Value {
+        self.vm.alloc(Value::StringValue(StringValue::from_string(string.to_string())))
+    }
+
+    pub fn new_string_buffer(&mut self) -> Value {
+        self.vm.alloc(Value::StringBuffer(StringBuffer::from_string(String::new())))
+    }
+
+    pub fn new_bytes_buffer(&mut self, bytes: Vec<u8>) -> Value {
+        self.vm.alloc(Value::BytesBuffer(BytesBuffer::from_vec(bytes)))
+    }
+}
+
+/// A pending native cannot be suspended once host frames sit between it and
+/// the `CallStack` that would be saved.
+fn unsuspendable(e: VmError) -> VmError {
+    match e {
+        VmError::Pending(_) => VmError::Suspend,
+        e => e,
+    }
+}
 
 pub struct CallStack {
     frames: Vec<CallFrame>,
+    /// Whether `YIELD` is allowed, and where the yielded value waits.
+    coroutine: bool,
+    yielded: Option<Value>,
+}
+
+impl CallStack {
+    pub fn new(function: Rc<Function>, args: Vec<Value>) -> CallStack {
+        CallStack {
+            frames: vec![CallFrame::new(function, args)],
+            coroutine: false,
+            yielded: None,
+        }
+    }
+
+    pub(crate) fn new_coroutine(function: Rc<Function>, args: Vec<Value>) -> CallStack {
+        CallStack { coroutine: true, ..CallStack::new(function, args) }
+    }
+
+    pub(crate) fn from_parts(frames: Vec<CallFrame>, coroutine: bool, yielded: Option<Value>) -> CallStack {
+        CallStack { frames, coroutine, yielded }
+    }
+
+    pub(crate) fn is_coroutine(&self) -> bool {
+        self.coroutine
+    }
+
+    pub(crate) fn get_yielded(&self) -> Option<&Value> {
+        self.yielded.as_ref()
+    }
+
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Frames from the outermost call to the innermost one.
+    pub fn get_frames(&self) -> &[CallFrame] {
+        &self.frames
+    }
+
+    pub fn get_frame(&self, depth: usize) -> Option<&CallFrame> {
+        self.frames.get(depth)
+    }
+
+    /// Executes a single instruction of the innermost frame, returning the
+    /// result once the outermost frame has returned.
+    pub fn step(&mut self, vm: &mut Vm) -> Result<Option<Value>, VmError> {
+        #[cfg(feature = "trace")]
+        {
+            if vm.tracer.is_some() {
+                return self.step_traced(vm);
+            }
+        }
+        self.execute(vm)
+    }
+
+    /// Runs instructions up to the next call, return, yield or error, one
+    /// at a time if the function is not verified or the VM has a tracer or
+    /// profiler.
+    fn advance(&mut self, vm: &mut Vm) -> Result<Option<Value>, VmError> {
+        #[cfg(feature = "trace")]
+        {
+            if vm.tracer.is_some() {
+                return self.step_traced(vm);
+            }
+        }
+        if vm.profiler.is_some() {
+            return self.execute(vm);
+        }
+        let frame = self.frames.last_mut().ok_or(VmError::StackEmpty)?;
+        let action = if frame.get_function().is_verified() {
+            run_many(frame)?
+        } else {
+            parse_and_run(frame)?
+        };
+        self.perform(vm, action)
+    }
+
+    #[cfg(feature = "trace")]
+    fn step_traced(&mut self, vm: &mut Vm) -> Result<Option<Value>, VmError> {
+        let depth = self.frames.len();
+        let frame = self.frames.last().ok_or(VmError::StackEmpty)?;
+        let cursor = frame.get_cursor();
+        let (operation, _) = decode(frame.get_bytecode(), cursor)
+            .ok_or(VmError::BytecodeRead(cursor))?;
+        let before = frame.get_stack().to_vec();
+        let base = vm.trace_depth;
+        vm.trace_depth = base + depth;
+        let out = self.execute(vm);
+        vm.trace_depth = base;
+        if let Some(tracer) = &mut vm.tracer {
+            let after = self.frames.get(depth - 1).map_or(&[][..], |f| f.get_stack());
+            let depth = base + depth;
+            tracer.trace(&TraceEvent { depth, cursor, operation: &operation, before: &before, after });
+        }
+        out
+    }
+
+    /// Executes a single instruction, counting it if a profiler is running.
+    fn execute(&mut self, vm: &mut Vm) -> Result<Option<Value>, VmError> {
+        let depth = self.frames.len();
+        let op_code = match &mut vm.profiler {
+            Some(profiler) => profiler.before(self)?,
+            None => None,
+        };
+        let frame = self.frames.last_mut().ok_or(VmError::StackEmpty)?;
+        let action = parse_and_run(frame)?;
+        let out = self.perform(vm, action)?;
+        if let Some(profiler) = &mut vm.profiler {
+            profiler.after(self, depth, op_code);
+        }
+        Ok(out)
+    }
+
+    /// Carries out what an instruction asked of the call stack.
+    fn perform(&mut self, vm: &mut Vm, action: VmAction) -> Result<Option<Value>, VmError> {
+        let frame = self.frames.last_mut().ok_or(VmError::StackEmpty)?;
+        match action {
+            VmAction::None => (),
+            VmAction::Jump(offset) => frame.jump(offset)?,
+            VmAction::Call(f, args) => {
+                self.frames.push(CallFrame::new(f, args));
+            },
+            VmAction::CallNative(f, args) => {
+                let out = f(args)?;
+                frame.push(out);
+            },
+            VmAction::CallNativeFunction(f, args) => {
+                let out = vm.call(&Value::NativeFunction(f), &args)?;
+                self.frames.last_mut().unwrap().push(out);
+            },
+            VmAction::CallMethod(object, name, args) => {
+                let out = object.call_method(&mut VmContext::new(vm), name.as_str(), &args)?;
+                self.frames.last_mut().unwrap().push(out);
+            },
+            VmAction::Return(out) => return Ok(self.finish_frame(out)),
+            VmAction::TailCall(target, args) => match target {
+                Value::Function(f) => *frame = CallFrame::new(f, args),
+                _ => match vm.call(&target, &args) {
+                    Ok(out) => {
+                        self.frames.pop();
+                        return Ok(self.deliver(out));
+                    },
+                    Err(e @ VmError::Pending(_)) => {
+                        // a pending native resumes into the caller
+                        self.frames.pop();
+                        return Err(e);
+                    },
+                    // the frame stays, as after a failed CALL
+                    Err(e) => return Err(e),
+                },
+            },
+            VmAction::Yield(out) => {
+                if !self.coroutine {
+                    return Err(VmError::Yield);
+                }
+                self.yielded = Some(out);
+            },
+            VmAction::Resume(co, input) => {
+                let (out, done) = match vm.resume(&co, input)? {
+                    Resumed::Yield(out) => (out, false),
+                    Resumed::Return(out) => (out, true),
+                };
+                let frame = self.frames.last_mut().unwrap();
+                frame.push(out);
+                frame.push(Value::Bool(done));
+            },
+            VmAction::ResumeNext(co, offset) => {
+                let out = vm.resume(&co, Value::None)?;
+                let frame = self.frames.last_mut().unwrap();
+                match out {
+                    Resumed::Yield(out) => {
+                        frame.push(Value::Coroutine(co));
+                        frame.push(out);
+                    },
+                    Resumed::Return(_) => frame.jump(offset)?,
+                }
+            },
+        }
+        Ok(None)
+    }
+
+    /// Pops the innermost frame, handing `out` to its caller, or returns it
+    /// if there is none.
+    fn finish_frame(&mut self, out: Value) -> Option<Value> {
+        self.frames.pop();
+        self.deliver(out)
+    }
+
+    /// Pushes the result of a call onto the innermost frame, or returns it
+    /// if there is none.
+    pub(crate) fn deliver(&mut self, out: Value) -> Option<Value> {
+        match self.frames.last_mut() {
+            Some(caller) => {
+                caller.push(out);
+                None
+            },
+            None => Some(out),
+        }
+    }
+
+    /// Runs until the outermost frame returns or the coroutine yields.
+    fn run_until_yield(&mut self, vm: &mut Vm) -> Result<Resumed, VmError> {
+        loop {
+            if let Some(out) = self.advance(vm)? {
+                return Ok(Resumed::Return(out));
+            }
+            if let Some(out) = self.yielded.take() {
+                return Ok(Resumed::Yield(out));
+            }
+        }
+    }
+
+    pub fn run(&mut self, vm: &mut Vm) -> Result<Value, VmError> {
+        loop {
+            if let Some(out) = self.advance(vm)? {
+                return Ok(out);
+            }
+        }
+    }
+
+    /// Like `run`, but a native called directly from guest code can return
+    /// `VmError::Pending` to suspend the whole stack until the host has its
+    /// result.
+    pub fn run_async(mut self, vm: &mut Vm) -> Result<Poll, VmError> {
+        match self.run(vm) {
+            Ok(out) => Ok(Poll::Ready(out)),
+            Err(VmError::Pending(waiting)) => Ok(Poll::Pending(waiting, Suspended::new(self))),
+            Err(e) => Err(e),
+        }
+    }
 }
 
 pub struct CallFrame {
     stack: Vec<Value>,
     local: Vec<Value>,
     cursor: usize,
-    bytecode: Bytes,
+    function: Rc<Function>,
 }
 
 impl CallFrame {
-    pub fn new(f: &Function) -> CallFrame {
-        let local = vec![Value::List(f.module.clone())];
+    /// Local 0 holds the function's module, followed by the arguments.
+    pub fn new(function: Rc<Function>, args: Vec<Value>) -> CallFrame {
+        let mut local = Vec::with_capacity(args.len() + 1);
+        local.push(Value::List(function.module.clone()));
+        local.extend(args);
         CallFrame {
             stack: vec![],
             local,
             cursor: 0,
-            bytecode: f.bytecode.clone(),
+            function,
         }
     }
 
+    pub(crate) fn from_parts(function: Rc<Function>, stack: Vec<Value>, local: Vec<Value>, cursor: usize) -> CallFrame {
+        CallFrame { stack, local, cursor, function }
+    }
+
     pub fn get_cursor(&self) -> usize {
         self.cursor
     }
 
+    fn jump(&mut self, offset: i32) -> Result<(), VmError> {
+        let cursor = self.cursor as i64 + offset as i64;
+        self.cursor = usize::try_from(cursor).map_err(|_| VmError::BytecodeRead(self.cursor))?;
+        Ok(())
+    }
+
     pub fn set_cursor(&mut self, cursor: usize) {
         self.cursor = cursor
     }
 
+    pub(crate) fn take_stack(&mut self) -> Vec<Value> {
+        mem::take(&mut self.stack)
+    }
+
+    pub(crate) fn put_stack(&mut self, stack: Vec<Value>) {
+        self.stack = stack;
+    }
+
+    pub fn get_function(&self) -> &Rc<Function> {
+        &self.function
+    }
+
     pub fn get_bytecode(&self) -> &[u8] {
-        &self.bytecode.0
+        &self.function.bytecode.0
+    }
+
+    /// Operand stack, bottom first.
+    pub fn get_stack(&self) -> &[Value] {
+        &self.stack
+    }
+
+    pub fn get_locals(&self) -> &[Value] {
+        &self.local
     }
 
     pub fn load(&self, index: u8) -> Result<&Value, VmError> {
-        self.local.get(index as usize).ok_or_else(|| VmError::FrameRead(index))
+        self.local.get(index as usize).ok_or(VmError::FrameRead(index))
     }
 
     fn get_mut_or_resize(&mut self, index: u8) -> &mut Value {
@@ -67,3 +528,268 @@ impl CallFrame {
         self.stack.pop().ok_or(VmError::StackEmpty)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::{CallStack, Vm};
+    use crate::datamodel::{Bytes, Function, List, NativeFunction, Value, ValueType};
+    use crate::operation::{assemble, Operation};
+    use crate::test_util::{function, string};
+    use crate::VmError;
+
+    fn add(args: Vec<Value>) -> Result<Value, VmError> {
+        match (&args[0], &args[1]) {
+            (Value::Integer(a), Value::Integer(b)) => Ok(Value::Integer(a + b)),
+            _ => Err(VmError::Type(args[0].get_type(), 0)),
+        }
+    }
+
+    #[test]
+    fn run_loop_with_jumps() {
+        let f = function(List::from_vec(vec![]), &[
+            Operation::LiteralInteger(0),
+            Operation::FrameLocalStore(2),
+            // loop
+            Operation::FrameLocalLoad(1),
+            Operation::LiteralInteger(1),
+            Operation::Sub,
+            Operation::JumpNeg(15),
+            Operation::FrameLocalLoad(2),
+            Operation::FrameLocalLoad(1),
+            Operation::Add,
+            Operation::FrameLocalStore(2),
+            Operation::FrameLocalLoad(1),
+            Operation::LiteralInteger(1),
+            Operation::Sub,
+            Operation::FrameLocalStore(1),
+            Operation::Jump(2),
+            // done
+            Operation::FrameLocalLoad(2),
+            Operation::Return,
+        ]);
+        let mut stack = CallStack::new(f, vec![Value::Integer(10)]);
+        assert!(matches!(stack.run(&mut Vm::new()).unwrap(), Value::Integer(55)));
+        assert_eq!(stack.depth(), 0);
+    }
+
+    #[test]
+    fn call_from_host() {
+        let mut vm = Vm::new();
+        let sub = function(List::from_vec(vec![]), &[
+            Operation::FrameLocalLoad(1),
+            Operation::FrameLocalLoad(2),
+            Operation::Sub,
+            Operation::Return,
+        ]);
+        let args = [Value::Integer(50), Value::Integer(8)];
+        assert!(matches!(vm.call(&Value::Function(sub), &args), Ok(Value::Integer(42))));
+        assert!(matches!(vm.call(&Value::NativeFn(add), &args), Ok(Value::Integer(58))));
+        assert!(matches!(vm.call(&Value::Integer(1), &args), Err(VmError::Type(_, 0))));
+
+        // a closure captures its environment in its module
+        let closure = function(List::from_vec(vec![Value::Integer(100)]), &[
+            Operation::FrameLocalLoad(0),
+            Operation::LiteralInteger(0),
+            Operation::SeqGet,
+            Operation::FrameLocalLoad(1),
+            Operation::Add,
+            Operation::Return,
+        ]);
+        let out = vm.call(&Value::Function(closure), &[Value::Integer(1)]);
+        assert!(matches!(out, Ok(Value::Integer(101))));
+    }
+
+    #[test]
+    fn native_calls_back_into_guest() {
+        let twice = NativeFunction::new(|ctx, args| {
+            let once = ctx.call(&args[0], &args[1..])?;
+            ctx.call(&args[0], &[once])
+        });
+        let module = List::from_vec(vec![Value::NativeFunction(twice)]);
+        let double = function(module.clone(), &[
+            Operation::FrameLocalLoad(1),
+            Operation::LiteralInteger(2),
+            Operation::Mul,
+            Operation::Return,
+        ]);
+        module.push(Value::Function(double));
+        let main = function(module, &[
+            Operation::FrameLocalLoad(0),
+            Operation::LiteralInteger(1),
+            Operation::SeqGet,
+            Operation::LiteralInteger(5),
+            Operation::FrameLocalLoad(0),
+            Operation::LiteralInteger(0),
+            Operation::SeqGet,
+            Operation::Call(2),
+            Operation::Return,
+        ]);
+        let out = Vm::new().call(&Value::Function(main), &[]);
+        assert!(matches!(out, Ok(Value::Integer(20))));
+    }
+
+    #[test]
+    fn runaway_reentrancy() {
+        let recurse = NativeFunction::new(|ctx, args| ctx.call(&args[0], args));
+        let target = Value::NativeFunction(recurse);
+        let out = Vm::new().call(&target, std::slice::from_ref(&target));
+        assert!(matches!(out, Err(VmError::StackOverflow)));
+    }
+
+    #[test]
+    fn stateful_native() {
+        use std::cell::Cell;
+
+        let count = Rc::new(Cell::new(0));
+        let captured = count.clone();
+        let counter = Value::NativeFunction(NativeFunction::new(move |_, _| {
+            captured.set(captured.get() + 1);
+            Ok(Value::Integer(captured.get()))
+        }));
+        let mut vm = Vm::new();
+        vm.call(&counter, &[]).unwrap();
+        assert!(matches!(vm.call(&counter, &[]), Ok(Value::Integer(2))));
+        assert_eq!(count.get(), 2);
+    }
+
+    #[test]
+    fn native_host_data() {
+        struct Database(Vec<(&'static str, i64)>);
+
+        let lookup = NativeFunction::new(|ctx, args| {
+            let key = match &args[0] {
+                Value::StringValue(s) => s.as_str().to_string(),
+                e => return Err(VmError::Type(e.get_type(), 0)),
+            };
+            let db: &Database = ctx.get_host_data().unwrap();
+            let hits: Vec<_> = db.0.iter()
+                .filter(|(k, _)| *k == key)
+                .map(|&(_, v)| Value::Integer(v))
+                .collect();
+            Ok(ctx.new_list(hits))
+        });
+        let mut vm = Vm::new();
+        vm.set_host_data(Database(vec![("a", 1), ("b", 2), ("a", 3)]));
+        let key = string("a");
+        let out = match vm.call(&Value::NativeFunction(lookup), &[key]).unwrap() {
+            Value::List(l) => l,
+            _ => panic!(),
+        };
+        assert_eq!(out.len(), 2);
+        assert!(matches!(out.get(1), Some(Value::Integer(3))));
+        assert_eq!(vm.get_allocations(), 1);
+        vm.get_host_data_mut::<Database>().unwrap().0.clear();
+        assert!(vm.get_host_data::<Database>().unwrap().0.is_empty());
+    }
+
+    #[test]
+    fn failed_native_tail_call_keeps_frame() {
+        // the frame is left as the same failing CALL would leave it
+        let outcome = |op| {
+            let f = function(List::from_vec(vec![Value::NativeFn(add)]), &[
+                Operation::FrameLocalLoad(1),
+                Operation::LiteralInteger(1),
+                Operation::FrameLocalLoad(0),
+                Operation::LiteralInteger(0),
+                Operation::SeqGet,
+                op,
+                Operation::Return,
+            ]);
+            let mut stack = CallStack::new(f, vec![Value::Bool(true)]);
+            let err = stack.run(&mut Vm::new()).unwrap_err();
+            assert!(matches!(err, VmError::Type(ValueType::Bool, 0)));
+            assert_eq!(stack.depth(), 1);
+            let frame = stack.get_frame(0).unwrap();
+            (frame.get_cursor(), frame.get_stack().len())
+        };
+        assert_eq!(outcome(Operation::TailCall(2)), outcome(Operation::Call(2)));
+    }
+
+    /// Runs `bytecode` to completion and one step at a time, describing the
+    /// result of each, or the error and the frame it left behind.
+    fn run_both_ways(bytecode: Vec<u8>, args: Vec<Value>) -> [String; 2] {
+        let f = Rc::new(Function::new(List::from_vec(vec![]), Bytes::from_vec(bytecode)));
+        let describe = |out: Result<Value, VmError>, stack: &CallStack| match out {
+            Ok(out) => out.to_string(),
+            Err(e) => {
+                let frame = stack.get_frame(0).unwrap();
+                let items: Vec<_> = frame.get_stack().iter().map(|t| t.to_string()).collect();
+                format!("{:?} at {} with {:?}", e, frame.get_cursor(), items)
+            },
+        };
+        let mut vm = Vm::new();
+        let mut stack = CallStack::new(f.clone(), args.clone());
+        let fast = describe(stack.run(&mut vm), &stack);
+        let mut stack = CallStack::new(f, args);
+        let out = loop {
+            match stack.step(&mut vm) {
+                Ok(None) => (),
+                Ok(Some(out)) => break Ok(out),
+                Err(e) => break Err(e),
+            }
+        };
+        [fast, describe(out, &stack)]
+    }
+
+    #[test]
+    fn run_matches_single_steps() {
+        // builds [0, ..., n - 1] and sums it, mixing inlined instructions
+        // with ones that go through parse_and_run
+        let mut ops = vec![
+            Operation::ListCreate,
+            Operation::FrameLocalStore(2),
+            Operation::LiteralInteger(0),
+            Operation::FrameLocalStore(3),
+            // loop: 4
+            Operation::FrameLocalLoad(3),
+            Operation::FrameLocalLoad(1),
+            Operation::CmpJumpLt(8),
+            Operation::Jump(14),
+            // push: 8
+            Operation::FrameLocalLoad(2),
+            Operation::FrameLocalLoad(3),
+            Operation::ListPush,
+            Operation::IncLocal(3, 1),
+            Operation::LiteralFalse,
+            Operation::JumpZero(4),
+            // sum: 14
+            Operation::LiteralInteger(0),
+            Operation::FrameLocalLoad(2),
+            Operation::IterNew,
+            // 17
+            Operation::ForIter(24),
+            Operation::FrameLocalStore(4),
+            Operation::FrameLocalStore(5),
+            Operation::FrameLocalLoad(4),
+            Operation::Add,
+            Operation::FrameLocalLoad(5),
+            Operation::Jump(17),
+            // 24
+            Operation::Return,
+        ];
+        let [fast, steps] = run_both_ways(assemble(&ops).unwrap(), vec![Value::Integer(5)]);
+        assert_eq!(fast, "10");
+        assert_eq!(fast, steps);
+        // nothing left to pop once the loop ends
+        ops[24] = Operation::Mul;
+        let [fast, steps] = run_both_ways(assemble(&ops).unwrap(), vec![Value::Integer(5)]);
+        assert!(fast.starts_with("StackEmpty at 73 "), "{}", fast);
+        assert_eq!(fast, steps);
+        // local 0 is the module list
+        ops[20] = Operation::AddLocals(0, 0);
+        let [fast, steps] = run_both_ways(assemble(&ops).unwrap(), vec![Value::Integer(5)]);
+        assert!(fast.starts_with("Type(List, 1) at 63 "), "{}", fast);
+        assert_eq!(fast, steps);
+
+        // ends mid-instruction, so it runs one step at a time
+        ops[20] = Operation::FrameLocalLoad(4);
+        ops[24] = Operation::Return;
+        let mut bytecode = assemble(&ops).unwrap();
+        bytecode.push(crate::operation::LIT_INT);
+        let f = Function::new(List::from_vec(vec![]), Bytes::from_vec(bytecode.clone()));
+        assert!(!f.is_verified());
+        assert_eq!(run_both_ways(bytecode, vec![Value::Integer(3)]), ["3", "3"]);
+    }
+}
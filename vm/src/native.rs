@@ -0,0 +1,227 @@
+use crate::convert::{ConvertError, FromValue, IntoValue};
+use crate::datamodel::{List, NativeFunction, Value, ValueType};
+use crate::VmError;
+
+/// An argument type a wrapped native can take, possibly borrowing from the
+/// argument `Value`.
+pub trait NativeArg<'a>: Sized {
+    fn from_arg(value: &'a Value) -> Result<Self, ConvertError>;
+}
+
+impl<'a, T: FromValue> NativeArg<'a> for T {
+    fn from_arg(value: &'a Value) -> Result<T, ConvertError> {
+        T::from_value(value)
+    }
+}
+
+/// Only `StringValue` can be borrowed; buffers must be taken as `String`.
+impl<'a> NativeArg<'a> for &'a str {
+    fn from_arg(value: &'a Value) -> Result<&'a str, ConvertError> {
+        match value {
+            Value::StringValue(s) => Ok(s.as_str()),
+            _ => Err(ConvertError::Type {
+                expected: ValueType::StringValue,
+                found: value.get_type(),
+            }),
+        }
+    }
+}
+
+/// Only `Bytes` can be borrowed; buffers must be taken as `Bytes`.
+impl<'a> NativeArg<'a> for &'a [u8] {
+    fn from_arg(value: &'a Value) -> Result<&'a [u8], ConvertError> {
+        match value {
+            Value::Bytes(b) => Ok(&b.0),
+            _ => Err(ConvertError::Type {
+                expected: ValueType::Bytes,
+                found: value.get_type(),
+            }),
+        }
+    }
+}
+
+/// Maps a parameter type to the same type borrowing for any lifetime, so
+/// functions like `fn(&str)` can be wrapped for every call.
+pub trait ArgFamily<'a> {
+    type Arg: NativeArg<'a>;
+}
+
+impl<'a, T: FromValue> ArgFamily<'a> for T {
+    type Arg = T;
+}
+
+impl<'a> ArgFamily<'a> for &str {
+    type Arg = &'a str;
+}
+
+impl<'a> ArgFamily<'a> for &[u8] {
+    type Arg = &'a [u8];
+}
+
+pub trait NativeReturn {
+    fn into_result(self) -> Result<Value, VmError>;
+}
+
+impl<T: IntoValue> NativeReturn for T {
+    fn into_result(self) -> Result<Value, VmError> {
+        Ok(self.into_value())
+    }
+}
+
+impl<T: IntoValue, E: Into<VmError>> NativeReturn for Result<T, E> {
+    fn into_result(self) -> Result<Value, VmError> {
+        self.map(IntoValue::into_value).map_err(Into::into)
+    }
+}
+
+/// Rust functions that can be wrapped into a `NativeFunction`, checking
+/// arity and converting arguments and return values. `Args` only serves to
+/// tell the implementations for different parameter lists apart.
+pub trait IntoNative<Args> {
+    fn into_native(self) -> NativeFunction;
+}
+
+macro_rules! into_native {
+    ($n:expr; $($t:ident $i:tt),*) => {
+        impl<F, R, $($t),*> IntoNative<($($t,)*)> for F
+        where
+            F: Fn($($t),*) -> R + 'static,
+            F: for<'a> Fn($(<$t as ArgFamily<'a>>::Arg),*) -> R,
+            R: NativeReturn,
+            $($t: for<'a> ArgFamily<'a>,)*
+        {
+            #[allow(unused_variables)]
+            fn into_native(self) -> NativeFunction {
+                NativeFunction::new(move |_, args| {
+                    if args.len() != $n {
+                        return Err(VmError::Arity($n, args.len()));
+                    }
+                    self($(<$t as ArgFamily>::Arg::from_arg(&args[$i])
+                        .map_err(|e| e.at($i))?),*).into_result()
+                })
+            }
+        }
+    };
+}
+
+into_native!(0;);
+into_native!(1; A 0);
+into_native!(2; A 0, B 1);
+into_native!(3; A 0, B 1, C 2);
+into_native!(4; A 0, B 1, C 2, D 3);
+into_native!(5; A 0, B 1, C 2, D 3, E 4);
+into_native!(6; A 0, B 1, C 2, D 3, E 4, G 5);
+
+/// A module `List` whose slots are also known by name, so a compiler can
+/// resolve guest references to natives into `SEQ_GET` indexes.
+pub struct NativeModule {
+    list: List,
+    names: Vec<String>,
+}
+
+impl Default for NativeModule {
+    fn default() -> NativeModule {
+        NativeModule::new()
+    }
+}
+
+impl NativeModule {
+    pub fn new() -> NativeModule {
+        NativeModule::from_list(List::from_vec(vec![]))
+    }
+
+    /// Names are appended after any slots already in `list`.
+    pub fn from_list(list: List) -> NativeModule {
+        let names = vec![String::new(); list.len()];
+        NativeModule { list, names }
+    }
+
+    pub fn get_list(&self) -> &List {
+        &self.list
+    }
+
+    pub fn index_of(&self, name: &str) -> Option<usize> {
+        self.names.iter().position(|n| n == name)
+    }
+
+    /// Installs `value` under `name`, replacing any previous value with that
+    /// name, and returns its index in the module list.
+    pub fn insert(&mut self, name: &str, value: Value) -> usize {
+        match self.index_of(name) {
+            Some(i) => {
+                self.list.set(i, value);
+                i
+            },
+            None => {
+                self.list.push(value);
+                self.names.push(name.to_string());
+                self.names.len() - 1
+            },
+        }
+    }
+
+    pub fn register<Args, F: IntoNative<Args>>(&mut self, name: &str, f: F) -> usize {
+        self.insert(name, Value::NativeFunction(f.into_native()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IntoNative, NativeModule};
+    use crate::convert::ConvertError;
+    use crate::datamodel::{List, Value};
+    use crate::machine::Vm;
+    use crate::operation::Operation;
+    use crate::test_util::{function, string};
+    use crate::VmError;
+
+    fn repeat(n: i64, s: &str) -> Result<String, VmError> {
+        if n < 0 {
+            return Err(VmError::Native("negative count".to_string()));
+        }
+        Ok(s.repeat(n as usize))
+    }
+
+    #[test]
+    fn marshals_arguments() {
+        let mut vm = Vm::new();
+        let f = Value::NativeFunction(repeat.into_native());
+        match vm.call(&f, &[Value::Integer(3), string("ab")]) {
+            Ok(Value::StringValue(s)) => assert_eq!(s.as_str(), "ababab"),
+            _ => panic!(),
+        }
+        assert!(matches!(vm.call(&f, &[Value::Integer(1)]), Err(VmError::Arity(2, 1))));
+        assert!(matches!(
+            vm.call(&f, &[string("3"), string("ab")]),
+            Err(VmError::Convert(ConvertError::Type { .. }, 0))));
+        assert!(matches!(vm.call(&f, &[Value::Integer(-1), string("ab")]), Err(VmError::Native(_))));
+
+        let sum = Value::NativeFunction((|v: Vec<i64>| v.iter().sum::<i64>()).into_native());
+        let list = Value::List(List::from_vec(vec![Value::Integer(2), Value::Integer(3)]));
+        assert!(matches!(vm.call(&sum, &[list]), Ok(Value::Integer(5))));
+    }
+
+    #[test]
+    fn guest_finds_natives_by_name() {
+        let mut module = NativeModule::new();
+        module.register("neg", |x: i64| -x);
+        let i = module.register("repeat", repeat);
+        assert_eq!(module.index_of("repeat"), Some(i));
+        assert_eq!(module.register("neg", |x: i64| x - 1), 0);
+
+        let main = function(module.get_list().clone(), &[
+            Operation::LiteralInteger(2),
+            Operation::FrameLocalLoad(1),
+            Operation::FrameLocalLoad(0),
+            Operation::LiteralInteger(i as i64),
+            Operation::SeqGet,
+            Operation::Call(2),
+            Operation::Return,
+        ]);
+        let out = Vm::new().call(&Value::Function(main), &[string("xy")]);
+        match out {
+            Ok(Value::StringValue(s)) => assert_eq!(s.as_str(), "xyxy"),
+            _ => panic!(),
+        }
+    }
+}
@@ -1,10 +1,18 @@
-use std::convert::TryInto;
+use std::convert::{TryFrom, TryInto};
 use std::cmp::Ordering;
+use std::mem;
+use std::rc::Rc;
 
 use crate::{
     VmAction, VmError,
-    datamodel::{BytesBuffer, List, StringBuffer, Value},
+    convert::ConvertError,
+    coroutine::Coroutine,
+    datamodel::{Bytes, BytesBuffer, Iter, List, Range, StringBuffer, StringValue, Value},
+    display,
+    encoding::Encoding,
     machine::{CallFrame},
+    pack::Packing,
+    unicode,
 };
 
 macro_rules! type_err {
@@ -74,13 +82,20 @@ macro_rules! int_op {
     };
 }
 
+// forced inline, or single steps slow down now that run_many calls it too
+#[inline(always)]
 pub fn parse_and_run(frame: &mut CallFrame) -> Result<VmAction, VmError> {
     let mut cursor = frame.get_cursor();
-    let op_code = *frame.get_bytecode().get(cursor).ok_or_else(|| VmError::BytecodeRead(cursor))?;
+    let op_code = *frame.get_bytecode().get(cursor).ok_or(VmError::BytecodeRead(cursor))?;
     cursor += 1;
     let result = match op_code {
         NONE => Ok(VmAction::None),
-        ADD => math_op!(frame, |lhs, rhs| lhs + rhs),
+        ADD => {
+            let rhs = frame.pop()?;
+            let lhs = frame.pop()?;
+            frame.push(add(&lhs, &rhs)?);
+            Ok(VmAction::None)
+        },
         SUB => math_op!(frame, |lhs, rhs| lhs - rhs),
         MUL => math_op!(frame, |lhs, rhs| lhs * rhs),
         DIV => {
@@ -89,7 +104,7 @@ pub fn parse_and_run(frame: &mut CallFrame) -> Result<VmAction, VmError> {
             let out = match lhs {
                 Value::Integer(lhs) => match rhs {
                     Value::Integer(rhs) => Value::Integer(
-                        lhs.checked_div(rhs).ok_or_else(|| VmError::DivByZero)?),
+                        lhs.checked_div(rhs).ok_or(VmError::DivByZero)?),
                     _ => type_err!(rhs, 0),
                 },
                 Value::Real(lhs) => match rhs {
@@ -107,7 +122,7 @@ pub fn parse_and_run(frame: &mut CallFrame) -> Result<VmAction, VmError> {
             let out = match lhs {
                 Value::Integer(lhs) => match rhs {
                     Value::Integer(rhs) => Value::Integer(
-                        lhs.checked_rem(rhs).ok_or_else(|| VmError::DivByZero)?),
+                        lhs.checked_rem(rhs).ok_or(VmError::DivByZero)?),
                     _ => type_err!(rhs, 0),
                 },
                 Value::Real(lhs) => match rhs {
@@ -163,18 +178,28 @@ pub fn parse_and_run(frame: &mut CallFrame) -> Result<VmAction, VmError> {
             frame.push(out);
             Ok(VmAction::None)
         },
+        CHAR_TO_INT => {
+            let t = match frame.pop()? {
+                Value::Char(c) => Value::Integer(c as i64),
+                e => type_err!(e, 0),
+            };
+            frame.push(t);
+            Ok(VmAction::None)
+        },
+        INT_TO_CHAR => {
+            let t = match frame.pop()? {
+                Value::Integer(i) => u32::try_from(i).ok()
+                    .and_then(char::from_u32)
+                    .ok_or(VmError::CodePoint(i))?,
+                e => type_err!(e, 0),
+            };
+            frame.push(Value::Char(t));
+            Ok(VmAction::None)
+        },
         CMP => {
             let rhs = frame.pop()?;
             let lhs = frame.pop()?;
-            let result = match lhs.cmp(&rhs) {
-                Some(order) => Value::Integer(match order {
-                    Ordering::Less => -1,
-                    Ordering::Equal => 0,
-                    Ordering::Greater => 1,
-                }),
-                None => Value::None,
-            };
-            frame.push(result);
+            frame.push(compare(&lhs, &rhs));
             Ok(VmAction::None)
         },
         CALL => {
@@ -184,13 +209,47 @@ pub fn parse_and_run(frame: &mut CallFrame) -> Result<VmAction, VmError> {
             for _ in 0..num_args {
                 args.push(frame.pop()?);
             }
+            args.reverse();
             match fn_target {
                 Value::Function(f) => Ok(VmAction::Call(f, args)),
                 Value::NativeFn(f) => Ok(VmAction::CallNative(f, args)),
+                Value::NativeFunction(f) => Ok(VmAction::CallNativeFunction(f, args)),
                 _ => type_err!(fn_target, 0),
             }
         },
+        CALL_METHOD => {
+            let num_args = *bytecode_take!(frame, cursor) as usize;
+            let object = match frame.pop()? {
+                Value::Unknown(t) => t,
+                e => type_err!(e, 0),
+            };
+            let name = match frame.pop()? {
+                Value::StringValue(s) => s,
+                e => type_err!(e, 1),
+            };
+            let mut args = Vec::new();
+            for _ in 0..num_args {
+                args.push(frame.pop()?);
+            }
+            args.reverse();
+            Ok(VmAction::CallMethod(object, name, args))
+        },
         RETURN => Ok(VmAction::Return(frame.pop()?)),
+        TAIL_CALL => {
+            let num_args = *bytecode_take!(frame, cursor) as usize;
+            let fn_target = frame.pop()?;
+            let mut args = Vec::new();
+            for _ in 0..num_args {
+                args.push(frame.pop()?);
+            }
+            args.reverse();
+            match fn_target {
+                Value::Function(_) | Value::NativeFn(_) | Value::NativeFunction(_) => {
+                    Ok(VmAction::TailCall(fn_target, args))
+                },
+                _ => type_err!(fn_target, 0),
+            }
+        },
         JUMP => {
             let dst = bytecode_take!(frame, cursor, 4);
             let dst = i32::from_be_bytes(dst.try_into().unwrap());
@@ -199,13 +258,7 @@ pub fn parse_and_run(frame: &mut CallFrame) -> Result<VmAction, VmError> {
         JUMP_ZERO => {
             let dst = bytecode_take!(frame, cursor, 4);
             let dst = i32::from_be_bytes(dst.try_into().unwrap());
-            let check = match frame.pop()? {
-                Value::Bool(t) => !t,
-                Value::Integer(t) => t == 0,
-                Value::Real(t) => t == 0.0,
-                e @ _ => type_err!(e, 0),
-            };
-            if check {
+            if is_zero(frame.pop()?)? {
                 Ok(VmAction::Jump(dst))
             } else {
                 Ok(VmAction::None)
@@ -214,18 +267,41 @@ pub fn parse_and_run(frame: &mut CallFrame) -> Result<VmAction, VmError> {
         JUMP_NEG => {
             let dst = bytecode_take!(frame, cursor, 4);
             let dst = i32::from_be_bytes(dst.try_into().unwrap());
-            let check = match frame.pop()? {
-                Value::None => true,
-                Value::Integer(t) => t < 0,
-                Value::Real(t) => t < 0.0,
-                e @ _ => type_err!(e, 0),
-            };
-            if check {
+            if is_neg(frame.pop()?)? {
                 Ok(VmAction::Jump(dst))
             } else {
                 Ok(VmAction::None)
             }
         },
+        FOR_ITER => {
+            let dst = bytecode_take!(frame, cursor, 4);
+            let dst = i32::from_be_bytes(dst.try_into().unwrap());
+            match frame.pop()? {
+                Value::Iter(iter) => match iter.next()? {
+                    Some(item) => {
+                        frame.push(Value::Iter(iter));
+                        frame.push(item);
+                        Ok(VmAction::None)
+                    },
+                    None => Ok(VmAction::Jump(dst)),
+                },
+                Value::Coroutine(t) => Ok(VmAction::ResumeNext(t, dst)),
+                e => type_err!(e, 0),
+            }
+        },
+        ITER_NEW => {
+            let t = frame.pop()?;
+            let iter = match &t {
+                // coroutines iterate over what they yield
+                Value::Coroutine(_) => t,
+                _ => match Iter::new(t.clone()) {
+                    Some(iter) => Value::Iter(iter),
+                    None => type_err!(t, 0),
+                },
+            };
+            frame.push(iter);
+            Ok(VmAction::None)
+        },
         LIT_NONE => {
             frame.push(Value::None);
             Ok(VmAction::None)
@@ -244,12 +320,39 @@ pub fn parse_and_run(frame: &mut CallFrame) -> Result<VmAction, VmError> {
             frame.push(Value::Integer(i));
             Ok(VmAction::None)
         },
+        LIT_INT_SMALL => {
+            let i = *bytecode_take!(frame, cursor) as i8;
+            frame.push(Value::Integer(i as i64));
+            Ok(VmAction::None)
+        },
         LIT_REAL => {
             let b = bytecode_take!(frame, cursor, 8);
             let r = f64::from_be_bytes(b.try_into().unwrap());
             frame.push(Value::Real(r));
             Ok(VmAction::None)
         },
+        CO_NEW => {
+            let num_args = *bytecode_take!(frame, cursor) as usize;
+            let function = match frame.pop()? {
+                Value::Function(f) => f,
+                e => type_err!(e, 0),
+            };
+            let mut args = Vec::new();
+            for _ in 0..num_args {
+                args.push(frame.pop()?);
+            }
+            args.reverse();
+            frame.push(Value::Coroutine(Coroutine::new(function, args)));
+            Ok(VmAction::None)
+        },
+        YIELD => Ok(VmAction::Yield(frame.pop()?)),
+        RESUME => {
+            let input = frame.pop()?;
+            match frame.pop()? {
+                Value::Coroutine(t) => Ok(VmAction::Resume(t, input)),
+                e => type_err!(e, 1),
+            }
+        },
         FRM_LOAD => {
             let i = *bytecode_take!(frame, cursor);
             frame.push(frame.load(i)?.clone());
@@ -261,6 +364,30 @@ pub fn parse_and_run(frame: &mut CallFrame) -> Result<VmAction, VmError> {
             frame.store(i, t);
             Ok(VmAction::None)
         },
+        ADD_LOCALS => {
+            let b = bytecode_take!(frame, cursor, 2);
+            let (a, b) = (b[0], b[1]);
+            let out = add(frame.load(a)?, frame.load(b)?)?;
+            frame.push(out);
+            Ok(VmAction::None)
+        },
+        INC_LOCAL => {
+            let b = bytecode_take!(frame, cursor, 2);
+            let (i, step) = (b[0], b[1] as i8);
+            let out = add(frame.load(i)?, &Value::Integer(step as i64))?;
+            frame.store(i, out);
+            Ok(VmAction::None)
+        },
+        CMP_JUMP_LT => {
+            let dst = bytecode_take!(frame, cursor, 4);
+            let dst = i32::from_be_bytes(dst.try_into().unwrap());
+            let rhs = frame.pop()?;
+            let lhs = frame.pop()?;
+            match lhs.cmp(&rhs) {
+                Some(Ordering::Less) | None => Ok(VmAction::Jump(dst)),
+                _ => Ok(VmAction::None),
+            }
+        },
         FRM_SWAP => {
             let i = *bytecode_take!(frame, cursor);
             let mut t = frame.pop()?;
@@ -287,7 +414,7 @@ pub fn parse_and_run(frame: &mut CallFrame) -> Result<VmAction, VmError> {
             let ele = frame.pop()?;
             let list = match frame.pop()? {
                 Value::List(t) => t,
-                e @ _ => type_err!(e, 1),
+                e => type_err!(e, 1),
             };
             list.push(ele);
             Ok(VmAction::None)
@@ -295,16 +422,16 @@ pub fn parse_and_run(frame: &mut CallFrame) -> Result<VmAction, VmError> {
         LIST_POP => {
             let list = match frame.pop()? {
                 Value::List(t) => t,
-                e @ _ => type_err!(e, 0),
+                e => type_err!(e, 0),
             };
-            let ele = list.pop().ok_or_else(|| VmError::IndexRead(0))?;
+            let ele = list.pop().ok_or(VmError::IndexRead(0))?;
             frame.push(ele);
             Ok(VmAction::None)
         },
         LIST_DOWNGRADE => {
             let list = match frame.pop()? {
                 Value::List(t) => t,
-                e @ _ => type_err!(e, 0),
+                e => type_err!(e, 0),
             };
             let weak = Value::ListWeak(list.downgrade());
             frame.push(weak);
@@ -313,7 +440,7 @@ pub fn parse_and_run(frame: &mut CallFrame) -> Result<VmAction, VmError> {
         LIST_UPGRADE => {
             let weak = match frame.pop()? {
                 Value::ListWeak(t) => t,
-                e @ _ => type_err!(e, 0),
+                e => type_err!(e, 0),
             };
             let out = match weak.upgrade() {
                 Some(list) => Value::List(list),
@@ -327,6 +454,48 @@ pub fn parse_and_run(frame: &mut CallFrame) -> Result<VmAction, VmError> {
             frame.push(t);
             Ok(VmAction::None)
         },
+        BYTES_DECODE => {
+            let encoding = *bytecode_take!(frame, cursor);
+            let encoding = Encoding::from_u8(encoding).ok_or(VmError::BytecodeRead(cursor - 1))?;
+            let s = match frame.pop()? {
+                Value::Bytes(b) => encoding.decode(&b.0)?,
+                Value::BytesBuffer(b) => encoding.decode(&b.0.borrow())?,
+                e => type_err!(e, 0),
+            };
+            frame.push(Value::StringValue(StringValue::from_string(s)));
+            Ok(VmAction::None)
+        },
+        BYTES_READ => {
+            let packing = *bytecode_take!(frame, cursor);
+            let packing = Packing::from_u8(packing).ok_or(VmError::BytecodeRead(cursor - 1))?;
+            let offset = match frame.pop()? {
+                Value::Integer(i) => i,
+                e => type_err!(e, 0),
+            };
+            let (value, next) = match frame.pop()? {
+                Value::Bytes(b) => packing.read(&b.0, offset)?,
+                Value::BytesBuffer(b) => packing.read(&b.0.borrow(), offset)?,
+                e => type_err!(e, 1),
+            };
+            frame.push(value);
+            frame.push(Value::Integer(next as i64));
+            Ok(VmAction::None)
+        },
+        BYTES_WRITE => {
+            let packing = *bytecode_take!(frame, cursor);
+            let packing = Packing::from_u8(packing).ok_or(VmError::BytecodeRead(cursor - 1))?;
+            let value = frame.pop()?;
+            let offset = match frame.pop()? {
+                Value::Integer(i) => i,
+                e => type_err!(e, 1),
+            };
+            let next = match frame.pop()? {
+                Value::BytesBuffer(b) => packing.write(&mut b.0.borrow_mut(), offset, &value)?,
+                e => type_err!(e, 2),
+            };
+            frame.push(Value::Integer(next as i64));
+            Ok(VmAction::None)
+        },
         STR_CREATE => {
             let t = Value::StringBuffer(StringBuffer::from_string(String::new()));
             frame.push(t);
@@ -334,41 +503,124 @@ pub fn parse_and_run(frame: &mut CallFrame) -> Result<VmAction, VmError> {
         },
         STR_CHAR_AT => {
             let i = match frame.pop()? {
-                Value::Integer(i) => i as usize,
-                e @ _ => type_err!(e, 0),
+                Value::Integer(i) => i,
+                e => type_err!(e, 0),
             };
             let c = match frame.pop()? {
-                Value::StringValue(s) => s.get_char_at(i),
-                Value::StringBuffer(s) => s.get_char_at(i),
-                e @ _ => type_err!(e, 1),
-            };
-            let v = match c {
-                Some(c) => Value::Char(c),
-                None => Value::None,
+                Value::StringValue(s) => s.get_char_at(i)?,
+                Value::StringBuffer(s) => s.get_char_at(i)?,
+                e => type_err!(e, 1),
             };
-            frame.push(v);
+            frame.push(Value::Char(c));
             Ok(VmAction::None)
         },
         STR_CHARS => {
             let chars = match frame.pop()? {
                 Value::StringValue(s) => s.get_chars(),
                 Value::StringBuffer(s) => s.get_chars(),
-                e @ _ => type_err!(e, 0),
+                e => type_err!(e, 0),
             };
             frame.push(Value::List(chars));
             Ok(VmAction::None)
         },
+        TO_STRING => {
+            let t = frame.pop()?;
+            let s = StringValue::from_string(t.to_string());
+            frame.push(Value::StringValue(s));
+            Ok(VmAction::None)
+        },
+        STR_APPEND => {
+            let t = frame.pop()?;
+            match frame.pop()? {
+                Value::StringBuffer(s) => s.append(&t.to_string()),
+                e => type_err!(e, 1),
+            }
+            Ok(VmAction::None)
+        },
+        STR_FREEZE => {
+            let s = match frame.pop()? {
+                Value::StringBuffer(s) => StringValue::from_string(s.0.borrow().clone()),
+                Value::StringValue(s) => s,
+                e => type_err!(e, 0),
+            };
+            frame.push(Value::StringValue(s));
+            Ok(VmAction::None)
+        },
+        STR_CONCAT => {
+            let rhs = frame.pop()?;
+            let lhs = frame.pop()?;
+            let mut out = match lhs {
+                Value::StringValue(s) => s.as_str().to_string(),
+                Value::StringBuffer(s) => s.0.borrow().clone(),
+                e => type_err!(e, 1),
+            };
+            match rhs {
+                Value::StringValue(s) => out.push_str(s.as_str()),
+                Value::StringBuffer(s) => out.push_str(&s.0.borrow()),
+                e => type_err!(e, 0),
+            }
+            frame.push(Value::StringValue(StringValue::from_string(out)));
+            Ok(VmAction::None)
+        },
+        STR_FORMAT => {
+            let num_args = *bytecode_take!(frame, cursor) as usize;
+            let mut args = Vec::new();
+            for _ in 0..num_args {
+                args.push(frame.pop()?);
+            }
+            args.reverse();
+            let template = match frame.pop()? {
+                Value::StringValue(s) => s,
+                e => type_err!(e, num_args as u8),
+            };
+            let out = display::format(template.as_str(), &args)?;
+            frame.push(Value::StringValue(StringValue::from_string(out)));
+            Ok(VmAction::None)
+        },
+        STR_NEXT_CHAR => {
+            let i = match frame.pop()? {
+                Value::Integer(i) => i,
+                e => type_err!(e, 0),
+            };
+            let next = match frame.pop()? {
+                Value::StringValue(s) => unicode::next_char(s.as_str(), i)?,
+                Value::StringBuffer(s) => unicode::next_char(&s.0.borrow(), i)?,
+                e => type_err!(e, 1),
+            };
+            // -1 marks the end so the loop can exit with JUMP_NEG
+            let (c, next) = match next {
+                Some((c, next)) => (Value::Char(c), next as i64),
+                None => (Value::None, -1),
+            };
+            frame.push(c);
+            frame.push(Value::Integer(next));
+            Ok(VmAction::None)
+        },
+        STR_ENCODE => {
+            let encoding = *bytecode_take!(frame, cursor);
+            let encoding = Encoding::from_u8(encoding).ok_or(VmError::BytecodeRead(cursor - 1))?;
+            let b = match frame.pop()? {
+                Value::StringValue(s) => encoding.encode(s.as_str())?,
+                Value::StringBuffer(s) => encoding.encode(&s.0.borrow())?,
+                e => type_err!(e, 0),
+            };
+            frame.push(Value::Bytes(Bytes::from_vec(b)));
+            Ok(VmAction::None)
+        },
         SEQ_GET => {
             let i = match frame.pop()? {
                 Value::Integer(i) => i,
-                e @ _ => type_err!(e, 0),
+                e => type_err!(e, 0),
             };
             let out = match frame.pop()? {
                 Value::List(l) => l.get(i as usize),
                 Value::Bytes(b) => b.get(i as usize),
                 Value::BytesBuffer(b) => b.get(i as usize),
-                e @ _ => type_err!(e, 1),
-            }.ok_or_else(|| VmError::IndexRead(i))?;
+                Value::Range(r) => r.get(i as usize).map(Value::Integer),
+                Value::StringValue(s) => Some(Value::Char(unicode::char_at(s.as_str(), i)?)),
+                Value::StringBuffer(s) => Some(Value::Char(unicode::char_at(&s.0.borrow(), i)?)),
+                e => type_err!(e, 1),
+            }.ok_or(VmError::IndexRead(i))?;
             frame.push(out);
             Ok(VmAction::None)
         },
@@ -376,39 +628,35 @@ pub fn parse_and_run(frame: &mut CallFrame) -> Result<VmAction, VmError> {
             let v = frame.pop()?;
             let i = match frame.pop()? {
                 Value::Integer(i) => i,
-                e @ _ => type_err!(e, 1),
+                e => type_err!(e, 1),
             };
             match frame.pop()? {
                 Value::List(l) => l.set(i as usize, v),
                 Value::BytesBuffer(b) => {
                     let v = match v {
-                        Value::Integer(v) => v as u8,
-                        e @ _ => type_err!(e, 0),
+                        Value::Integer(v) => u8::try_from(v)
+                            .map_err(|_| ConvertError::Range(v).at(0))?,
+                        e => type_err!(e, 0),
                     };
                     b.set(i as usize, v)
                 },
-                e @ _ => type_err!(e, 2),
-            }.ok_or_else(|| VmError::IndexWrite(i))?;
+                e => type_err!(e, 2),
+            }.ok_or(VmError::IndexWrite(i))?;
             Ok(VmAction::None)
         },
         SEQ_GET_SLICE => {
-            let end = match frame.pop()? {
-                Value::Integer(i) => i,
-                e @ _ => type_err!(e, 0),
-            };
-            let start = match frame.pop()? {
-                Value::Integer(i) => i,
-                e @ _ => type_err!(e, 1),
-            };
+            // bounds are either a start and an end or a single range
             let out = match frame.pop()? {
-                Value::List(l) => l.get_slice(start as usize, end as usize).map(
-                    |l| Value::List(l)),
-                Value::Bytes(b) => b.get_slice(start as usize, end as usize).map(
-                    |b| Value::BytesBuffer(b)),
-                Value::BytesBuffer(b) => b.get_slice(start as usize, end as usize).map(
-                    |b| Value::BytesBuffer(b)),
-                e @ _ => type_err!(e, 2),
-            }.ok_or_else(|| VmError::SliceRead(start, end))?;
+                Value::Integer(end) => {
+                    let start = match frame.pop()? {
+                        Value::Integer(i) => i,
+                        e => type_err!(e, 1),
+                    };
+                    get_slice(frame.pop()?, start, end)?
+                },
+                Value::Range(r) => get_range_slice(frame.pop()?, &r)?,
+                e => type_err!(e, 0),
+            };
             frame.push(out);
             Ok(VmAction::None)
         },
@@ -421,11 +669,12 @@ pub fn parse_and_run(frame: &mut CallFrame) -> Result<VmAction, VmError> {
         SEQ_LEN => {
             let len = match frame.pop()? {
                 Value::List(l) => l.len(),
+                Value::Range(r) => r.len(),
                 Value::Bytes(b) => b.len(),
                 Value::BytesBuffer(b) => b.len(),
                 Value::StringValue(s) => s.as_str().len(),
                 Value::StringBuffer(s) => s.len(),
-                e @ _ => type_err!(e, 0),
+                e => type_err!(e, 0),
             };
             frame.push(Value::Integer(len as i64));
             Ok(VmAction::None)
@@ -433,19 +682,290 @@ pub fn parse_and_run(frame: &mut CallFrame) -> Result<VmAction, VmError> {
         SEQ_RESIZE => {
             let len = match frame.pop()? {
                 Value::Integer(i) => i,
-                e @ _ => type_err!(e, 0),
+                e => type_err!(e, 0),
             } as usize;
             match frame.pop()? {
                 Value::List(l) => l.resize(len),
                 Value::BytesBuffer(b) => b.resize(len),
-                e @ _ => type_err!(e, 1),
+                e => type_err!(e, 1),
             }
             Ok(VmAction::None)
         },
+        RANGE_NEW => {
+            let step = match frame.pop()? {
+                Value::Integer(i) => i,
+                e => type_err!(e, 0),
+            };
+            let end = match frame.pop()? {
+                Value::Integer(i) => i,
+                e => type_err!(e, 1),
+            };
+            let start = match frame.pop()? {
+                Value::Integer(i) => i,
+                e => type_err!(e, 2),
+            };
+            let range = Range::new(start, end, step).ok_or(VmError::ZeroStep)?;
+            frame.push(Value::Range(range));
+            Ok(VmAction::None)
+        },
         _ => return Err(VmError::BytecodeRead(cursor))
     };
     frame.set_cursor(cursor);
-    return result;
+    result
+}
+
+fn get_slice(seq: Value, start: i64, end: i64) -> Result<Value, VmError> {
+    let out = match seq {
+        Value::List(l) => l.get_slice(start as usize, end as usize).map(
+            Value::List),
+        Value::Bytes(b) => b.get_slice(start as usize, end as usize).map(
+            Value::BytesBuffer),
+        Value::BytesBuffer(b) => b.get_slice(start as usize, end as usize).map(
+            Value::BytesBuffer),
+        Value::StringValue(s) => {
+            let t = unicode::slice(s.as_str(), start, end)?;
+            Some(Value::StringValue(StringValue::from_string(t.to_string())))
+        },
+        Value::StringBuffer(s) => {
+            let t = unicode::slice(&s.0.borrow(), start, end)?.to_string();
+            Some(Value::StringValue(StringValue::from_string(t)))
+        },
+        e => type_err!(e, 2),
+    };
+    out.ok_or(VmError::SliceRead(start, end))
+}
+
+/// Slices by a range, which may step over or reverse items. Strings only
+/// take ranges with a step of one.
+fn get_range_slice(seq: Value, r: &Range) -> Result<Value, VmError> {
+    let err = VmError::SliceRead(r.start, r.end);
+    let out = match seq {
+        Value::List(l) => {
+            let items = l.0.borrow();
+            let out = r.indices(items.len()).ok_or(err)?.map(|i| items[i].clone());
+            Value::List(List::from_vec(out.collect()))
+        },
+        Value::Bytes(b) => {
+            let out = r.indices(b.len()).ok_or(err)?.map(|i| b.0[i]);
+            Value::BytesBuffer(BytesBuffer::from_vec(out.collect()))
+        },
+        Value::BytesBuffer(b) => {
+            let bytes = b.0.borrow();
+            let out = r.indices(bytes.len()).ok_or(err)?.map(|i| bytes[i]);
+            Value::BytesBuffer(BytesBuffer::from_vec(out.collect()))
+        },
+        Value::Range(t) => Value::Range(t.get_range(r).ok_or(err)?),
+        Value::StringValue(_) | Value::StringBuffer(_) if r.step != 1 => return Err(err),
+        t @ Value::StringValue(_) | t @ Value::StringBuffer(_) => {
+            return get_slice(t, r.start, r.end.max(r.start));
+        },
+        e => type_err!(e, 1),
+    };
+    Ok(out)
+}
+
+/// Integer or real arithmetic on operands of the same type.
+fn arith(
+    lhs: &Value, rhs: &Value, int: fn(i64, i64) -> i64, real: fn(f64, f64) -> f64,
+) -> Result<Value, VmError> {
+    match (lhs, rhs) {
+        (Value::Integer(lhs), Value::Integer(rhs)) => Ok(Value::Integer(int(*lhs, *rhs))),
+        (Value::Real(lhs), Value::Real(rhs)) => Ok(Value::Real(real(*lhs, *rhs))),
+        (Value::Integer(_), _) | (Value::Real(_), _) => type_err!(rhs, 0),
+        _ => type_err!(lhs, 1),
+    }
+}
+
+/// `ADD`, shared with the superinstructions built on it.
+fn add(lhs: &Value, rhs: &Value) -> Result<Value, VmError> {
+    arith(lhs, rhs, |lhs, rhs| lhs + rhs, |lhs, rhs| lhs + rhs)
+}
+
+/// Result of `CMP`: -1, 0 or 1, or none for values that do not compare.
+fn compare(lhs: &Value, rhs: &Value) -> Value {
+    match lhs.cmp(rhs) {
+        Some(order) => Value::Integer(match order {
+            Ordering::Less => -1,
+            Ordering::Equal => 0,
+            Ordering::Greater => 1,
+        }),
+        None => Value::None,
+    }
+}
+
+/// Whether `JUMP_ZERO` jumps on `t`.
+fn is_zero(t: Value) -> Result<bool, VmError> {
+    match t {
+        Value::Bool(t) => Ok(!t),
+        Value::Integer(t) => Ok(t == 0),
+        Value::Real(t) => Ok(t == 0.0),
+        e => type_err!(e, 0),
+    }
+}
+
+/// Whether `JUMP_NEG` jumps on `t`.
+fn is_neg(t: Value) -> Result<bool, VmError> {
+    match t {
+        Value::None => Ok(true),
+        Value::Integer(t) => Ok(t < 0),
+        Value::Real(t) => Ok(t < 0.0),
+        e => type_err!(e, 0),
+    }
+}
+
+/// Runs instructions of a verified function until one needs the call
+/// stack or the host: a call, return, yield or resume. Common instructions
+/// run here with the cursor and operand stack held in locals, the rest
+/// through `parse_and_run`. The frame is left as single steps would leave
+/// it, including on errors.
+pub fn run_many(frame: &mut CallFrame) -> Result<VmAction, VmError> {
+    let function = Rc::clone(frame.get_function());
+    let code = &function.bytecode.0[..];
+    let mut stack = frame.take_stack();
+    let mut cursor = frame.get_cursor();
+    let result = loop {
+        if let Some(out) = run_inline(frame, code, &mut stack, &mut cursor).transpose() {
+            break out;
+        }
+    };
+    frame.put_stack(stack);
+    frame.set_cursor(cursor);
+    result
+}
+
+/// One instruction of `run_many`, returning the action that ends the run.
+/// Like `parse_and_run`, `cursor` only moves once the instruction succeeds,
+/// or to just past it when a jump lands out of range.
+#[inline(always)]
+fn run_inline(
+    frame: &mut CallFrame, code: &[u8], stack: &mut Vec<Value>, cursor: &mut usize,
+) -> Result<Option<VmAction>, VmError> {
+    let mut pc = *cursor;
+    let op_code = *code.get(pc).ok_or(VmError::BytecodeRead(pc))?;
+    pc += 1;
+    match op_code {
+        NONE => (),
+        ADD => {
+            let rhs = pop(stack)?;
+            let lhs = pop(stack)?;
+            stack.push(add(&lhs, &rhs)?);
+        },
+        SUB => {
+            let rhs = pop(stack)?;
+            let lhs = pop(stack)?;
+            stack.push(arith(&lhs, &rhs, |lhs, rhs| lhs - rhs, |lhs, rhs| lhs - rhs)?);
+        },
+        MUL => {
+            let rhs = pop(stack)?;
+            let lhs = pop(stack)?;
+            stack.push(arith(&lhs, &rhs, |lhs, rhs| lhs * rhs, |lhs, rhs| lhs * rhs)?);
+        },
+        CMP => {
+            let rhs = pop(stack)?;
+            let lhs = pop(stack)?;
+            stack.push(compare(&lhs, &rhs));
+        },
+        JUMP => {
+            let dst = read_i32(code, &mut pc)?;
+            *cursor = pc;
+            pc = jump(pc, dst)?;
+        },
+        JUMP_ZERO => {
+            let dst = read_i32(code, &mut pc)?;
+            if is_zero(pop(stack)?)? {
+                *cursor = pc;
+                pc = jump(pc, dst)?;
+            }
+        },
+        JUMP_NEG => {
+            let dst = read_i32(code, &mut pc)?;
+            if is_neg(pop(stack)?)? {
+                *cursor = pc;
+                pc = jump(pc, dst)?;
+            }
+        },
+        CMP_JUMP_LT => {
+            let dst = read_i32(code, &mut pc)?;
+            let rhs = pop(stack)?;
+            let lhs = pop(stack)?;
+            if let Some(Ordering::Less) | None = lhs.cmp(&rhs) {
+                *cursor = pc;
+                pc = jump(pc, dst)?;
+            }
+        },
+        LIT_NONE => stack.push(Value::None),
+        LIT_TRUE => stack.push(Value::Bool(true)),
+        LIT_FALSE => stack.push(Value::Bool(false)),
+        LIT_INT => {
+            let b = read(code, &mut pc, 8)?;
+            stack.push(Value::Integer(i64::from_be_bytes(b.try_into().unwrap())));
+        },
+        LIT_INT_SMALL => {
+            let i = read(code, &mut pc, 1)?[0] as i8;
+            stack.push(Value::Integer(i as i64));
+        },
+        FRM_LOAD => {
+            let i = read(code, &mut pc, 1)?[0];
+            stack.push(frame.load(i)?.clone());
+        },
+        FRM_STORE => {
+            let i = read(code, &mut pc, 1)?[0];
+            let t = pop(stack)?;
+            frame.store(i, t);
+        },
+        ADD_LOCALS => {
+            let b = read(code, &mut pc, 2)?;
+            stack.push(add(frame.load(b[0])?, frame.load(b[1])?)?);
+        },
+        INC_LOCAL => {
+            let b = read(code, &mut pc, 2)?;
+            let out = add(frame.load(b[0])?, &Value::Integer(b[1] as i8 as i64))?;
+            frame.store(b[0], out);
+        },
+        FRM_COPY => {
+            let t = stack.last().ok_or(VmError::StackEmpty)?.clone();
+            stack.push(t);
+        },
+        FRM_POP => {
+            pop(stack)?;
+        },
+        _ => {
+            frame.put_stack(mem::take(stack));
+            frame.set_cursor(*cursor);
+            let action = parse_and_run(frame);
+            *stack = frame.take_stack();
+            *cursor = frame.get_cursor();
+            pc = *cursor;
+            match action? {
+                VmAction::None => (),
+                VmAction::Jump(dst) => pc = jump(pc, dst)?,
+                action => return Ok(Some(action)),
+            }
+        },
+    }
+    *cursor = pc;
+    Ok(None)
+}
+
+fn pop(stack: &mut Vec<Value>) -> Result<Value, VmError> {
+    stack.pop().ok_or(VmError::StackEmpty)
+}
+
+fn read<'a>(code: &'a [u8], cursor: &mut usize, n: usize) -> Result<&'a [u8], VmError> {
+    let t = code.get(*cursor..*cursor + n).ok_or(VmError::BytecodeRead(*cursor))?;
+    *cursor += n;
+    Ok(t)
+}
+
+fn read_i32(code: &[u8], cursor: &mut usize) -> Result<i32, VmError> {
+    Ok(i32::from_be_bytes(read(code, cursor, 4)?.try_into().unwrap()))
+}
+
+/// Target of a jump from `cursor`, failing like `CallFrame::jump` when it
+/// lands before the start, as it can from a cursor set by the host or by
+/// `snapshot::restore`.
+fn jump(cursor: usize, offset: i32) -> Result<usize, VmError> {
+    usize::try_from(cursor as i64 + offset as i64).map_err(|_| VmError::BytecodeRead(cursor))
 }
 
 pub const NONE: u8 = 1;
@@ -466,6 +986,9 @@ pub const NOT: u8 = 13;
 // real
 pub const INT_TO_REAL: u8 = 14;
 pub const REAL_TO_INT: u8 = 15;
+// char
+pub const CHAR_TO_INT: u8 = 16;
+pub const INT_TO_CHAR: u8 = 17;
 pub const CMP: u8 = 19;
 // call and jump
 pub const CALL: u8 = 20;
@@ -473,12 +996,21 @@ pub const RETURN: u8 = 21;
 pub const JUMP: u8 = 22;
 pub const JUMP_ZERO: u8 = 23;
 pub const JUMP_NEG: u8 = 24;
+pub const CALL_METHOD: u8 = 25;
+// iter
+pub const FOR_ITER: u8 = 26;
+pub const ITER_NEW: u8 = 27;
+pub const TAIL_CALL: u8 = 28;
 // literal
 pub const LIT_NONE: u8 = 30;
 pub const LIT_TRUE: u8 = 31;
 pub const LIT_FALSE: u8 = 32;
 pub const LIT_INT: u8 = 33;
 pub const LIT_REAL: u8 = 34;
+// coroutine
+pub const CO_NEW: u8 = 35;
+pub const YIELD: u8 = 36;
+pub const RESUME: u8 = 37;
 // frame
 pub const FRM_LOAD: u8 = 40;
 pub const FRM_STORE: u8 = 41;
@@ -493,10 +1025,20 @@ pub const LIST_DOWNGRADE: u8 = 53;
 pub const LIST_UPGRADE: u8 = 54;
 // bytes
 pub const BYTES_CREATE: u8 = 55;
+pub const BYTES_DECODE: u8 = 56;
+pub const BYTES_READ: u8 = 57;
+pub const BYTES_WRITE: u8 = 58;
 // string
 pub const STR_CREATE: u8 = 60;
 pub const STR_CHAR_AT: u8 = 61;
 pub const STR_CHARS: u8 = 62;
+pub const TO_STRING: u8 = 63;
+pub const STR_APPEND: u8 = 64;
+pub const STR_FREEZE: u8 = 65;
+pub const STR_CONCAT: u8 = 66;
+pub const STR_FORMAT: u8 = 67;
+pub const STR_NEXT_CHAR: u8 = 68;
+pub const STR_ENCODE: u8 = 69;
 // seq
 pub const SEQ_GET: u8 = 70;
 pub const SEQ_SET: u8 = 71;
@@ -505,7 +1047,15 @@ pub const SEQ_SET_SLICE: u8 = 73;
 pub const SEQ_APPEND: u8 = 74;
 pub const SEQ_LEN: u8 = 75;
 pub const SEQ_RESIZE: u8 = 76;
+// range
+pub const RANGE_NEW: u8 = 80;
+// superinstructions, see `optimize::fuse`
+pub const ADD_LOCALS: u8 = 90;
+pub const INC_LOCAL: u8 = 91;
+pub const CMP_JUMP_LT: u8 = 92;
+pub const LIT_INT_SMALL: u8 = 93;
 
+#[derive(Clone, Debug, PartialEq)]
 pub enum Operation {
     None,
     // int and real math
@@ -525,6 +1075,9 @@ pub enum Operation {
     // real
     IntToReal,
     RealToInt,
+    // char
+    CharToInt,
+    IntToChar,
     Cmp,
     // call and jump
     Call(u8),
@@ -532,12 +1085,25 @@ pub enum Operation {
     Jump(usize),
     JumpZero(usize),
     JumpNeg(usize),
+    CallMethod(u8),
+    // iter
+    /// Pushes the next item, or pops the iterator and jumps when exhausted.
+    ForIter(usize),
+    IterNew,
+    /// `CALL` that reuses the caller's frame; natives return straight to the
+    /// caller's caller.
+    TailCall(u8),
     // literal
     LiteralNone,
     LiteralTrue,
     LiteralFalse,
     LiteralInteger(i64),
     LiteralReal(f64),
+    // coroutine
+    CoroutineNew(u8),
+    Yield,
+    /// Pushes the yielded or returned value, then whether it returned.
+    Resume,
     // frame
     FrameLocalLoad(u8),
     FrameLocalStore(u8),
@@ -552,10 +1118,20 @@ pub enum Operation {
     ListUpgrade,
     // bytes
     BytesBufferCreate,
+    BytesDecode(Encoding),
+    BytesRead(Packing),
+    BytesWrite(Packing),
     // string
     StringBufferCreate,
     StringGetCharAt,
     StringGetChars,
+    ToString,
+    StringAppend,
+    StringFreeze,
+    StringConcat,
+    StringFormat(u8),
+    StringNextChar,
+    StringEncode(Encoding),
     // seq
     SeqGet,
     SeqSet,
@@ -564,6 +1140,17 @@ pub enum Operation {
     SeqAppend,
     SeqLen,
     SeqResize,
+    // range
+    RangeNew,
+    // superinstructions
+    /// `FRAME_LOCAL_LOAD a; FRAME_LOCAL_LOAD b; ADD`.
+    AddLocals(u8, u8),
+    /// `FRAME_LOCAL_LOAD n; LIT_INT step; ADD; FRAME_LOCAL_STORE n`.
+    IncLocal(u8, i8),
+    /// `CMP; JUMP_NEG`, so it also jumps when the operands do not compare.
+    CmpJumpLt(usize),
+    /// `LIT_INT` in two bytes instead of nine.
+    LiteralIntegerSmall(i8),
 }
 
 pub fn assemble(ops: &[Operation]) -> Option<Vec<u8>> {
@@ -588,6 +1175,8 @@ pub fn assemble(ops: &[Operation]) -> Option<Vec<u8>> {
             Operation::Not => out.push(NOT),
             Operation::IntToReal => out.push(INT_TO_REAL),
             Operation::RealToInt => out.push(REAL_TO_INT),
+            Operation::CharToInt => out.push(CHAR_TO_INT),
+            Operation::IntToChar => out.push(INT_TO_CHAR),
             Operation::Cmp => out.push(CMP),
             Operation::Call(n) => {
                 out.push(CALL);
@@ -609,6 +1198,20 @@ pub fn assemble(ops: &[Operation]) -> Option<Vec<u8>> {
                 jumps.push((out.len(), *n));
                 out.extend_from_slice(&[0; 4]);
             },
+            Operation::CallMethod(n) => {
+                out.push(CALL_METHOD);
+                out.push(*n);
+            },
+            Operation::ForIter(n) => {
+                out.push(FOR_ITER);
+                jumps.push((out.len(), *n));
+                out.extend_from_slice(&[0; 4]);
+            },
+            Operation::IterNew => out.push(ITER_NEW),
+            Operation::TailCall(n) => {
+                out.push(TAIL_CALL);
+                out.push(*n);
+            },
             Operation::LiteralNone => out.push(LIT_NONE),
             Operation::LiteralTrue => out.push(LIT_TRUE),
             Operation::LiteralFalse => out.push(LIT_FALSE),
@@ -620,6 +1223,12 @@ pub fn assemble(ops: &[Operation]) -> Option<Vec<u8>> {
                 out.push(LIT_REAL);
                 out.extend_from_slice(&n.to_be_bytes());
             },
+            Operation::CoroutineNew(n) => {
+                out.push(CO_NEW);
+                out.push(*n);
+            },
+            Operation::Yield => out.push(YIELD),
+            Operation::Resume => out.push(RESUME),
             Operation::FrameLocalLoad(n) => {
                 out.push(FRM_LOAD);
                 out.push(*n);
@@ -640,9 +1249,34 @@ pub fn assemble(ops: &[Operation]) -> Option<Vec<u8>> {
             Operation::ListDowngrade => out.push(LIST_DOWNGRADE),
             Operation::ListUpgrade => out.push(LIST_UPGRADE),
             Operation::BytesBufferCreate => out.push(BYTES_CREATE),
+            Operation::BytesDecode(e) => {
+                out.push(BYTES_DECODE);
+                out.push(e.to_u8());
+            },
+            Operation::BytesRead(p) => {
+                out.push(BYTES_READ);
+                out.push(p.to_u8());
+            },
+            Operation::BytesWrite(p) => {
+                out.push(BYTES_WRITE);
+                out.push(p.to_u8());
+            },
             Operation::StringBufferCreate => out.push(STR_CREATE),
             Operation::StringGetCharAt => out.push(STR_CHAR_AT),
             Operation::StringGetChars => out.push(STR_CHARS),
+            Operation::ToString => out.push(TO_STRING),
+            Operation::StringAppend => out.push(STR_APPEND),
+            Operation::StringFreeze => out.push(STR_FREEZE),
+            Operation::StringConcat => out.push(STR_CONCAT),
+            Operation::StringNextChar => out.push(STR_NEXT_CHAR),
+            Operation::StringEncode(e) => {
+                out.push(STR_ENCODE);
+                out.push(e.to_u8());
+            },
+            Operation::StringFormat(n) => {
+                out.push(STR_FORMAT);
+                out.push(*n);
+            },
             Operation::SeqGet => out.push(SEQ_GET),
             Operation::SeqSet => out.push(SEQ_SET),
             Operation::SeqGetSlice => out.push(SEQ_GET_SLICE),
@@ -650,127 +1284,285 @@ pub fn assemble(ops: &[Operation]) -> Option<Vec<u8>> {
             Operation::SeqAppend => out.push(SEQ_APPEND),
             Operation::SeqLen => out.push(SEQ_LEN),
             Operation::SeqResize => out.push(SEQ_RESIZE),
+            Operation::RangeNew => out.push(RANGE_NEW),
+            Operation::AddLocals(a, b) => out.extend_from_slice(&[ADD_LOCALS, *a, *b]),
+            Operation::IncLocal(n, step) => out.extend_from_slice(&[INC_LOCAL, *n, *step as u8]),
+            Operation::CmpJumpLt(n) => {
+                out.push(CMP_JUMP_LT);
+                jumps.push((out.len(), *n));
+                out.extend_from_slice(&[0; 4]);
+            },
+            Operation::LiteralIntegerSmall(t) => out.extend_from_slice(&[LIT_INT_SMALL, *t as u8]),
         }
     }
     for (j, dst) in jumps {
         let i = *offsets.get(dst)? as isize;
-        let n: i32 = ((j as isize) - i - 1).try_into().ok()?;
+        let n: i32 = (i - (j as isize + 4)).try_into().ok()?;
         out.get_mut(j..j+4)?.copy_from_slice(&n.to_be_bytes());
     }
     Some(out)
 }
 
+/// Decodes the instruction at `cursor`, returning it along with the offset
+/// of the next instruction. Jump targets are absolute bytecode offsets.
+pub fn decode(bytecode: &[u8], mut cursor: usize) -> Option<(Operation, usize)> {
+    let op_code = *bytecode.get(cursor)?;
+    cursor += 1;
+    let op = match op_code {
+        NONE => Operation::None,
+        ADD => Operation::Add,
+        SUB => Operation::Sub,
+        MUL => Operation::Mul,
+        DIV => Operation::Div,
+        REM => Operation::Rem,
+        NEG => Operation::Neg,
+        SHL => Operation::Shl,
+        SHR => Operation::Shr,
+        AND => Operation::And,
+        OR  => Operation::Or,
+        XOR => Operation::Xor,
+        NOT => Operation::Not,
+        INT_TO_REAL => Operation::IntToReal,
+        REAL_TO_INT => Operation::RealToInt,
+        CHAR_TO_INT => Operation::CharToInt,
+        INT_TO_CHAR => Operation::IntToChar,
+        CMP => Operation::Cmp,
+        CALL => {
+            let n = bytecode.get(cursor)?;
+            cursor += 1;
+            Operation::Call(*n)
+        },
+        RETURN => Operation::Return,
+        JUMP => {
+            let dst = bytecode.get(cursor..cursor+4)?;
+            cursor += 4;
+            let dst = i32::from_be_bytes(dst.try_into().unwrap());
+            Operation::Jump((cursor as i32 + dst) as usize)
+        },
+        JUMP_ZERO => {
+            let dst = bytecode.get(cursor..cursor+4)?;
+            cursor += 4;
+            let dst = i32::from_be_bytes(dst.try_into().unwrap());
+            Operation::JumpZero((cursor as i32 + dst) as usize)
+        },
+        JUMP_NEG => {
+            let dst = bytecode.get(cursor..cursor+4)?;
+            cursor += 4;
+            let dst = i32::from_be_bytes(dst.try_into().unwrap());
+            Operation::JumpNeg((cursor as i32 + dst) as usize)
+        },
+        CALL_METHOD => {
+            let n = bytecode.get(cursor)?;
+            cursor += 1;
+            Operation::CallMethod(*n)
+        },
+        FOR_ITER => {
+            let dst = bytecode.get(cursor..cursor+4)?;
+            cursor += 4;
+            let dst = i32::from_be_bytes(dst.try_into().unwrap());
+            Operation::ForIter((cursor as i32 + dst) as usize)
+        },
+        ITER_NEW => Operation::IterNew,
+        TAIL_CALL => {
+            let n = bytecode.get(cursor)?;
+            cursor += 1;
+            Operation::TailCall(*n)
+        },
+        LIT_NONE => Operation::LiteralNone,
+        LIT_TRUE => Operation::LiteralTrue,
+        LIT_FALSE => Operation::LiteralFalse,
+        LIT_INT => {
+            let n = bytecode.get(cursor..cursor+8)?;
+            cursor += 8;
+            let int = i64::from_be_bytes(n.try_into().unwrap());
+            Operation::LiteralInteger(int)
+        },
+        LIT_INT_SMALL => {
+            let n = bytecode.get(cursor)?;
+            cursor += 1;
+            Operation::LiteralIntegerSmall(*n as i8)
+        },
+        LIT_REAL => {
+            let n = bytecode.get(cursor..cursor+8)?;
+            cursor += 8;
+            let real = f64::from_be_bytes(n.try_into().unwrap());
+            Operation::LiteralReal(real)
+        },
+        CO_NEW => {
+            let n = bytecode.get(cursor)?;
+            cursor += 1;
+            Operation::CoroutineNew(*n)
+        },
+        YIELD => Operation::Yield,
+        RESUME => Operation::Resume,
+        FRM_LOAD => {
+            let n = bytecode.get(cursor)?;
+            cursor += 1;
+            Operation::FrameLocalLoad(*n)
+        },
+        ADD_LOCALS => {
+            let n = bytecode.get(cursor..cursor+2)?;
+            cursor += 2;
+            Operation::AddLocals(n[0], n[1])
+        },
+        INC_LOCAL => {
+            let n = bytecode.get(cursor..cursor+2)?;
+            cursor += 2;
+            Operation::IncLocal(n[0], n[1] as i8)
+        },
+        CMP_JUMP_LT => {
+            let dst = bytecode.get(cursor..cursor+4)?;
+            cursor += 4;
+            let dst = i32::from_be_bytes(dst.try_into().unwrap());
+            Operation::CmpJumpLt((cursor as i32 + dst) as usize)
+        },
+        FRM_STORE => {
+            let n = bytecode.get(cursor)?;
+            cursor += 1;
+            Operation::FrameLocalStore(*n)
+        },
+        FRM_SWAP => {
+            let n = bytecode.get(cursor)?;
+            cursor += 1;
+            Operation::FrameLocalSwap(*n)
+        },
+        FRM_COPY => Operation::FrameStackCopy,
+        FRM_POP => Operation::FrameStackPop,
+        LIST_CREATE => Operation::ListCreate,
+        LIST_PUSH => Operation::ListPush,
+        LIST_POP => Operation::ListPop,
+        LIST_DOWNGRADE => Operation::ListDowngrade,
+        LIST_UPGRADE => Operation::ListUpgrade,
+        BYTES_CREATE => Operation::BytesBufferCreate,
+        BYTES_DECODE => {
+            let e = Encoding::from_u8(*bytecode.get(cursor)?)?;
+            cursor += 1;
+            Operation::BytesDecode(e)
+        },
+        BYTES_READ => {
+            let p = Packing::from_u8(*bytecode.get(cursor)?)?;
+            cursor += 1;
+            Operation::BytesRead(p)
+        },
+        BYTES_WRITE => {
+            let p = Packing::from_u8(*bytecode.get(cursor)?)?;
+            cursor += 1;
+            Operation::BytesWrite(p)
+        },
+        STR_CREATE => Operation::StringBufferCreate,
+        STR_CHAR_AT => Operation::StringGetCharAt,
+        STR_CHARS => Operation::StringGetChars,
+        TO_STRING => Operation::ToString,
+        STR_APPEND => Operation::StringAppend,
+        STR_FREEZE => Operation::StringFreeze,
+        STR_CONCAT => Operation::StringConcat,
+        STR_NEXT_CHAR => Operation::StringNextChar,
+        STR_ENCODE => {
+            let e = Encoding::from_u8(*bytecode.get(cursor)?)?;
+            cursor += 1;
+            Operation::StringEncode(e)
+        },
+        STR_FORMAT => {
+            let n = bytecode.get(cursor)?;
+            cursor += 1;
+            Operation::StringFormat(*n)
+        },
+        SEQ_GET => Operation::SeqGet,
+        SEQ_SET => Operation::SeqSet,
+        SEQ_GET_SLICE => Operation::SeqGetSlice,
+        SEQ_SET_SLICE => Operation::SeqSetSlice,
+        SEQ_APPEND => Operation::SeqAppend,
+        SEQ_LEN => Operation::SeqLen,
+        SEQ_RESIZE => Operation::SeqResize,
+        RANGE_NEW => Operation::RangeNew,
+        _ => return None,
+    };
+    Some((op, cursor))
+}
+
+/// Checks that `bytecode` decodes to its end and that every jump lands on
+/// the start of an instruction. This only selects functions for `run_many`,
+/// which still checks every read and jump, since the host and
+/// `snapshot::restore` can leave a cursor anywhere.
+pub fn verify(bytecode: &[u8]) -> bool {
+    disassemble(bytecode).is_some()
+}
+
 pub fn disassemble(bytecode: &[u8]) -> Option<Vec<Operation>> {
     let mut offsets = vec![];
-    let mut jumps = vec![];
     let mut ops = vec![];
     let mut cursor = 0;
-    while let Some(op_code) = bytecode.get(cursor) {
+    while cursor < bytecode.len() {
         offsets.push(cursor);
-        cursor += 1;
-        match *op_code {
-            NONE => ops.push(Operation::None),
-            ADD => ops.push(Operation::Add),
-            SUB => ops.push(Operation::Sub),
-            MUL => ops.push(Operation::Mul),
-            DIV => ops.push(Operation::Div),
-            REM => ops.push(Operation::Rem),
-            NEG => ops.push(Operation::Neg),
-            SHL => ops.push(Operation::Shl),
-            SHR => ops.push(Operation::Shr),
-            AND => ops.push(Operation::And),
-            OR  => ops.push(Operation::Or),
-            XOR => ops.push(Operation::Xor),
-            NOT => ops.push(Operation::Not),
-            INT_TO_REAL => ops.push(Operation::IntToReal),
-            REAL_TO_INT => ops.push(Operation::RealToInt),
-            CMP => ops.push(Operation::Cmp),
-            CALL => {
-                let n = bytecode.get(cursor)?;
-                cursor += 1;
-                ops.push(Operation::Call(*n));
-            },
-            RETURN => ops.push(Operation::Return),
-            JUMP => {
-                let dst = bytecode.get(cursor..cursor+4)?;
-                cursor += 4;
-                let dst = i32::from_be_bytes(dst.try_into().unwrap());
-                jumps.push((ops.len(), (cursor as i32 + dst) as usize));
-                ops.push(Operation::Jump(0));
-            },
-            JUMP_ZERO => {
-                let dst = bytecode.get(cursor..cursor+4)?;
-                cursor += 4;
-                let dst = i32::from_be_bytes(dst.try_into().unwrap());
-                jumps.push((ops.len(), (cursor as i32 + dst) as usize));
-                ops.push(Operation::JumpZero(0));
-            },
-            JUMP_NEG => {
-                let dst = bytecode.get(cursor..cursor+4)?;
-                cursor += 4;
-                let dst = i32::from_be_bytes(dst.try_into().unwrap());
-                jumps.push((ops.len(), (cursor as i32 + dst) as usize));
-                ops.push(Operation::JumpNeg(0));
-            },
-            LIT_NONE => ops.push(Operation::LiteralNone),
-            LIT_TRUE => ops.push(Operation::LiteralTrue),
-            LIT_FALSE => ops.push(Operation::LiteralFalse),
-            LIT_INT => {
-                let n = bytecode.get(cursor..cursor+8)?;
-                cursor += 8;
-                let int = i64::from_be_bytes(n.try_into().unwrap());
-                ops.push(Operation::LiteralInteger(int))
-            },
-            LIT_REAL => {
-                let n = bytecode.get(cursor..cursor+8)?;
-                cursor += 8;
-                let real = f64::from_be_bytes(n.try_into().unwrap());
-                ops.push(Operation::LiteralReal(real))
-            },
-            FRM_LOAD => {
-                let n = bytecode.get(cursor)?;
-                cursor += 1;
-                ops.push(Operation::FrameLocalLoad(*n));
-            },
-            FRM_STORE => {
-                let n = bytecode.get(cursor)?;
-                cursor += 1;
-                ops.push(Operation::FrameLocalStore(*n));
-            },
-            FRM_SWAP => {
-                let n = bytecode.get(cursor)?;
-                cursor += 1;
-                ops.push(Operation::FrameLocalSwap(*n));
-            },
-            FRM_COPY => ops.push(Operation::FrameStackCopy),
-            FRM_POP => ops.push(Operation::FrameStackPop),
-            LIST_CREATE => ops.push(Operation::ListCreate),
-            LIST_PUSH => ops.push(Operation::ListPush),
-            LIST_POP => ops.push(Operation::ListPop),
-            LIST_DOWNGRADE => ops.push(Operation::ListDowngrade),
-            LIST_UPGRADE => ops.push(Operation::ListUpgrade),
-            BYTES_CREATE => ops.push(Operation::BytesBufferCreate),
-            STR_CREATE => ops.push(Operation::StringBufferCreate),
-            STR_CHAR_AT => ops.push(Operation::StringGetCharAt),
-            STR_CHARS => ops.push(Operation::StringGetChars),
-            SEQ_GET => ops.push(Operation::SeqGet),
-            SEQ_SET => ops.push(Operation::SeqSet),
-            SEQ_GET_SLICE => ops.push(Operation::SeqGetSlice),
-            SEQ_SET_SLICE => ops.push(Operation::SeqSetSlice),
-            SEQ_APPEND => ops.push(Operation::SeqAppend),
-            SEQ_LEN => ops.push(Operation::SeqLen),
-            SEQ_RESIZE => ops.push(Operation::SeqResize),
-            _ => return None,
-        }
+        let (op, next) = decode(bytecode, cursor)?;
+        ops.push(op);
+        cursor = next;
     }
-    for (i, j) in jumps {
-        let dst = offsets.binary_search(&j).ok()?;
-        match &mut ops[i] {
+    for op in ops.iter_mut() {
+        match op {
             | Operation::Jump(n)
             | Operation::JumpZero(n)
-            | Operation::JumpNeg(n) => *n = dst,
-            _ => unreachable!(),
+            | Operation::JumpNeg(n)
+            | Operation::ForIter(n)
+            | Operation::CmpJumpLt(n) => *n = offsets.binary_search(n).ok()?,
+            _ => (),
         }
     }
     Some(ops)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_and_run, Operation};
+    use crate::datamodel::{List, Value};
+    use crate::machine::CallFrame;
+    use crate::test_util::function;
+    use crate::{VmAction, VmError};
+
+    fn frame(ops: &[Operation]) -> CallFrame {
+        CallFrame::new(function(List::from_vec(vec![]), ops), vec![])
+    }
+
+    fn native(_: Vec<Value>) -> Result<Value, VmError> {
+        Ok(Value::None)
+    }
+
+    #[test]
+    fn call_passes_arguments_in_order() {
+        // arguments were handed over last first
+        let mut frame = frame(&[
+            Operation::LiteralInteger(1),
+            Operation::LiteralInteger(2),
+            Operation::Call(2),
+        ]);
+        parse_and_run(&mut frame).ok().unwrap();
+        parse_and_run(&mut frame).ok().unwrap();
+        frame.push(Value::NativeFn(native));
+        match parse_and_run(&mut frame).ok().unwrap() {
+            VmAction::CallNative(_, args) => {
+                assert!(matches!(args[..], [Value::Integer(1), Value::Integer(2)]));
+            },
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn jumps_are_relative_to_the_next_instruction() {
+        // offsets were encoded backwards from the immediate
+        let mut frame = frame(&[
+            Operation::Jump(2),
+            Operation::LiteralTrue,
+            // 2
+            Operation::Jump(1),
+        ]);
+        match parse_and_run(&mut frame).ok().unwrap() {
+            VmAction::Jump(offset) => assert_eq!(frame.get_cursor() as i32 + offset, 6),
+            _ => panic!(),
+        }
+        frame.set_cursor(6);
+        match parse_and_run(&mut frame).ok().unwrap() {
+            VmAction::Jump(offset) => assert_eq!(frame.get_cursor() as i32 + offset, 5),
+            _ => panic!(),
+        }
+    }
+}
@@ -0,0 +1,607 @@
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+
+use crate::operation::Operation;
+
+/// Rewrites `ops` into a shorter sequence with the same behaviour, running
+/// every pass until none of them changes anything:
+///
+/// - `tail_calls`
+/// - folding arithmetic, bitwise ops, conversions and `CMP` on literals,
+///   except where the result would overflow or fail at run time
+/// - dropping `FRAME_STACK_COPY; FRAME_STACK_POP`
+/// - turning `FRAME_LOCAL_STORE n; FRAME_LOCAL_LOAD n` into a stack copy and
+///   the store, so the value is not read back from the local
+/// - threading jumps whose target is an unconditional jump, and dropping
+///   jumps to the next operation
+/// - dropping code no path from the first operation reaches
+/// - finally, `fuse`
+///
+/// Jump targets are remapped as operations are removed. Patterns spanning
+/// several operations are left alone when a jump lands inside them.
+pub fn optimize(ops: &mut Vec<Operation>) {
+    tail_calls(ops);
+    loop {
+        let mut changed = fold_constants(ops);
+        changed |= remove_copy_pop(ops);
+        changed |= collapse_store_load(ops);
+        changed |= thread_jumps(ops);
+        changed |= remove_unreachable(ops);
+        if !changed {
+            break;
+        }
+    }
+    fuse(ops);
+}
+
+/// Replaces common sequences with superinstructions: `ADD_LOCALS`,
+/// `INC_LOCAL` for adding or subtracting a small literal in place,
+/// `CMP_JUMP_LT` and `LIT_INT_SMALL`. The other passes do not look into
+/// these, so this runs last.
+pub fn fuse(ops: &mut Vec<Operation>) {
+    use Operation::{
+        Add, AddLocals, Cmp, CmpJumpLt, FrameLocalLoad, FrameLocalStore, IncLocal, JumpNeg,
+        LiteralInteger, LiteralIntegerSmall, Sub,
+    };
+    let targets = jump_targets(ops);
+    let mut keep = vec![true; ops.len()];
+    let inside = |i: usize, len: usize| (i + 1..i + len).any(|t| targets.get(t) != Some(&false));
+    let mut i = 0;
+    while i < ops.len() {
+        let small = |op: Option<&Operation>| match op {
+            Some(&LiteralInteger(t)) => i8::try_from(t).ok(),
+            Some(&LiteralIntegerSmall(t)) => Some(t),
+            _ => None,
+        };
+        let inc = match (&ops[i], ops.get(i + 2), ops.get(i + 3)) {
+            (&FrameLocalLoad(n), Some(op @ (Add | Sub)), Some(&FrameLocalStore(m)))
+                if n == m && !inside(i, 4) => {
+                small(ops.get(i + 1))
+                    .and_then(|t| if matches!(op, Add) { Some(t) } else { t.checked_neg() })
+                    .map(|t| IncLocal(n, t))
+            },
+            _ => None,
+        };
+        let (fused, len) = match (inc, &ops[i], ops.get(i + 1), ops.get(i + 2)) {
+            (Some(t), _, _, _) => (t, 4),
+            (_, &FrameLocalLoad(a), Some(&FrameLocalLoad(b)), Some(Add)) if !inside(i, 3) => (AddLocals(a, b), 3),
+            (_, Cmp, Some(&JumpNeg(t)), _) if !inside(i, 2) => (CmpJumpLt(t), 2),
+            (_, op, _, _) => match small(Some(op)) {
+                Some(t) => (LiteralIntegerSmall(t), 1),
+                None => (op.clone(), 1),
+            },
+        };
+        ops[i] = fused;
+        for k in &mut keep[i + 1..i + len] {
+            *k = false;
+        }
+        i += len;
+    }
+    compact(ops, &keep);
+}
+
+/// Turns each `CALL` directly followed by `RETURN` into a `TAIL_CALL`.
+///
+/// The `RETURN` is left in place, so jumps aimed at it and the indexes of
+/// all operations stay valid.
+pub fn tail_calls(ops: &mut [Operation]) {
+    for i in 1..ops.len() {
+        if let (Operation::Call(n), Operation::Return) = (&ops[i - 1], &ops[i]) {
+            ops[i - 1] = Operation::TailCall(*n);
+        }
+    }
+}
+
+fn target_mut(op: &mut Operation) -> Option<&mut usize> {
+    match op {
+        | Operation::Jump(t)
+        | Operation::JumpZero(t)
+        | Operation::JumpNeg(t)
+        | Operation::ForIter(t)
+        | Operation::CmpJumpLt(t) => Some(t),
+        _ => None,
+    }
+}
+
+fn target(op: &Operation) -> Option<usize> {
+    let mut op = op.clone();
+    target_mut(&mut op).copied()
+}
+
+/// Which operations some jump lands on.
+fn jump_targets(ops: &[Operation]) -> Vec<bool> {
+    let mut out = vec![false; ops.len()];
+    for t in ops.iter().filter_map(target) {
+        if let Some(t) = out.get_mut(t) {
+            *t = true;
+        }
+    }
+    out
+}
+
+/// Removes the operations not marked in `keep`, pointing jumps at removed
+/// operations to the next one kept. Returns whether anything was removed.
+fn compact(ops: &mut Vec<Operation>, keep: &[bool]) -> bool {
+    let mut index = Vec::with_capacity(ops.len() + 1);
+    let mut kept = 0;
+    for &k in keep {
+        index.push(kept);
+        kept += k as usize;
+    }
+    index.push(kept);
+    if kept == ops.len() {
+        return false;
+    }
+    let mut i = 0;
+    ops.retain(|_| {
+        i += 1;
+        keep[i - 1]
+    });
+    for op in ops.iter_mut() {
+        if let Some(t) = target_mut(op) {
+            *t = index[(*t).min(keep.len())];
+        }
+    }
+    true
+}
+
+fn ordering(order: Ordering) -> i64 {
+    match order {
+        Ordering::Less => -1,
+        Ordering::Equal => 0,
+        Ordering::Greater => 1,
+    }
+}
+
+fn fold_unary(operand: &Operation, op: &Operation) -> Option<Operation> {
+    use Operation::{IntToReal, LiteralInteger, LiteralReal, Neg, Not, RealToInt};
+    Some(match (operand, op) {
+        (LiteralInteger(a), Neg) => LiteralInteger(a.checked_neg()?),
+        (LiteralInteger(a), Not) => LiteralInteger(!a),
+        (LiteralInteger(a), IntToReal) => LiteralReal(*a as f64),
+        (LiteralInteger(a), RealToInt) => LiteralInteger(*a),
+        (LiteralReal(a), Neg) => LiteralReal(-a),
+        (LiteralReal(a), IntToReal) => LiteralReal(*a),
+        (LiteralReal(a), RealToInt) => LiteralInteger(*a as i64),
+        _ => return None,
+    })
+}
+
+fn fold_binary(lhs: &Operation, rhs: &Operation, op: &Operation) -> Option<Operation> {
+    use Operation::{
+        Add, And, Cmp, Div, LiteralInteger, LiteralNone, LiteralReal, Mul, Or, Rem, Shl, Shr, Sub, Xor,
+    };
+    Some(match (lhs, rhs) {
+        (&LiteralInteger(a), &LiteralInteger(b)) => LiteralInteger(match op {
+            Add => a.checked_add(b)?,
+            Sub => a.checked_sub(b)?,
+            Mul => a.checked_mul(b)?,
+            Div => a.checked_div(b)?,
+            Rem => a.checked_rem(b)?,
+            Shl if (0..64).contains(&b) => a << b,
+            Shr if (0..64).contains(&b) => a >> b,
+            And => a & b,
+            Or => a | b,
+            Xor => a ^ b,
+            Cmp => ordering(a.cmp(&b)),
+            _ => return None,
+        }),
+        (&LiteralReal(a), &LiteralReal(b)) => match op {
+            Add => LiteralReal(a + b),
+            Sub => LiteralReal(a - b),
+            Mul => LiteralReal(a * b),
+            Div => LiteralReal(a / b),
+            Rem => LiteralReal(a % b),
+            Cmp => match a.partial_cmp(&b) {
+                Some(order) => LiteralInteger(ordering(order)),
+                None => LiteralNone,
+            },
+            _ => return None,
+        },
+        _ => return None,
+    })
+}
+
+fn fold_constants(ops: &mut Vec<Operation>) -> bool {
+    let targets = jump_targets(ops);
+    let mut keep = vec![true; ops.len()];
+    let mut i = 0;
+    while i + 1 < ops.len() {
+        if !targets[i + 1] {
+            if let Some(t) = fold_unary(&ops[i], &ops[i + 1]) {
+                ops[i] = t;
+                keep[i + 1] = false;
+                i += 2;
+                continue;
+            }
+            if i + 2 < ops.len() && !targets[i + 2] {
+                if let Some(t) = fold_binary(&ops[i], &ops[i + 1], &ops[i + 2]) {
+                    ops[i] = t;
+                    keep[i + 1] = false;
+                    keep[i + 2] = false;
+                    i += 3;
+                    continue;
+                }
+            }
+        }
+        i += 1;
+    }
+    compact(ops, &keep)
+}
+
+fn remove_copy_pop(ops: &mut Vec<Operation>) -> bool {
+    let targets = jump_targets(ops);
+    let mut keep = vec![true; ops.len()];
+    let mut i = 0;
+    // a pair ending the code must stay, as jumps to it need somewhere to land
+    while i + 2 < ops.len() {
+        if let (Operation::FrameStackCopy, Operation::FrameStackPop) = (&ops[i], &ops[i + 1]) {
+            if !targets[i + 1] {
+                keep[i] = false;
+                keep[i + 1] = false;
+                i += 2;
+                continue;
+            }
+        }
+        i += 1;
+    }
+    compact(ops, &keep)
+}
+
+fn collapse_store_load(ops: &mut [Operation]) -> bool {
+    let targets = jump_targets(ops);
+    let mut changed = false;
+    for i in 1..ops.len() {
+        if let (&Operation::FrameLocalStore(a), &Operation::FrameLocalLoad(b)) = (&ops[i - 1], &ops[i]) {
+            if a == b && !targets[i] {
+                ops[i - 1] = Operation::FrameStackCopy;
+                ops[i] = Operation::FrameLocalStore(a);
+                changed = true;
+            }
+        }
+    }
+    changed
+}
+
+fn thread_jumps(ops: &mut Vec<Operation>) -> bool {
+    let mut changed = false;
+    for i in 0..ops.len() {
+        let mut t = match target(&ops[i]) {
+            Some(t) => t,
+            None => continue,
+        };
+        // bounded, since jumps can form a loop
+        for _ in 0..ops.len() {
+            match ops.get(t) {
+                Some(&Operation::Jump(next)) if next != t => t = next,
+                _ => break,
+            }
+        }
+        let old = target_mut(&mut ops[i]).unwrap();
+        if *old != t {
+            *old = t;
+            changed = true;
+        }
+    }
+    let keep: Vec<bool> = ops.iter().enumerate()
+        .map(|(i, op)| !matches!(op, &Operation::Jump(t) if t == i + 1))
+        .collect();
+    compact(ops, &keep) || changed
+}
+
+/// Marks what control can reach from the first operation, then drops the
+/// rest.
+fn remove_unreachable(ops: &mut Vec<Operation>) -> bool {
+    let mut reached = vec![false; ops.len()];
+    let mut work = vec![0];
+    while let Some(i) = work.pop() {
+        if i >= ops.len() || reached[i] {
+            continue;
+        }
+        reached[i] = true;
+        if let Some(t) = target(&ops[i]) {
+            work.push(t);
+        }
+        match ops[i] {
+            Operation::Return | Operation::Jump(_) | Operation::TailCall(_) => (),
+            _ => work.push(i + 1),
+        }
+    }
+    compact(ops, &reached)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::{fold_constants, optimize, tail_calls};
+    use crate::datamodel::{Function, List, Value};
+    use crate::machine::{CallStack, Vm};
+    use crate::native::IntoNative;
+    use crate::operation::{assemble, disassemble, Operation};
+    use crate::profiler::Profiler;
+    use crate::test_util::function;
+    use crate::VmError;
+
+    fn run(ops: &[Operation], args: &[Value]) -> Result<Value, VmError> {
+        Vm::new().call(&Value::Function(function(List::from_vec(vec![]), ops)), args)
+    }
+
+    /// Outcome of running `ops` before and after optimizing them.
+    fn both(ops: &[Operation], args: &[Value]) -> (String, String) {
+        let mut optimized = ops.to_vec();
+        optimize(&mut optimized);
+        (format!("{:?}", run(ops, args)), format!("{:?}", run(&optimized, args)))
+    }
+
+    /// `f(n, acc)` returns `acc` once `n` reaches zero, otherwise recurses
+    /// with `f(n - 1, acc + n)` or, below 3, hands `acc` to the native in
+    /// module slot 1.
+    fn sum_to(native: Value) -> (Rc<Function>, List) {
+        let module = List::from_vec(vec![Value::None, native]);
+        let mut ops = vec![
+            Operation::FrameLocalLoad(1),
+            Operation::LiteralInteger(3),
+            Operation::Cmp,
+            Operation::JumpNeg(15),
+            Operation::FrameLocalLoad(1),
+            Operation::LiteralInteger(1),
+            Operation::Sub,
+            Operation::FrameLocalLoad(2),
+            Operation::FrameLocalLoad(1),
+            Operation::Add,
+            Operation::FrameLocalLoad(0),
+            Operation::LiteralInteger(0),
+            Operation::SeqGet,
+            Operation::Call(2),
+            Operation::Return,
+            // small: 15
+            Operation::FrameLocalLoad(2),
+            Operation::FrameLocalLoad(0),
+            Operation::LiteralInteger(1),
+            Operation::SeqGet,
+            Operation::Call(1),
+            Operation::Return,
+        ];
+        tail_calls(&mut ops);
+        assert_eq!(ops[13], Operation::TailCall(2));
+        assert_eq!(ops[19], Operation::TailCall(1));
+        let f = function(module.clone(), &ops);
+        module.set(0, Value::Function(f.clone()));
+        (f, module)
+    }
+
+    #[test]
+    fn tail_recursion_keeps_one_frame() {
+        // the native adds the 1 + 2 the recursion skipped
+        let finish = Value::NativeFunction((|acc: i64| acc + 3).into_native());
+        let (f, module) = sum_to(finish);
+        let mut stack = CallStack::new(f.clone(), vec![Value::Integer(100_000), Value::Integer(0)]);
+        let mut vm = Vm::new();
+        let out = loop {
+            assert_eq!(stack.depth(), 1);
+            if let Some(out) = stack.step(&mut vm).unwrap() {
+                break out;
+            }
+        };
+        assert!(matches!(out, Value::Integer(5_000_050_000)));
+
+        let mut profiler = Profiler::default();
+        let mut stack = CallStack::new(f.clone(), vec![Value::Integer(10), Value::Integer(0)]);
+        assert!(matches!(profiler.run(&mut vm, &mut stack), Ok(Value::Integer(55))));
+        assert_eq!(profiler.get_function(&f).unwrap().calls, 9);
+        // break the module <-> function cycle
+        module.set(0, Value::None);
+    }
+
+    #[test]
+    fn passes() {
+        let mut ops = vec![
+            Operation::LiteralInteger(6),
+            Operation::LiteralInteger(7),
+            Operation::Mul,
+            Operation::Neg,
+            Operation::FrameStackCopy,
+            Operation::FrameStackPop,
+            Operation::FrameLocalStore(1),
+            Operation::FrameLocalLoad(1),
+            Operation::Jump(10),
+            Operation::Add,
+            // 10
+            Operation::Jump(11),
+            Operation::LiteralReal(1.5),
+            Operation::LiteralInteger(2),
+            Operation::Add,
+            Operation::Return,
+        ];
+        optimize(&mut ops);
+        assert_eq!(ops, [
+            Operation::LiteralIntegerSmall(-42),
+            Operation::FrameStackCopy,
+            Operation::FrameLocalStore(1),
+            Operation::LiteralReal(1.5),
+            Operation::LiteralIntegerSmall(2),
+            Operation::Add,
+            Operation::Return,
+        ]);
+
+        // overflow and division by zero are left to fail at run time
+        let mut ops = vec![
+            Operation::LiteralInteger(1),
+            Operation::LiteralInteger(0),
+            Operation::Div,
+            Operation::LiteralInteger(i64::MAX),
+            Operation::LiteralInteger(1),
+            Operation::Add,
+            Operation::Return,
+        ];
+        assert!(!fold_constants(&mut ops));
+    }
+
+    #[test]
+    fn jumps_are_remapped() {
+        // sums 1..=n, with dead code and a jump chain in the way
+        let ops = [
+            Operation::LiteralInteger(0),
+            Operation::FrameLocalStore(2),
+            // loop: 2
+            Operation::FrameLocalLoad(1),
+            Operation::LiteralInteger(1),
+            Operation::Sub,
+            Operation::JumpNeg(19),
+            Operation::FrameLocalLoad(2),
+            Operation::FrameLocalLoad(1),
+            Operation::Add,
+            Operation::FrameLocalStore(2),
+            Operation::FrameLocalLoad(1),
+            Operation::LiteralInteger(1),
+            Operation::Sub,
+            Operation::FrameLocalStore(1),
+            Operation::Jump(16),
+            Operation::LiteralNone,
+            // 16
+            Operation::Jump(18),
+            Operation::Return,
+            // 18
+            Operation::Jump(2),
+            // done: 19
+            Operation::LiteralInteger(2),
+            Operation::LiteralInteger(3),
+            Operation::Mul,
+            Operation::FrameStackPop,
+            Operation::FrameLocalLoad(2),
+            Operation::Return,
+        ];
+        let mut optimized = ops.to_vec();
+        optimize(&mut optimized);
+        assert_eq!(optimized.len(), 14);
+        assert_eq!(optimized[5], Operation::JumpNeg(10));
+        assert_eq!(optimized[6..10], [
+            Operation::AddLocals(2, 1),
+            Operation::FrameLocalStore(2),
+            Operation::IncLocal(1, -1),
+            Operation::Jump(2),
+        ]);
+        assert_eq!(disassemble(&assemble(&optimized).unwrap()).unwrap(), optimized);
+        let (a, b) = both(&ops, &[Value::Integer(10)]);
+        assert_eq!((a.as_str(), b.as_str()), ("Ok(55)", "Ok(55)"));
+    }
+
+    /// Random straight line code with skipped and threaded jumps. Integer
+    /// magnitudes are tracked so no operation can overflow, which would
+    /// panic rather than fail.
+    #[test]
+    fn random_programs_agree() {
+        let mut seed: u64 = 0x9e37_79b9_7f4a_7c15;
+        let mut next = move |n: u64| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed % n
+        };
+        const LIMIT: f64 = (1u64 << 40) as f64;
+        for _ in 0..2000 {
+            let mut ops = vec![];
+            let mut stack: Vec<f64> = vec![];
+            let mut locals = [0.0; 4];
+            for _ in 0..next(25) {
+                let i = ops.len();
+                let d = stack.len();
+                match next(15) {
+                    0 | 1 => {
+                        let t = next(11) as i64 - 5;
+                        ops.push(Operation::LiteralInteger(t));
+                        stack.push(t.abs() as f64);
+                    },
+                    2 => {
+                        ops.push(Operation::LiteralReal(next(7) as f64 / 2.0 - 1.0));
+                        stack.push(2.0);
+                    },
+                    3 | 4 if d >= 2 => {
+                        let (b, a) = (stack.pop().unwrap(), stack.pop().unwrap());
+                        let choices = [
+                            (Operation::Add, a + b),
+                            (Operation::Sub, a + b),
+                            (Operation::Mul, a * b),
+                            (Operation::Div, a),
+                            (Operation::Rem, a),
+                            (Operation::And, a.max(b) * 2.0),
+                            (Operation::Or, a.max(b) * 2.0),
+                            (Operation::Xor, a.max(b) * 2.0),
+                            (Operation::Cmp, 1.0),
+                        ];
+                        let (op, bound) = choices[next(choices.len() as u64) as usize].clone();
+                        if bound >= LIMIT {
+                            stack.extend([a, b]);
+                            continue;
+                        }
+                        ops.push(op);
+                        stack.push(bound);
+                    },
+                    5 if d >= 1 => {
+                        let choices = [Operation::Neg, Operation::Not, Operation::IntToReal, Operation::RealToInt];
+                        ops.push(choices[next(4) as usize].clone());
+                        *stack.last_mut().unwrap() += 1.0;
+                    },
+                    6 if d >= 1 => {
+                        ops.extend([Operation::FrameStackCopy, Operation::FrameStackPop]);
+                    },
+                    7 if d >= 1 => {
+                        let n = next(3) as u8 + 1;
+                        locals[n as usize] = *stack.last().unwrap();
+                        ops.extend([Operation::FrameLocalStore(n), Operation::FrameLocalLoad(n)]);
+                    },
+                    8 => {
+                        let n = next(4) as u8;
+                        ops.push(Operation::FrameLocalLoad(n));
+                        stack.push(locals[n as usize]);
+                    },
+                    9 if d >= 1 => {
+                        // a conditional negation
+                        ops.extend([Operation::FrameStackCopy, Operation::JumpZero(i + 3), Operation::Neg]);
+                    },
+                    10 => {
+                        ops.extend([Operation::Jump(i + 2), Operation::Jump(i + 3), Operation::Jump(i + 1)]);
+                    },
+                    11 => {
+                        ops.extend([Operation::Jump(i + 2), Operation::LiteralNone]);
+                    },
+                    12 => {
+                        let (a, b) = (next(4) as u8, next(4) as u8);
+                        ops.extend([Operation::FrameLocalLoad(a), Operation::FrameLocalLoad(b), Operation::Add]);
+                        stack.push(locals[a as usize] + locals[b as usize]);
+                    },
+                    13 => {
+                        let n = next(3) as u8 + 1;
+                        let op = if next(2) == 0 { Operation::Add } else { Operation::Sub };
+                        let step = next(300) as i64 - 150;
+                        ops.extend([
+                            Operation::FrameLocalLoad(n),
+                            Operation::LiteralInteger(step),
+                            op,
+                            Operation::FrameLocalStore(n),
+                        ]);
+                        locals[n as usize] += 150.0;
+                    },
+                    14 if d >= 1 => {
+                        // negates values below a literal
+                        ops.extend([
+                            Operation::FrameStackCopy,
+                            Operation::LiteralInteger(next(5) as i64 - 2),
+                            Operation::Cmp,
+                            Operation::JumpNeg(i + 5),
+                            Operation::Neg,
+                        ]);
+                    },
+                    _ => (),
+                }
+            }
+            if stack.is_empty() {
+                ops.push(Operation::LiteralInteger(1));
+            }
+            ops.push(Operation::Return);
+            let (a, b) = both(&ops, &[Value::Integer(3)]);
+            assert_eq!(a, b, "{:?}", ops);
+        }
+    }
+}
@@ -0,0 +1,318 @@
+use std::convert::{TryFrom, TryInto};
+
+use crate::convert::ConvertError;
+use crate::datamodel::Value;
+use crate::VmError;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NumKind {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    I64,
+    /// Read as the `Integer` with the same bits, so values above `i64::MAX`
+    /// come out negative.
+    U64,
+    F32,
+    F64,
+    /// LEB128 varints, which ignore the byte order.
+    Uleb128,
+    Sleb128,
+}
+
+const KINDS: [NumKind; 12] = [
+    NumKind::I8, NumKind::U8, NumKind::I16, NumKind::U16, NumKind::I32, NumKind::U32,
+    NumKind::I64, NumKind::U64, NumKind::F32, NumKind::F64, NumKind::Uleb128, NumKind::Sleb128,
+];
+
+const BIG_ENDIAN: u8 = 0x80;
+
+/// Layout of a number in a byte sequence, the immediate of `BYTES_READ` and
+/// `BYTES_WRITE`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Packing {
+    pub kind: NumKind,
+    pub big_endian: bool,
+}
+
+macro_rules! read_num {
+    ($t:ty, $bytes:expr, $be:expr) => {
+        {
+            let t = $bytes.try_into().unwrap();
+            if $be { <$t>::from_be_bytes(t) } else { <$t>::from_le_bytes(t) }
+        }
+    };
+}
+
+macro_rules! write_int {
+    ($t:ty, $value:expr, $be:expr) => {
+        {
+            let t = <$t>::try_from($value).map_err(|_| ConvertError::Range($value).at(0))?;
+            if $be { t.to_be_bytes().to_vec() } else { t.to_le_bytes().to_vec() }
+        }
+    };
+}
+
+impl Packing {
+    pub fn new(kind: NumKind, big_endian: bool) -> Packing {
+        Packing { kind, big_endian }
+    }
+
+    pub fn from_u8(t: u8) -> Option<Packing> {
+        let kind = *KINDS.get((t & !BIG_ENDIAN) as usize)?;
+        Some(Packing { kind, big_endian: t & BIG_ENDIAN != 0 })
+    }
+
+    pub fn to_u8(self) -> u8 {
+        let kind = KINDS.iter().position(|&k| k == self.kind).unwrap() as u8;
+        if self.big_endian { kind | BIG_ENDIAN } else { kind }
+    }
+
+    /// Size in bytes, or `None` for varints.
+    pub fn width(self) -> Option<usize> {
+        match self.kind {
+            NumKind::I8 | NumKind::U8 => Some(1),
+            NumKind::I16 | NumKind::U16 => Some(2),
+            NumKind::I32 | NumKind::U32 | NumKind::F32 => Some(4),
+            NumKind::I64 | NumKind::U64 | NumKind::F64 => Some(8),
+            NumKind::Uleb128 | NumKind::Sleb128 => None,
+        }
+    }
+
+    /// Reads the number at `offset` and returns it with the offset just past
+    /// it.
+    pub fn read(self, bytes: &[u8], offset: i64) -> Result<(Value, usize), VmError> {
+        if offset < 0 || offset as usize > bytes.len() {
+            return Err(VmError::IndexRead(offset));
+        }
+        let start = offset as usize;
+        let width = match self.width() {
+            Some(t) => t,
+            None => return read_leb128(bytes, start, self.kind == NumKind::Sleb128),
+        };
+        let t = bytes.get(start..start + width).ok_or(VmError::IndexRead(offset))?;
+        let be = self.big_endian;
+        let out = match self.kind {
+            NumKind::I8 => Value::Integer(t[0] as i8 as i64),
+            NumKind::U8 => Value::Integer(t[0] as i64),
+            NumKind::I16 => Value::Integer(read_num!(i16, t, be) as i64),
+            NumKind::U16 => Value::Integer(read_num!(u16, t, be) as i64),
+            NumKind::I32 => Value::Integer(read_num!(i32, t, be) as i64),
+            NumKind::U32 => Value::Integer(read_num!(u32, t, be) as i64),
+            NumKind::I64 => Value::Integer(read_num!(i64, t, be)),
+            NumKind::U64 => Value::Integer(read_num!(u64, t, be) as i64),
+            NumKind::F32 => Value::Real(read_num!(f32, t, be) as f64),
+            NumKind::F64 => Value::Real(read_num!(f64, t, be)),
+            NumKind::Uleb128 | NumKind::Sleb128 => unreachable!(),
+        };
+        Ok((out, start + width))
+    }
+
+    /// Writes `value` at `offset`, growing `bytes` if it runs past the end,
+    /// and returns the offset just past it. Integers that do not fit are a
+    /// range error rather than being truncated.
+    pub fn write(self, bytes: &mut Vec<u8>, offset: i64, value: &Value) -> Result<usize, VmError> {
+        if offset < 0 || offset as usize > bytes.len() {
+            return Err(VmError::IndexWrite(offset));
+        }
+        let be = self.big_endian;
+        let encoded = match (self.kind, value) {
+            (NumKind::I8, Value::Integer(v)) => write_int!(i8, *v, be),
+            (NumKind::U8, Value::Integer(v)) => write_int!(u8, *v, be),
+            (NumKind::I16, Value::Integer(v)) => write_int!(i16, *v, be),
+            (NumKind::U16, Value::Integer(v)) => write_int!(u16, *v, be),
+            (NumKind::I32, Value::Integer(v)) => write_int!(i32, *v, be),
+            (NumKind::U32, Value::Integer(v)) => write_int!(u32, *v, be),
+            (NumKind::I64, Value::Integer(v)) => write_int!(i64, *v, be),
+            (NumKind::U64, Value::Integer(v)) => write_int!(u64, *v, be),
+            (NumKind::Uleb128, Value::Integer(v)) => {
+                let v = u64::try_from(*v).map_err(|_| ConvertError::Range(*v).at(0))?;
+                write_uleb128(v)
+            },
+            (NumKind::Sleb128, Value::Integer(v)) => write_sleb128(*v),
+            (NumKind::F32, Value::Real(r)) => {
+                let r = *r as f32;
+                if be { r.to_be_bytes().to_vec() } else { r.to_le_bytes().to_vec() }
+            },
+            (NumKind::F64, Value::Real(r)) => {
+                if be { r.to_be_bytes().to_vec() } else { r.to_le_bytes().to_vec() }
+            },
+            (_, e) => return Err(VmError::Type(e.get_type(), 0)),
+        };
+        let start = offset as usize;
+        let end = start + encoded.len();
+        if end > bytes.len() {
+            bytes.resize(end, 0);
+        }
+        bytes[start..end].copy_from_slice(&encoded);
+        Ok(end)
+    }
+}
+
+fn read_leb128(bytes: &[u8], start: usize, signed: bool) -> Result<(Value, usize), VmError> {
+    let mut out: u64 = 0;
+    let mut shift = 0;
+    let mut pos = start;
+    loop {
+        let b = *bytes.get(pos).ok_or(VmError::IndexRead(pos as i64))?;
+        // the tenth byte only has room for the top bit; signed, it is all
+        // sign and must flip bit 62, or the nine bytes before already held
+        // the value
+        if shift == 63 {
+            let ok = match signed {
+                false => b <= 1,
+                true if out >> 62 & 1 == 0 => b == 0x7f,
+                true => b == 0,
+            };
+            if !ok {
+                return Err(VmError::Decode(pos));
+            }
+        }
+        out |= ((b & 0x7f) as u64) << shift;
+        shift += 7;
+        pos += 1;
+        if b & 0x80 == 0 {
+            if signed && shift < 64 && b & 0x40 != 0 {
+                out |= !0 << shift;
+            }
+            return Ok((Value::Integer(out as i64), pos));
+        }
+    }
+}
+
+pub(crate) fn write_uleb128(mut v: u64) -> Vec<u8> {
+    let mut out = vec![];
+    loop {
+        let b = (v & 0x7f) as u8;
+        v >>= 7;
+        if v == 0 {
+            out.push(b);
+            return out;
+        }
+        out.push(b | 0x80);
+    }
+}
+
+pub(crate) fn write_sleb128(mut v: i64) -> Vec<u8> {
+    let mut out = vec![];
+    loop {
+        let b = (v & 0x7f) as u8;
+        v >>= 7;
+        if (v == 0 && b & 0x40 == 0) || (v == -1 && b & 0x40 != 0) {
+            out.push(b);
+            return out;
+        }
+        out.push(b | 0x80);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{NumKind, Packing};
+    use crate::convert::ConvertError;
+    use crate::datamodel::{BytesBuffer, List, Value};
+    use crate::machine::Vm;
+    use crate::operation::{assemble, decode, Operation};
+    use crate::test_util::function;
+    use crate::VmError;
+
+    fn roundtrip(p: Packing, v: Value) -> (Vec<u8>, Value) {
+        let mut bytes = vec![0xaa];
+        let end = p.write(&mut bytes, 1, &v).unwrap();
+        assert_eq!(end, bytes.len());
+        let (out, next) = p.read(&bytes, 1).unwrap();
+        assert_eq!(next, end);
+        (bytes[1..].to_vec(), out)
+    }
+
+    #[test]
+    fn fixed_width() {
+        let (b, v) = roundtrip(Packing::new(NumKind::I16, true), Value::Integer(-2));
+        assert_eq!((b, v.to_string()), (vec![0xff, 0xfe], "-2".to_string()));
+        let (b, v) = roundtrip(Packing::new(NumKind::U32, false), Value::Integer(0x01020304));
+        assert_eq!((b, v.to_string()), (vec![4, 3, 2, 1], "16909060".to_string()));
+        let (b, v) = roundtrip(Packing::new(NumKind::F32, true), Value::Real(1.5));
+        assert_eq!((b, v.to_string()), (vec![0x3f, 0xc0, 0, 0], "1.5".to_string()));
+        let (_, v) = roundtrip(Packing::new(NumKind::F64, false), Value::Real(-0.1));
+        assert_eq!(v.to_string(), "-0.1");
+
+        let p = Packing::new(NumKind::U8, false);
+        assert!(matches!(p.write(&mut vec![], 0, &Value::Integer(256)),
+            Err(VmError::Convert(ConvertError::Range(256), 0))));
+        assert!(matches!(p.write(&mut vec![], 1, &Value::Integer(1)), Err(VmError::IndexWrite(1))));
+        assert!(matches!(Packing::new(NumKind::I32, false).read(&[0; 3], 0), Err(VmError::IndexRead(0))));
+        assert_eq!(Packing::from_u8(Packing::new(NumKind::U64, true).to_u8()),
+            Some(Packing::new(NumKind::U64, true)));
+    }
+
+    #[test]
+    fn leb128() {
+        let u = Packing::new(NumKind::Uleb128, false);
+        let s = Packing::new(NumKind::Sleb128, false);
+        assert_eq!(roundtrip(u, Value::Integer(624485)).0, [0xe5, 0x8e, 0x26]);
+        assert_eq!(roundtrip(s, Value::Integer(-123456)).0, [0xc0, 0xbb, 0x78]);
+        for v in [0, 63, 64, -64, -65, 1 << 62, -(1 << 62) - 1, i64::MAX, i64::MIN].iter() {
+            assert_eq!(roundtrip(s, Value::Integer(*v)).1.to_string(), v.to_string());
+        }
+        assert_eq!(roundtrip(u, Value::Integer(i64::MAX)).1.to_string(), i64::MAX.to_string());
+        assert!(matches!(u.write(&mut vec![], 0, &Value::Integer(-1)), Err(VmError::Convert(..))));
+        assert!(matches!(u.read(&[0x80, 0x80], 0), Err(VmError::IndexRead(2))));
+        assert!(matches!(u.read(&[0xff; 11], 0), Err(VmError::Decode(9))));
+        // overflow past i64, and a tenth byte that only repeats the sign
+        let mut bytes = [0xff; 10];
+        for &(last, ok) in [(0x7f, false), (0x01, false), (0x00, true)].iter() {
+            bytes[9] = last;
+            assert_eq!(matches!(s.read(&bytes, 0), Err(VmError::Decode(9))), !ok, "{:#x}", last);
+        }
+        bytes[8] = 0xbf;
+        for &(last, ok) in [(0x00, false), (0x7e, false), (0x7f, true)].iter() {
+            bytes[9] = last;
+            assert_eq!(matches!(s.read(&bytes, 0), Err(VmError::Decode(9))), !ok, "{:#x}", last);
+        }
+    }
+
+    #[test]
+    fn pack_opcodes() {
+        // writes a u16 length prefix and an sleb128 into a new buffer, then
+        // reads the varint back from offset 2
+        let ops = [
+            Operation::BytesBufferCreate,
+            Operation::FrameLocalStore(2),
+            Operation::FrameLocalLoad(2),
+            Operation::LiteralInteger(0),
+            Operation::LiteralInteger(258),
+            Operation::BytesWrite(Packing::new(NumKind::U16, true)),
+            Operation::FrameLocalStore(3),
+            Operation::FrameLocalLoad(2),
+            Operation::FrameLocalLoad(3),
+            Operation::FrameLocalLoad(1),
+            Operation::BytesWrite(Packing::new(NumKind::Sleb128, false)),
+            Operation::FrameStackPop,
+            Operation::FrameLocalLoad(2),
+            Operation::LiteralInteger(2),
+            Operation::BytesRead(Packing::new(NumKind::Sleb128, false)),
+            Operation::FrameStackPop,
+            Operation::Return,
+        ];
+        let bytecode = assemble(&ops).unwrap();
+        assert_eq!(decode(&bytecode, bytecode.len() - 4), Some((ops[14].clone(), bytecode.len() - 2)));
+        let f = Value::Function(function(List::from_vec(vec![]), &ops));
+        let out = Vm::new().call(&f, &[Value::Integer(-300)]).unwrap();
+        assert_eq!(out.to_string(), "-300");
+
+        let buffer = BytesBuffer::from_vec(vec![0, 0]);
+        let f = Value::Function(function(List::from_vec(vec![]), &[
+            Operation::FrameLocalLoad(1),
+            Operation::LiteralInteger(0),
+            Operation::LiteralInteger(300),
+            Operation::SeqSet,
+            Operation::LiteralNone,
+            Operation::Return,
+        ]));
+        assert!(matches!(Vm::new().call(&f, &[Value::BytesBuffer(buffer)]),
+            Err(VmError::Convert(ConvertError::Range(300), 0))));
+    }
+}
@@ -0,0 +1,333 @@
+use std::collections::BTreeMap;
+use std::mem;
+use std::rc::Rc;
+
+use crate::datamodel::{Function, Value};
+use crate::machine::{CallStack, Vm};
+use crate::operation::TAIL_CALL;
+use crate::VmError;
+
+pub struct FunctionProfile {
+    pub function: Rc<Function>,
+    /// Number of frames entered for this function.
+    pub calls: u64,
+    /// Instructions executed while the function was anywhere on the stack,
+    /// counting recursive frames once. Added when its outermost frame
+    /// returns.
+    pub inclusive: u64,
+    /// Instructions executed by the function's own frames.
+    pub exclusive: u64,
+    /// Instructions executed per bytecode offset.
+    pub offsets: BTreeMap<usize, u64>,
+    // frames on the stack, and the instruction count when the first entered
+    active: u32,
+    entered: u64,
+}
+
+/// Counts executed instructions while driving a `CallStack`, including the
+/// stacks that natives call back into.
+///
+/// Everything is counted in instructions rather than wall time, so a
+/// profile of the same program is always identical.
+pub struct Profiler {
+    interval: u64,
+    executed: u64,
+    opcodes: Vec<u64>,
+    functions: Vec<FunctionProfile>,
+    stack: Vec<usize>,
+    samples: BTreeMap<String, u64>,
+}
+
+impl Default for Profiler {
+    fn default() -> Profiler {
+        Profiler::new(1)
+    }
+}
+
+impl Profiler {
+    /// Records a stack sample every `interval` instructions, in addition to
+    /// the exact per-instruction counts.
+    pub fn new(interval: u64) -> Profiler {
+        Profiler {
+            interval: interval.max(1),
+            executed: 0,
+            opcodes: vec![0; 256],
+            functions: vec![],
+            stack: vec![],
+            samples: BTreeMap::new(),
+        }
+    }
+
+    pub fn get_executed(&self) -> u64 {
+        self.executed
+    }
+
+    pub fn get_opcode_count(&self, op_code: u8) -> u64 {
+        self.opcodes[op_code as usize]
+    }
+
+    /// Profiles in the order the functions were first entered.
+    pub fn get_functions(&self) -> &[FunctionProfile] {
+        &self.functions
+    }
+
+    pub fn get_function(&self, function: &Rc<Function>) -> Option<&FunctionProfile> {
+        self.functions.iter().find(|p| Rc::ptr_eq(&p.function, function))
+    }
+
+    /// Stack samples in the folded format read by flamegraph tools, one
+    /// `outer;inner count` line per distinct stack, sorted.
+    pub fn to_folded(&self) -> String {
+        let mut out = String::new();
+        for (stack, count) in self.samples.iter() {
+            out.push_str(&format!("{} {}\n", stack, count));
+        }
+        out
+    }
+
+    pub fn run(&mut self, vm: &mut Vm, stack: &mut CallStack) -> Result<Value, VmError> {
+        self.leave_frames(0);
+        self.enter_frames(stack, true);
+        // the VM profiles every stack it runs while it holds the profiler
+        let outer = vm.replace_profiler(Some(Box::new(mem::take(self))));
+        let out = stack.run(vm);
+        *self = *vm.replace_profiler(outer).unwrap();
+        self.leave_frames(0);
+        out
+    }
+
+    /// Enters the frames of a stack that was not profiled yet, counting them
+    /// as calls if `fresh`. Returns the depth to leave back to.
+    pub(crate) fn enter_frames(&mut self, stack: &CallStack, fresh: bool) -> usize {
+        let base = self.stack.len();
+        for frame in stack.get_frames() {
+            let i = self.index_of(frame.get_function());
+            if fresh {
+                self.functions[i].calls += 1;
+            }
+            self.enter(i);
+        }
+        base
+    }
+
+    pub(crate) fn leave_frames(&mut self, base: usize) {
+        while self.stack.len() > base {
+            self.leave();
+        }
+    }
+
+    /// Counts the instruction `stack` is about to execute, returning its
+    /// op code.
+    pub(crate) fn before(&mut self, stack: &CallStack) -> Result<Option<u8>, VmError> {
+        let frame = stack.get_frames().last().ok_or(VmError::StackEmpty)?;
+        let cursor = frame.get_cursor();
+        let op_code = frame.get_bytecode().get(cursor).copied();
+        if let Some(op_code) = op_code {
+            self.record(op_code, cursor);
+        }
+        Ok(op_code)
+    }
+
+    /// Follows the calls and returns of an instruction that started at
+    /// `depth`.
+    pub(crate) fn after(&mut self, stack: &CallStack, depth: usize, op_code: Option<u8>) {
+        if stack.depth() > depth {
+            self.enter_call(stack);
+        } else if stack.depth() < depth {
+            self.leave();
+        } else if op_code == Some(TAIL_CALL) {
+            // the frame was replaced by the callee's
+            self.leave();
+            self.enter_call(stack);
+        }
+    }
+
+    fn enter_call(&mut self, stack: &CallStack) {
+        let frame = stack.get_frames().last().unwrap();
+        let i = self.index_of(frame.get_function());
+        self.functions[i].calls += 1;
+        self.enter(i);
+    }
+
+    fn enter(&mut self, i: usize) {
+        let profile = &mut self.functions[i];
+        if profile.active == 0 {
+            profile.entered = self.executed;
+        }
+        profile.active += 1;
+        self.stack.push(i);
+    }
+
+    fn leave(&mut self) {
+        if let Some(i) = self.stack.pop() {
+            let profile = &mut self.functions[i];
+            profile.active -= 1;
+            if profile.active == 0 {
+                profile.inclusive += self.executed - profile.entered;
+            }
+        }
+    }
+
+    fn index_of(&mut self, function: &Rc<Function>) -> usize {
+        match self.functions.iter().position(|p| Rc::ptr_eq(&p.function, function)) {
+            Some(i) => i,
+            None => {
+                self.functions.push(FunctionProfile {
+                    function: function.clone(),
+                    calls: 0,
+                    inclusive: 0,
+                    exclusive: 0,
+                    offsets: BTreeMap::new(),
+                    active: 0,
+                    entered: 0,
+                });
+                self.functions.len() - 1
+            },
+        }
+    }
+
+    fn record(&mut self, op_code: u8, cursor: usize) {
+        self.executed += 1;
+        self.opcodes[op_code as usize] += 1;
+        let current = *self.stack.last().unwrap();
+        let profile = &mut self.functions[current];
+        profile.exclusive += 1;
+        *profile.offsets.entry(cursor).or_insert(0) += 1;
+        if self.executed.is_multiple_of(self.interval) {
+            let names: Vec<_> = self.stack.iter().map(|&i| self.name_of(i)).collect();
+            *self.samples.entry(names.join(";")).or_insert(0) += 1;
+        }
+    }
+
+    fn name_of(&self, i: usize) -> String {
+        match &self.functions[i].function.name {
+            Some(name) => name.clone(),
+            None => format!("<fn {}>", i),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::Profiler;
+    use crate::datamodel::{Bytes, Function, List, NativeFunction, Value};
+    use crate::machine::{CallStack, Vm};
+    use crate::operation::{assemble, Operation, CALL, LIT_INT};
+
+    fn function(name: &str, module: &List, ops: &[Operation]) -> Rc<Function> {
+        let bytecode = Bytes::from_vec(assemble(ops).unwrap());
+        let mut f = Function::new(module.clone(), bytecode);
+        f.name = Some(name.to_string());
+        Rc::new(f)
+    }
+
+    #[test]
+    fn counts_calls() {
+        let module = List::from_vec(vec![]);
+        let double = function("double", &module, &[
+            Operation::FrameLocalLoad(1),
+            Operation::LiteralInteger(2),
+            Operation::Mul,
+            Operation::Return,
+        ]);
+        module.push(Value::Function(double.clone()));
+        let main = function("main", &module, &[
+            Operation::LiteralInteger(1),
+            Operation::FrameLocalLoad(0),
+            Operation::LiteralInteger(0),
+            Operation::SeqGet,
+            Operation::Call(1),
+            Operation::FrameLocalLoad(0),
+            Operation::LiteralInteger(0),
+            Operation::SeqGet,
+            Operation::Call(1),
+            Operation::Return,
+        ]);
+        let mut profiler = Profiler::default();
+        let out = profiler.run(&mut Vm::new(), &mut CallStack::new(main.clone(), vec![])).unwrap();
+        assert!(matches!(out, Value::Integer(4)));
+        assert_eq!(profiler.get_executed(), 18);
+        assert_eq!(profiler.get_opcode_count(LIT_INT), 5);
+        assert_eq!(profiler.get_opcode_count(CALL), 2);
+
+        let p = profiler.get_function(&main).unwrap();
+        assert_eq!((p.calls, p.inclusive, p.exclusive), (1, 18, 10));
+        let p = profiler.get_function(&double).unwrap();
+        assert_eq!((p.calls, p.inclusive, p.exclusive), (2, 8, 8));
+        assert_eq!(p.offsets.get(&0), Some(&2));
+        assert_eq!(profiler.to_folded(), "main 10\nmain;double 8\n");
+    }
+
+    #[test]
+    fn recursion_counted_once() {
+        let module = List::from_vec(vec![]);
+        let countdown = function("countdown", &module, &[
+            Operation::FrameLocalLoad(1),
+            Operation::JumpZero(10),
+            Operation::FrameLocalLoad(1),
+            Operation::LiteralInteger(1),
+            Operation::Sub,
+            Operation::FrameLocalLoad(0),
+            Operation::LiteralInteger(0),
+            Operation::SeqGet,
+            Operation::Call(1),
+            Operation::Return,
+            Operation::LiteralInteger(0),
+            Operation::Return,
+        ]);
+        module.push(Value::Function(countdown.clone()));
+        let mut profiler = Profiler::default();
+        let mut stack = CallStack::new(countdown.clone(), vec![Value::Integer(2)]);
+        profiler.run(&mut Vm::new(), &mut stack).unwrap();
+        let p = profiler.get_function(&countdown).unwrap();
+        assert_eq!((p.calls, p.inclusive, p.exclusive), (3, 24, 24));
+        assert_eq!(profiler.to_folded(), "\
+countdown 10
+countdown;countdown 10
+countdown;countdown;countdown 4
+");
+    }
+
+    #[test]
+    fn native_callbacks() {
+        let twice = NativeFunction::new(|ctx, args| {
+            let once = ctx.call(&args[0], &args[1..])?;
+            ctx.call(&args[0], &[once])
+        });
+        let module = List::from_vec(vec![Value::NativeFunction(twice)]);
+        let double = function("double", &module, &[
+            Operation::FrameLocalLoad(1),
+            Operation::LiteralInteger(2),
+            Operation::Mul,
+            Operation::Return,
+        ]);
+        module.push(Value::Function(double.clone()));
+        let main = function("main", &module, &[
+            Operation::FrameLocalLoad(0),
+            Operation::LiteralInteger(1),
+            Operation::SeqGet,
+            Operation::LiteralInteger(5),
+            Operation::FrameLocalLoad(0),
+            Operation::LiteralInteger(0),
+            Operation::SeqGet,
+            Operation::Call(2),
+            Operation::Return,
+        ]);
+        let mut profiler = Profiler::default();
+        let mut vm = Vm::new();
+        let out = profiler.run(&mut vm, &mut CallStack::new(main.clone(), vec![])).unwrap();
+        assert!(matches!(out, Value::Integer(20)));
+        assert_eq!(profiler.get_executed(), 17);
+        let p = profiler.get_function(&main).unwrap();
+        assert_eq!((p.calls, p.inclusive, p.exclusive), (1, 17, 9));
+        let p = profiler.get_function(&double).unwrap();
+        assert_eq!((p.calls, p.inclusive, p.exclusive), (2, 8, 8));
+        assert_eq!(profiler.to_folded(), "main 9\nmain;double 8\n");
+
+        // the VM stops profiling once the run is over
+        vm.call(&Value::Function(main), &[]).unwrap();
+        assert_eq!(profiler.get_executed(), 17);
+    }
+}
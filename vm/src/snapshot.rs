@@ -0,0 +1,815 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::rc::{Rc, Weak};
+
+use crate::coroutine::Coroutine;
+use crate::datamodel::{
+    Bytes, BytesBuffer, Function, Iter, List, ListWeak, Range, StringBuffer, StringValue, Value,
+    ValueType,
+};
+use crate::machine::{CallFrame, CallStack};
+use crate::pack::{write_sleb128, write_uleb128, NumKind, Packing};
+use crate::VmError;
+
+const MAGIC: &[u8] = b"GLSN";
+/// Header of values encoded with `Value::encode`.
+const DATA_MAGIC: &[u8] = b"GLVL";
+const VERSION: u8 = 1;
+
+/// Tag of a value already written, followed by its object id.
+const BACK_REF: u8 = 0xff;
+
+/// Values nested deeper than this fail to read with `VmError::Decode`,
+/// since the reader recurses on the host stack. Lists and coroutines are
+/// filled in after the value that holds them, so only weak lists, iterators
+/// and functions nest, and well-formed input stays far below it.
+pub const MAX_DEPTH: usize = 64;
+
+/// Serializes a `CallStack` between steps, with every frame and all values
+/// reachable from it, into bytes that `restore` turns back into a stack
+/// continuing where this one stood.
+///
+/// Lists, buffers, strings, iterators, coroutines and functions are written
+/// once and referenced after that, so sharing and cycles survive. A weak list
+/// is restored pointing at its target if the target is alive; a target only
+/// reachable through weak lists is dropped once restored, as it would be
+/// here once the host released it.
+///
+/// Native functions and host objects cannot be serialized and are written
+/// as their index in `natives`, which `restore` must be given again; those
+/// missing from it fail with `VmError::Serialize`. Host data set on the `Vm`
+/// is not part of the snapshot.
+pub fn save(stack: &CallStack, natives: &[Value]) -> Result<Vec<u8>, VmError> {
+    let mut out = Writer::new(MAGIC, Some(natives));
+    out.call_stack(stack)?;
+    out.finish()?;
+    Ok(out.out)
+}
+
+/// Rebuilds a stack saved by `save`, to be run on any `Vm`. Malformed input
+/// fails with `VmError::Decode` at the offset where reading stopped.
+pub fn restore(bytes: &[u8], natives: &[Value]) -> Result<CallStack, VmError> {
+    let mut input = Reader::new(bytes, MAGIC, Some(natives))?;
+    let stack = input.call_stack()?;
+    input.finish()?;
+    Ok(stack)
+}
+
+impl Value {
+    /// Serializes plain data: everything but functions, coroutines, natives
+    /// and host objects, which fail with `VmError::Serialize`. The bytes
+    /// start with a header and tag every value with its type, and shared
+    /// lists, buffers and strings are written once, so sharing and cycles
+    /// survive `decode`. Reals are stored as their bits, so NaN and signed
+    /// zero round trip too.
+    pub fn encode(&self) -> Result<Vec<u8>, VmError> {
+        let mut out = Writer::new(DATA_MAGIC, None);
+        out.value(self)?;
+        out.finish()?;
+        Ok(out.out)
+    }
+
+    /// Reads bytes from `encode`. Malformed input fails with
+    /// `VmError::Decode` at the offset where reading stopped.
+    pub fn decode(bytes: &[u8]) -> Result<Value, VmError> {
+        let mut input = Reader::new(bytes, DATA_MAGIC, None)?;
+        let out = input.value()?;
+        input.finish()?;
+        Ok(out)
+    }
+}
+
+/// Mutable containers are written empty at their first reference and
+/// filled in afterwards, so any reference to them, cyclic or not, resolves
+/// to an object that already exists when reading.
+enum Shell {
+    List(List),
+    Coroutine(Coroutine),
+}
+
+struct Writer<'a> {
+    out: Vec<u8>,
+    /// Keyed by type as well as address, since a string and bytes value can
+    /// share their allocation.
+    ids: HashMap<(u8, usize), usize>,
+    pending: VecDeque<Shell>,
+    /// `None` when encoding plain data, which rejects code.
+    natives: Option<&'a [Value]>,
+}
+
+impl<'a> Writer<'a> {
+    fn new(magic: &[u8], natives: Option<&'a [Value]>) -> Writer<'a> {
+        let mut out = magic.to_vec();
+        out.push(VERSION);
+        Writer { out, ids: HashMap::new(), pending: VecDeque::new(), natives }
+    }
+
+    fn uint(&mut self, v: usize) {
+        self.out.extend(write_uleb128(v as u64));
+    }
+
+    fn int(&mut self, v: i64) {
+        self.out.extend(write_sleb128(v));
+    }
+
+    fn raw(&mut self, t: &[u8]) {
+        self.uint(t.len());
+        self.out.extend_from_slice(t);
+    }
+
+    /// Writes a back reference and returns false if the object at `ptr` was
+    /// seen before, otherwise gives it the next id and writes its tag.
+    fn object<T: ?Sized>(&mut self, t: ValueType, ptr: *const T) -> bool {
+        let next = self.ids.len();
+        match *self.ids.entry((t as u8, ptr as *const () as usize)).or_insert(next) {
+            id if id != next => {
+                self.out.push(BACK_REF);
+                self.uint(id);
+                false
+            },
+            _ => {
+                self.out.push(t as u8);
+                true
+            },
+        }
+    }
+
+    fn value(&mut self, value: &Value) -> Result<(), VmError> {
+        let t = value.get_type();
+        let code = matches!(t, ValueType::Coroutine | ValueType::Function);
+        if code && self.natives.is_none() {
+            return Err(VmError::Serialize(t));
+        }
+        match value {
+            Value::None => self.out.push(t as u8),
+            Value::Bool(b) => {
+                self.out.push(t as u8);
+                self.out.push(*b as u8);
+            },
+            Value::Integer(i) => {
+                self.out.push(t as u8);
+                self.int(*i);
+            },
+            Value::Real(r) => {
+                self.out.push(t as u8);
+                self.out.extend_from_slice(&r.to_le_bytes());
+            },
+            Value::Char(c) => {
+                self.out.push(t as u8);
+                self.uint(*c as usize);
+            },
+            Value::List(l) => if self.object(t, Rc::as_ptr(&l.0)) {
+                self.pending.push_back(Shell::List(l.clone()));
+            },
+            Value::ListWeak(w) => {
+                self.out.push(t as u8);
+                match w.upgrade() {
+                    Some(l) => {
+                        self.out.push(1);
+                        self.value(&Value::List(l))?;
+                    },
+                    None => self.out.push(0),
+                }
+            },
+            Value::Bytes(b) => if self.object(t, Rc::as_ptr(&b.0)) {
+                self.raw(&b.0);
+            },
+            Value::BytesBuffer(b) => if self.object(t, Rc::as_ptr(&b.0)) {
+                self.raw(&b.0.borrow());
+            },
+            Value::StringValue(s) => if self.object(t, Rc::as_ptr(&s.as_bytes().0)) {
+                self.raw(s.as_str().as_bytes());
+            },
+            Value::StringBuffer(s) => if self.object(t, Rc::as_ptr(&s.0)) {
+                self.raw(s.0.borrow().as_bytes());
+            },
+            Value::Range(r) => {
+                self.out.push(t as u8);
+                self.int(r.start);
+                self.int(r.end);
+                self.int(r.step);
+            },
+            Value::Iter(i) => if self.object(t, Rc::as_ptr(&i.0)) {
+                let (source, position) = i.get_state();
+                self.value(&source)?;
+                self.uint(position);
+            },
+            Value::Coroutine(co) => if self.object(t, Rc::as_ptr(&co.0)) {
+                self.out.push(co.is_started() as u8);
+                self.pending.push_back(Shell::Coroutine(co.clone()));
+            },
+            Value::Function(f) => if self.object(t, Rc::as_ptr(f)) {
+                self.function(f)?;
+            },
+            Value::NativeFn(_) | Value::NativeFunction(_) | Value::Unknown(_) => {
+                let index = self.natives.unwrap_or_default().iter()
+                    .position(|n| n.get_type() == t && n.cmp(value) == Some(Ordering::Equal))
+                    .ok_or(VmError::Serialize(t))?;
+                self.out.push(t as u8);
+                self.uint(index);
+            },
+        }
+        Ok(())
+    }
+
+    fn function(&mut self, f: &Function) -> Result<(), VmError> {
+        match &f.name {
+            Some(name) => {
+                self.out.push(1);
+                self.raw(name.as_bytes());
+            },
+            None => self.out.push(0),
+        }
+        self.uint(f.lines.len());
+        for &(offset, line) in &f.lines {
+            self.uint(offset);
+            self.uint(line as usize);
+        }
+        self.value(&Value::List(f.module.clone()))?;
+        self.value(&Value::Bytes(f.bytecode.clone()))
+    }
+
+    fn values(&mut self, values: &[Value]) -> Result<(), VmError> {
+        self.uint(values.len());
+        values.iter().try_for_each(|t| self.value(t))
+    }
+
+    fn call_stack(&mut self, stack: &CallStack) -> Result<(), VmError> {
+        self.out.push(stack.is_coroutine() as u8);
+        self.uint(stack.depth());
+        for frame in stack.get_frames() {
+            self.value(&Value::Function(frame.get_function().clone()))?;
+            self.uint(frame.get_cursor());
+            self.values(frame.get_stack())?;
+            self.values(frame.get_locals())?;
+        }
+        match stack.get_yielded() {
+            Some(t) => {
+                self.out.push(1);
+                self.value(t)
+            },
+            None => {
+                self.out.push(0);
+                Ok(())
+            },
+        }
+    }
+
+    fn finish(&mut self) -> Result<(), VmError> {
+        while let Some(shell) = self.pending.pop_front() {
+            match shell {
+                Shell::List(l) => self.values(&l.0.borrow())?,
+                Shell::Coroutine(co) => match &*co.get_stack() {
+                    Some(stack) => {
+                        self.out.push(1);
+                        self.call_stack(stack)?;
+                    },
+                    None => self.out.push(0),
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    /// Indexed by object id, in the order the writer assigned them.
+    objects: Vec<Value>,
+    pending: VecDeque<Shell>,
+    natives: Option<&'a [Value]>,
+    /// Calls to `value` in progress.
+    depth: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8], magic: &[u8], natives: Option<&'a [Value]>) -> Result<Reader<'a>, VmError> {
+        if bytes.get(..magic.len()) != Some(magic) || bytes.get(magic.len()) != Some(&VERSION) {
+            return Err(VmError::Decode(0));
+        }
+        Ok(Reader {
+            bytes,
+            pos: magic.len() + 1,
+            objects: vec![],
+            pending: VecDeque::new(),
+            natives,
+            depth: 0,
+        })
+    }
+
+    fn error(&self) -> VmError {
+        VmError::Decode(self.pos)
+    }
+
+    fn byte(&mut self) -> Result<u8, VmError> {
+        let b = *self.bytes.get(self.pos).ok_or_else(|| self.error())?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn flag(&mut self) -> Result<bool, VmError> {
+        match self.byte()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(VmError::Decode(self.pos - 1)),
+        }
+    }
+
+    fn int(&mut self) -> Result<i64, VmError> {
+        match Packing::new(NumKind::Sleb128, false).read(self.bytes, self.pos as i64) {
+            Ok((Value::Integer(i), next)) => {
+                self.pos = next;
+                Ok(i)
+            },
+            _ => Err(self.error()),
+        }
+    }
+
+    fn uint(&mut self) -> Result<usize, VmError> {
+        let start = self.pos;
+        match Packing::new(NumKind::Uleb128, false).read(self.bytes, start as i64) {
+            Ok((Value::Integer(i), next)) => {
+                self.pos = next;
+                usize::try_from(i).map_err(|_| VmError::Decode(start))
+            },
+            _ => Err(self.error()),
+        }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], VmError> {
+        let end = self.pos.checked_add(len).filter(|&t| t <= self.bytes.len());
+        let t = &self.bytes[self.pos..end.ok_or_else(|| self.error())?];
+        self.pos += len;
+        Ok(t)
+    }
+
+    fn raw(&mut self) -> Result<&'a [u8], VmError> {
+        let len = self.uint()?;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> Result<String, VmError> {
+        let start = self.pos;
+        let t = self.raw()?;
+        String::from_utf8(t.to_vec()).map_err(|_| VmError::Decode(start))
+    }
+
+    /// Registers `value` under the next object id and returns it.
+    fn object(&mut self, value: Value) -> Value {
+        self.objects.push(value.clone());
+        value
+    }
+
+    fn value(&mut self) -> Result<Value, VmError> {
+        if self.depth == MAX_DEPTH {
+            return Err(self.error());
+        }
+        self.depth += 1;
+        let out = self.nested_value();
+        self.depth -= 1;
+        out
+    }
+
+    fn nested_value(&mut self) -> Result<Value, VmError> {
+        let start = self.pos;
+        let tag = self.byte()?;
+        if tag == BACK_REF {
+            let id = self.uint()?;
+            return self.objects.get(id).cloned().ok_or(VmError::Decode(start));
+        }
+        let code = tag == ValueType::Coroutine as u8 || tag == ValueType::Function as u8;
+        if code && self.natives.is_none() {
+            return Err(VmError::Decode(start));
+        }
+        let out = match tag {
+            t if t == ValueType::None as u8 => Value::None,
+            t if t == ValueType::Bool as u8 => Value::Bool(self.flag()?),
+            t if t == ValueType::Integer as u8 => Value::Integer(self.int()?),
+            t if t == ValueType::Real as u8 => {
+                let t = self.take(8)?;
+                Value::Real(f64::from_le_bytes(<[u8; 8]>::try_from(t).unwrap()))
+            },
+            t if t == ValueType::Char as u8 => {
+                let c = u32::try_from(self.uint()?).ok().and_then(char::from_u32);
+                Value::Char(c.ok_or(VmError::Decode(start + 1))?)
+            },
+            t if t == ValueType::List as u8 => {
+                let list = List::from_vec(vec![]);
+                self.pending.push_back(Shell::List(list.clone()));
+                self.object(Value::List(list))
+            },
+            t if t == ValueType::ListWeak as u8 => match self.flag()? {
+                true => Value::ListWeak(self.list()?.downgrade()),
+                false => Value::ListWeak(ListWeak(Weak::new())),
+            },
+            t if t == ValueType::Bytes as u8 => {
+                let t = self.raw()?;
+                self.object(Value::Bytes(Bytes::from_vec(t.to_vec())))
+            },
+            t if t == ValueType::BytesBuffer as u8 => {
+                let t = self.raw()?;
+                self.object(Value::BytesBuffer(BytesBuffer::from_vec(t.to_vec())))
+            },
+            t if t == ValueType::StringValue as u8 => {
+                let s = self.string()?;
+                self.object(Value::StringValue(StringValue::from_string(s)))
+            },
+            t if t == ValueType::StringBuffer as u8 => {
+                let s = self.string()?;
+                self.object(Value::StringBuffer(StringBuffer::from_string(s)))
+            },
+            t if t == ValueType::Range as u8 => {
+                let (a, b, c) = (self.int()?, self.int()?, self.int()?);
+                Value::Range(Range::new(a, b, c).ok_or_else(|| self.error())?)
+            },
+            t if t == ValueType::Iter as u8 => {
+                // nothing inside an iterator can refer back to it, so the id
+                // can be claimed before the iterator exists
+                let iterable = [
+                    BACK_REF,
+                    ValueType::List as u8,
+                    ValueType::Range as u8,
+                    ValueType::Bytes as u8,
+                    ValueType::BytesBuffer as u8,
+                    ValueType::StringValue as u8,
+                    ValueType::StringBuffer as u8,
+                ];
+                if !iterable.contains(self.bytes.get(self.pos).ok_or_else(|| self.error())?) {
+                    return Err(self.error());
+                }
+                let id = self.objects.len();
+                self.objects.push(Value::None);
+                let source = self.value()?;
+                let position = self.uint()?;
+                if Iter::new(source.clone()).is_none() || matches!(source, Value::Iter(_)) {
+                    return Err(VmError::Decode(start));
+                }
+                self.objects[id] = Value::Iter(Iter::from_state(source, position));
+                self.objects[id].clone()
+            },
+            t if t == ValueType::Coroutine as u8 => {
+                let co = Coroutine::empty(self.flag()?);
+                self.pending.push_back(Shell::Coroutine(co.clone()));
+                self.object(Value::Coroutine(co))
+            },
+            t if t == ValueType::Function as u8 => {
+                let id = self.objects.len();
+                self.objects.push(Value::None);
+                let f = Value::Function(Rc::new(self.function()?));
+                self.objects[id] = f.clone();
+                f
+            },
+            t if t == ValueType::NativeFn as u8
+                || t == ValueType::NativeFunction as u8
+                || t == ValueType::Unknown as u8 => {
+                let native = self.natives.unwrap_or_default().get(self.uint()?)
+                    .filter(|n| n.get_type() as u8 == tag)
+                    .ok_or(VmError::Decode(start))?;
+                native.clone()
+            },
+            _ => return Err(VmError::Decode(start)),
+        };
+        Ok(out)
+    }
+
+    fn list(&mut self) -> Result<List, VmError> {
+        let start = self.pos;
+        match self.value()? {
+            Value::List(l) => Ok(l),
+            _ => Err(VmError::Decode(start)),
+        }
+    }
+
+    fn function(&mut self) -> Result<Function, VmError> {
+        let name = match self.flag()? {
+            true => Some(self.string()?),
+            false => None,
+        };
+        let mut lines = vec![];
+        for _ in 0..self.uint()? {
+            let offset = self.uint()?;
+            let line = u32::try_from(self.uint()?).map_err(|_| self.error())?;
+            lines.push((offset, line));
+        }
+        let module = self.list()?;
+        let start = self.pos;
+        let bytecode = match self.value()? {
+            Value::Bytes(b) => b,
+            _ => return Err(VmError::Decode(start)),
+        };
+        let mut function = Function::new(module, bytecode);
+        function.name = name;
+        function.lines = lines;
+        Ok(function)
+    }
+
+    fn values(&mut self) -> Result<Vec<Value>, VmError> {
+        let mut out = vec![];
+        for _ in 0..self.uint()? {
+            out.push(self.value()?);
+        }
+        Ok(out)
+    }
+
+    fn call_stack(&mut self) -> Result<CallStack, VmError> {
+        let coroutine = self.flag()?;
+        let mut frames = vec![];
+        for _ in 0..self.uint()? {
+            let start = self.pos;
+            let function = match self.value()? {
+                Value::Function(f) => f,
+                _ => return Err(VmError::Decode(start)),
+            };
+            let cursor = self.uint()?;
+            let stack = self.values()?;
+            let local = self.values()?;
+            frames.push(CallFrame::from_parts(function, stack, local, cursor));
+        }
+        let yielded = match self.flag()? {
+            true => Some(self.value()?),
+            false => None,
+        };
+        Ok(CallStack::from_parts(frames, coroutine, yielded))
+    }
+
+    /// Fills in the containers read so far, then checks nothing follows.
+    fn finish(&mut self) -> Result<(), VmError> {
+        while let Some(shell) = self.pending.pop_front() {
+            match shell {
+                Shell::List(l) => {
+                    let items = self.values()?;
+                    l.append(items);
+                },
+                Shell::Coroutine(co) => if self.flag()? {
+                    let stack = self.call_stack()?;
+                    co.put_stack(stack);
+                },
+            }
+        }
+        if self.pos != self.bytes.len() {
+            return Err(self.error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::{restore, save, MAX_DEPTH};
+    use crate::datamodel::{Bytes, Function, List, Range, StringBuffer, Value, ValueType};
+    use crate::machine::{CallStack, Vm};
+    use crate::operation::Operation;
+    use crate::test_util::{function, string};
+    use crate::VmError;
+
+    fn square(args: Vec<Value>) -> Result<Value, VmError> {
+        match args[0] {
+            Value::Integer(i) => Ok(Value::Integer(i * i)),
+            _ => Err(VmError::Type(args[0].get_type(), 0)),
+        }
+    }
+
+    /// Collects the squares of a generator's output into a list holding
+    /// itself, returning the list and a weak reference to it.
+    fn script() -> Rc<Function> {
+        let gen = function(List::from_vec(vec![]), &[
+            Operation::LiteralInteger(0),
+            Operation::FrameLocalStore(2),
+            // loop: 2
+            Operation::FrameLocalLoad(2),
+            Operation::FrameLocalLoad(1),
+            Operation::Cmp,
+            Operation::JumpNeg(7),
+            Operation::Jump(15),
+            // body: 7
+            Operation::FrameLocalLoad(2),
+            Operation::Yield,
+            Operation::FrameStackPop,
+            Operation::FrameLocalLoad(2),
+            Operation::LiteralInteger(1),
+            Operation::Add,
+            Operation::FrameLocalStore(2),
+            Operation::Jump(2),
+            // done: 15
+            Operation::LiteralNone,
+            Operation::Return,
+        ]);
+        function(List::from_vec(vec![Value::NativeFn(square), Value::Function(gen)]), &[
+            Operation::ListCreate,
+            Operation::FrameLocalStore(2),
+            Operation::FrameLocalLoad(2),
+            Operation::FrameLocalLoad(2),
+            Operation::ListPush,
+            Operation::FrameLocalLoad(2),
+            Operation::ListDowngrade,
+            Operation::FrameLocalStore(4),
+            Operation::FrameLocalLoad(1),
+            Operation::FrameLocalLoad(0),
+            Operation::LiteralInteger(1),
+            Operation::SeqGet,
+            Operation::CoroutineNew(1),
+            Operation::IterNew,
+            // loop: 14
+            Operation::ForIter(24),
+            Operation::FrameLocalStore(5),
+            Operation::FrameLocalLoad(2),
+            Operation::FrameLocalLoad(5),
+            Operation::FrameLocalLoad(0),
+            Operation::LiteralInteger(0),
+            Operation::SeqGet,
+            Operation::Call(1),
+            Operation::ListPush,
+            Operation::Jump(14),
+            // done: 24
+            Operation::ListCreate,
+            Operation::FrameLocalStore(6),
+            Operation::FrameLocalLoad(6),
+            Operation::FrameLocalLoad(2),
+            Operation::ListPush,
+            Operation::FrameLocalLoad(6),
+            Operation::FrameLocalLoad(4),
+            Operation::ListPush,
+            Operation::FrameLocalLoad(6),
+            Operation::Return,
+        ])
+    }
+
+    #[test]
+    fn resume_from_any_step() {
+        let natives = [Value::NativeFn(square)];
+        let f = script();
+        let expected = Vm::new().call(&Value::Function(f.clone()), &[Value::Integer(4)]).unwrap();
+        assert_eq!(expected.to_string(), "[[[...], 0, 1, 4, 9], <weak [[...], 0, 1, 4, 9]>]");
+
+        let mut step = 0;
+        loop {
+            let mut vm = Vm::new();
+            let mut stack = CallStack::new(f.clone(), vec![Value::Integer(4)]);
+            for _ in 0..step {
+                assert!(stack.step(&mut vm).unwrap().is_none());
+            }
+            let bytes = save(&stack, &natives).unwrap();
+            let mut restored = restore(&bytes, &natives).unwrap();
+            assert_eq!(restored.depth(), stack.depth());
+            let out = restored.run(&mut Vm::new()).unwrap();
+            assert_eq!(out.to_string(), expected.to_string());
+            if stack.step(&mut vm).unwrap().is_some() {
+                break;
+            }
+            step += 1;
+        }
+        assert!(step > 50);
+    }
+
+    #[test]
+    fn sharing_and_cycles() {
+        let list = List::from_vec(vec![]);
+        list.push(Value::List(list.clone()));
+        let bytes = Value::Bytes(Bytes::from_vec(vec![1, 2]));
+        let f = function(List::from_vec(vec![]), &[Operation::Return]);
+        f.module.push(Value::Function(f.clone()));
+        let stack = CallStack::new(f, vec![
+            Value::List(list.clone()),
+            Value::ListWeak(list.downgrade()),
+            bytes.clone(),
+            bytes,
+            Value::ListWeak(List::from_vec(vec![]).downgrade()),
+        ]);
+        let restored = restore(&save(&stack, &[]).unwrap(), &[]).unwrap();
+        let frame = &restored.get_frames()[0];
+        let locals = frame.get_locals();
+        let list = match &locals[1] {
+            Value::List(l) => l.clone(),
+            _ => panic!(),
+        };
+        assert!(matches!(list.get(0), Some(Value::List(t)) if Rc::ptr_eq(&t.0, &list.0)));
+        assert!(matches!(&locals[2], Value::ListWeak(w) if Rc::ptr_eq(&w.upgrade().unwrap().0, &list.0)));
+        assert!(matches!((&locals[3], &locals[4]), (Value::Bytes(a), Value::Bytes(b)) if Rc::ptr_eq(&a.0, &b.0)));
+        assert!(matches!(&locals[5], Value::ListWeak(w) if w.upgrade().is_none()));
+        let f = frame.get_function();
+        assert!(matches!(f.module.get(0), Some(Value::Function(t)) if Rc::ptr_eq(&t, f)));
+        assert!(matches!(&locals[0], Value::List(m) if Rc::ptr_eq(&m.0, &f.module.0)));
+        // break the function <-> module cycles
+        f.module.resize(0);
+        stack.get_frames()[0].get_function().module.resize(0);
+    }
+
+    #[test]
+    fn natives_and_bad_input() {
+        let stack = CallStack::new(script(), vec![Value::Integer(1)]);
+        assert!(matches!(save(&stack, &[]), Err(VmError::Serialize(ValueType::NativeFn))));
+        let bytes = save(&stack, &[Value::NativeFn(square)]).unwrap();
+        assert!(matches!(restore(&bytes, &[]), Err(VmError::Decode(_))));
+        assert!(matches!(restore(&bytes[..bytes.len() - 1], &[Value::NativeFn(square)]), Err(VmError::Decode(_))));
+        assert!(matches!(restore(b"GLSN\x02", &[]), Err(VmError::Decode(0))));
+    }
+
+    #[test]
+    fn restored_cursor_fails_alike() {
+        // a jump hidden in the immediate of LIT_INT, landing before the start
+        let f = function(List::from_vec(vec![]), &[
+            Operation::None,
+            Operation::LiteralInteger(0x16ff_ffff_0000_0000),
+            Operation::Return,
+        ]);
+        let mut stack = CallStack::new(f, vec![]);
+        let before = save(&stack, &[]).unwrap();
+        stack.step(&mut Vm::new()).unwrap();
+        let mut bytes = save(&stack, &[]).unwrap();
+        let at = (0..bytes.len()).find(|&i| bytes[i] != before[i]).unwrap();
+        assert_eq!((before[at], bytes[at]), (0, 1));
+        bytes[at] = 2;
+
+        let mut run = restore(&bytes, &[]).unwrap();
+        let mut step = restore(&bytes, &[]).unwrap();
+        let mut vm = Vm::new();
+        let stepped = loop {
+            match step.step(&mut vm) {
+                Ok(None) => (),
+                out => break out,
+            }
+        };
+        assert!(matches!(run.run(&mut vm), Err(VmError::BytecodeRead(7))));
+        assert!(matches!(stepped, Err(VmError::BytecodeRead(7))));
+        for stack in [&run, &step].iter() {
+            let frame = &stack.get_frames()[0];
+            assert_eq!(frame.get_cursor(), 7);
+            assert!(frame.get_stack().is_empty());
+        }
+    }
+
+    #[test]
+    fn deep_data() {
+        // weak lists of weak lists, nested far past MAX_DEPTH
+        let weak = [ValueType::ListWeak as u8, 1].repeat(200_000);
+        let bytes = [&b"GLVL\x01"[..], &weak].concat();
+        assert!(matches!(Value::decode(&bytes), Err(VmError::Decode(t)) if t == 5 + 2 * MAX_DEPTH));
+
+        // an iterator over something that cannot be iterated fails before
+        // reading it
+        let bytes = [&b"GLVL\x01"[..], &[ValueType::Iter as u8], &weak].concat();
+        assert!(matches!(Value::decode(&bytes), Err(VmError::Decode(6))));
+    }
+
+    #[test]
+    fn deep_snapshot() {
+        // one frame whose function is a weak list of a weak list of ...
+        let weak = [ValueType::ListWeak as u8, 1].repeat(200_000);
+        let bytes = [&b"GLSN\x01\x00\x01"[..], &weak].concat();
+        assert!(matches!(restore(&bytes, &[]), Err(VmError::Decode(t)) if t == 7 + 2 * MAX_DEPTH));
+        // functions whose module is another function, and so on
+        let f = [ValueType::Function as u8, 0, 0].repeat(200_000);
+        let bytes = [&b"GLSN\x01\x00\x01"[..], &f].concat();
+        assert!(matches!(restore(&bytes, &[]), Err(VmError::Decode(t)) if t == 7 + 3 * MAX_DEPTH));
+    }
+
+    #[test]
+    fn encode_data() {
+        let shared = string("hé");
+        let list = List::from_vec(vec![
+            Value::None,
+            Value::Bool(true),
+            Value::Integer(-300),
+            Value::Real(f64::NAN),
+            Value::Char('\u{1f600}'),
+            Value::Range(Range::new(0, 10, 3).unwrap()),
+            Value::Bytes(Bytes::from_vec(vec![0, 255])),
+            Value::StringBuffer(StringBuffer::from_string("buf".to_string())),
+            shared.clone(),
+            shared,
+        ]);
+        list.push(Value::List(list.clone()));
+        let bytes = Value::List(list.clone()).encode().unwrap();
+        let out = match Value::decode(&bytes).unwrap() {
+            Value::List(l) => l,
+            _ => panic!(),
+        };
+        assert_eq!(Value::List(out.clone()).to_string(), Value::List(list.clone()).to_string());
+        assert!(matches!((out.get(8), out.get(9)),
+            (Some(Value::StringValue(a)), Some(Value::StringValue(b))) if Rc::ptr_eq(&a.as_bytes().0, &b.as_bytes().0)));
+        assert!(matches!(out.get(10), Some(Value::List(t)) if Rc::ptr_eq(&t.0, &out.0)));
+        list.resize(0);
+        out.resize(0);
+
+        assert_eq!(Value::Integer(5).encode().unwrap(), b"GLVL\x01\x02\x05");
+        assert!(matches!(Value::decode(b"GLVL\x01\x02\x05\x00"), Err(VmError::Decode(7))));
+        assert!(matches!(Value::decode(b"GLVL\x01\x05\x01"), Err(VmError::Decode(7))));
+    }
+
+    #[test]
+    fn encode_rejects_code() {
+        let f = function(List::from_vec(vec![]), &[Operation::Return]);
+        let list = Value::List(List::from_vec(vec![Value::Integer(1), Value::Function(f)]));
+        assert!(matches!(list.encode(), Err(VmError::Serialize(ValueType::Function))));
+        assert!(matches!(Value::NativeFn(square).encode(), Err(VmError::Serialize(ValueType::NativeFn))));
+
+        // snapshots are not plain data
+        let stack = CallStack::new(script(), vec![]);
+        let bytes = save(&stack, &[Value::NativeFn(square)]).unwrap();
+        assert!(matches!(Value::decode(&bytes), Err(VmError::Decode(0))));
+    }
+}
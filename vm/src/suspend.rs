@@ -0,0 +1,165 @@
+use crate::datamodel::Value;
+use crate::machine::{CallStack, Vm};
+use crate::VmError;
+
+/// Outcome of `CallStack::run_async` and `Suspended::resume`.
+pub enum Poll {
+    Ready(Value),
+    /// What the pending native waits on, and the state to resume with its
+    /// result.
+    Pending(Value, Suspended),
+}
+
+/// A `CallStack` stopped on a pending native function. It holds no borrow
+/// of the `Vm`, so the host can keep it in an event loop and run other code
+/// meanwhile.
+pub struct Suspended {
+    stack: CallStack,
+}
+
+impl Suspended {
+    pub(crate) fn new(stack: CallStack) -> Suspended {
+        Suspended { stack }
+    }
+
+    pub fn get_stack(&self) -> &CallStack {
+        &self.stack
+    }
+
+    /// Continues with `value` as the result of the pending native.
+    pub fn resume(mut self, vm: &mut Vm, value: Value) -> Result<Poll, VmError> {
+        match self.stack.deliver(value) {
+            Some(out) => Ok(Poll::Ready(out)),
+            None => self.stack.run_async(vm),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+
+    use super::Poll;
+    use crate::datamodel::{Function, List, NativeFunction, Value};
+    use crate::machine::{CallStack, Vm};
+    use crate::operation::Operation;
+    use crate::test_util::{function, string};
+    use crate::VmError;
+
+    /// Reads queued on a fake device, answered by the event loop in order.
+    #[derive(Default)]
+    struct FakeIo {
+        waiting: VecDeque<(i64, String)>,
+        next_id: i64,
+    }
+
+    fn read() -> Value {
+        Value::NativeFunction(NativeFunction::new(|ctx, args| {
+            let path = args[0].to_string();
+            let io: &mut FakeIo = ctx.get_host_data_mut().unwrap();
+            io.next_id += 1;
+            io.waiting.push_back((io.next_id, path));
+            Err(VmError::Pending(Value::Integer(io.next_id)))
+        }))
+    }
+
+    /// `read(a) + read(b)`, each read made by a helper function, the second
+    /// one through a tail call.
+    fn script() -> Rc<Function> {
+        let module = List::from_vec(vec![read()]);
+        let fetch = function(module.clone(), &[
+            Operation::FrameLocalLoad(1),
+            Operation::FrameLocalLoad(0),
+            Operation::LiteralInteger(0),
+            Operation::SeqGet,
+            Operation::TailCall(1),
+            Operation::Return,
+        ]);
+        module.push(Value::Function(fetch));
+        function(module, &[
+            Operation::FrameLocalLoad(1),
+            Operation::FrameLocalLoad(0),
+            Operation::LiteralInteger(1),
+            Operation::SeqGet,
+            Operation::Call(1),
+            Operation::FrameLocalLoad(2),
+            Operation::FrameLocalLoad(0),
+            Operation::LiteralInteger(0),
+            Operation::SeqGet,
+            Operation::Call(1),
+            Operation::StringConcat,
+            Operation::Return,
+        ])
+    }
+
+    #[test]
+    fn event_loop() {
+        let mut vm = Vm::new();
+        vm.set_host_data(FakeIo::default());
+        let stack = CallStack::new(script(), vec![string("a"), string("b")]);
+        let mut poll = stack.run_async(&mut vm).unwrap();
+        let mut served = vec![];
+        let out = loop {
+            let (waiting, state) = match poll {
+                Poll::Ready(out) => break out,
+                Poll::Pending(waiting, state) => (waiting, state),
+            };
+            let (id, path) = vm.get_host_data_mut::<FakeIo>().unwrap().waiting.pop_front().unwrap();
+            assert!(matches!(waiting, Value::Integer(i) if i == id));
+            served.push(path.clone());
+
+            // another script can use the vm while this one waits
+            let other = function(List::from_vec(vec![]), &[Operation::LiteralInteger(7), Operation::Return]);
+            assert!(matches!(vm.call(&Value::Function(other), &[]), Ok(Value::Integer(7))));
+
+            poll = state.resume(&mut vm, string(&format!("<{}>", path))).unwrap();
+        };
+        assert_eq!(served, ["a", "b"]);
+        assert_eq!(out.to_string(), "<a><b>");
+    }
+
+    #[test]
+    fn pending_in_top_level_tail_call() {
+        let main = function(List::from_vec(vec![read()]), &[
+            Operation::FrameLocalLoad(1),
+            Operation::FrameLocalLoad(0),
+            Operation::LiteralInteger(0),
+            Operation::SeqGet,
+            Operation::TailCall(1),
+        ]);
+        let mut vm = Vm::new();
+        vm.set_host_data(FakeIo::default());
+        let state = match CallStack::new(main, vec![string("x")]).run_async(&mut vm) {
+            Ok(Poll::Pending(_, state)) => state,
+            _ => panic!(),
+        };
+        assert_eq!(state.get_stack().depth(), 0);
+        assert!(matches!(state.resume(&mut vm, Value::Integer(3)), Ok(Poll::Ready(Value::Integer(3)))));
+    }
+
+    #[test]
+    fn pending_behind_host_frames() {
+        let mut vm = Vm::new();
+        vm.set_host_data(FakeIo::default());
+        let out = vm.call(&Value::Function(script()), &[string("a"), string("b")]);
+        assert!(matches!(out, Err(VmError::Suspend)));
+
+        // a native calling back into a guest that waits cannot be suspended
+        let relay = Value::NativeFunction(NativeFunction::new(|ctx, args| ctx.call(&args[0], &args[1..])));
+        let main = function(List::from_vec(vec![relay, Value::Function(script())]), &[
+            Operation::FrameLocalLoad(0),
+            Operation::LiteralInteger(1),
+            Operation::SeqGet,
+            Operation::FrameLocalLoad(1),
+            Operation::FrameLocalLoad(1),
+            Operation::FrameLocalLoad(0),
+            Operation::LiteralInteger(0),
+            Operation::SeqGet,
+            Operation::Call(3),
+            Operation::Return,
+        ]);
+        let out = CallStack::new(main, vec![string("a")]).run_async(&mut vm);
+        assert!(matches!(out, Err(VmError::Suspend)));
+    }
+}
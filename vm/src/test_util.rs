@@ -0,0 +1,33 @@
+//! Helpers shared by the unit tests of several modules.
+
+#[cfg(feature = "trace")]
+use std::cell::RefCell;
+#[cfg(feature = "trace")]
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use crate::datamodel::{Bytes, Function, List, StringValue, Value};
+use crate::operation::{assemble, Operation};
+
+pub(crate) fn function(module: List, ops: &[Operation]) -> Rc<Function> {
+    Rc::new(Function::new(module, Bytes::from_vec(assemble(ops).unwrap())))
+}
+
+pub(crate) fn string(s: &str) -> Value {
+    Value::StringValue(StringValue::from_string(s.to_string()))
+}
+
+/// Writer whose output stays readable through the shared buffer.
+#[cfg(feature = "trace")]
+pub(crate) struct Shared(pub Rc<RefCell<Vec<u8>>>);
+
+#[cfg(feature = "trace")]
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,132 @@
+use std::fmt::Write as _;
+use std::io::Write;
+
+use crate::datamodel::Value;
+use crate::operation::Operation;
+
+/// One executed instruction. `after` is the stack of the frame that ran the
+/// instruction, which is empty once that frame has returned. `depth` also
+/// counts the frames below a native that called back into the VM.
+pub struct TraceEvent<'a> {
+    pub depth: usize,
+    pub cursor: usize,
+    pub operation: &'a Operation,
+    pub before: &'a [Value],
+    pub after: &'a [Value],
+}
+
+pub trait Tracer {
+    fn trace(&mut self, event: &TraceEvent);
+}
+
+/// Writes one line per instruction:
+/// `<depth> <cursor> <operation> [<stack before>] -> [<stack after>]`.
+pub struct TextTracer<W: Write> {
+    out: W,
+}
+
+impl<W: Write> TextTracer<W> {
+    pub fn new(out: W) -> TextTracer<W> {
+        TextTracer { out }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl<W: Write> Tracer for TextTracer<W> {
+    fn trace(&mut self, event: &TraceEvent) {
+        let mut line = String::new();
+        write!(line, "{} {} {:?} [", event.depth, event.cursor, event.operation).unwrap();
+        write_stack(&mut line, event.before);
+        line.push_str("] -> [");
+        write_stack(&mut line, event.after);
+        line.push(']');
+        // a broken log must not abort the guest program
+        let _ = writeln!(self.out, "{}", line);
+    }
+}
+
+fn write_stack(out: &mut String, stack: &[Value]) {
+    for (i, val) in stack.iter().enumerate() {
+        if i > 0 {
+            out.push_str(", ");
+        }
+        write!(out, "{:?}", val).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::TextTracer;
+    use crate::datamodel::{List, NativeFunction, Value};
+    use crate::machine::{CallStack, Vm};
+    use crate::operation::Operation;
+    use crate::test_util::{Shared, function};
+
+    #[test]
+    fn text_log() {
+        let f = function(List::from_vec(vec![]), &[
+            Operation::LiteralInteger(40),
+            Operation::FrameLocalLoad(1),
+            Operation::Add,
+            Operation::Return,
+        ]);
+        let mut stack = CallStack::new(f, vec![Value::Integer(2)]);
+        let buf = Rc::new(RefCell::new(vec![]));
+        let mut vm = Vm::new();
+        vm.set_tracer(Some(Box::new(TextTracer::new(Shared(buf.clone())))));
+        stack.run(&mut vm).unwrap();
+        let log = String::from_utf8(buf.borrow().clone()).unwrap();
+        assert_eq!(log, "\
+1 0 LiteralInteger(40) [] -> [40]
+1 9 FrameLocalLoad(1) [40] -> [40, 2]
+1 11 Add [40, 2] -> [42]
+1 12 Return [42] -> []
+");
+    }
+
+    #[test]
+    fn native_callback() {
+        let call = NativeFunction::new(|ctx, args| ctx.call(&args[0], &args[1..]));
+        let module = List::from_vec(vec![Value::NativeFunction(call)]);
+        let double = function(module.clone(), &[
+            Operation::FrameLocalLoad(1),
+            Operation::FrameLocalLoad(1),
+            Operation::Add,
+            Operation::Return,
+        ]);
+        module.push(Value::Function(double));
+        let main = function(module, &[
+            Operation::FrameLocalLoad(0),
+            Operation::LiteralInteger(1),
+            Operation::SeqGet,
+            Operation::LiteralInteger(4),
+            Operation::FrameLocalLoad(0),
+            Operation::LiteralInteger(0),
+            Operation::SeqGet,
+            Operation::Call(2),
+            Operation::Return,
+        ]);
+        let buf = Rc::new(RefCell::new(vec![]));
+        let mut vm = Vm::new();
+        vm.set_tracer(Some(Box::new(TextTracer::new(Shared(buf.clone())))));
+        vm.call(&Value::Function(main), &[]).unwrap();
+        let log = String::from_utf8(buf.borrow().clone()).unwrap();
+        let lines: Vec<_> = log.lines().collect();
+        assert_eq!(lines.len(), 13);
+        assert_eq!(&lines[6..], [
+            "1 32 SeqGet [<fn>, 4, [<native fn>, <fn>], 0] -> [<fn>, 4, <native fn>]",
+            "2 0 FrameLocalLoad(1) [] -> [4]",
+            "2 2 FrameLocalLoad(1) [4] -> [4, 4]",
+            "2 4 Add [4, 4] -> [8]",
+            "2 5 Return [8] -> []",
+            "1 33 Call(2) [<fn>, 4, <native fn>] -> [8]",
+            "1 35 Return [8] -> []",
+        ]);
+    }
+}
@@ -0,0 +1,239 @@
+use crate::VmError;
+
+/// The char starting at byte offset `index`.
+pub fn char_at(s: &str, index: i64) -> Result<char, VmError> {
+    if index < 0 || index as usize >= s.len() {
+        return Err(VmError::IndexRead(index));
+    }
+    if !s.is_char_boundary(index as usize) {
+        return Err(VmError::CharBoundary(index));
+    }
+    Ok(s[index as usize..].chars().next().unwrap())
+}
+
+/// The substring between byte offsets `start` and `end`, both of which must
+/// fall on char boundaries.
+pub fn slice(s: &str, start: i64, end: i64) -> Result<&str, VmError> {
+    if start < 0 || start > end || end as usize > s.len() {
+        return Err(VmError::SliceRead(start, end));
+    }
+    for i in [start, end] {
+        if !s.is_char_boundary(i as usize) {
+            return Err(VmError::CharBoundary(i));
+        }
+    }
+    Ok(&s[start as usize..end as usize])
+}
+
+/// The char at byte offset `index` and the offset of the one after it, or
+/// `None` at the end of the string.
+pub fn next_char(s: &str, index: i64) -> Result<Option<(char, usize)>, VmError> {
+    if index as usize == s.len() {
+        return Ok(None);
+    }
+    let c = char_at(s, index)?;
+    Ok(Some((c, index as usize + c.len_utf8())))
+}
+
+/// Byte offset where the grapheme cluster starting at `start` ends.
+///
+/// This approximates the extended grapheme clusters of UAX #29: CR LF,
+/// combining marks, variation selectors, emoji modifiers and tags, ZWJ
+/// sequences and regional indicator pairs stay together. Hangul syllables
+/// built from conjoining jamo and Indic conjuncts are not joined.
+pub fn next_grapheme(s: &str, start: usize) -> usize {
+    let mut chars = s[start..].char_indices().peekable();
+    let first = match chars.next() {
+        Some((_, c)) => c,
+        None => return start,
+    };
+    let mut end = start + first.len_utf8();
+    if first == '\r' {
+        if let Some((_, '\n')) = chars.peek() {
+            return end + 1;
+        }
+        return end;
+    }
+    if is_control(first) {
+        return end;
+    }
+    if is_regional_indicator(first) {
+        if let Some(&(i, c)) = chars.peek() {
+            if is_regional_indicator(c) {
+                chars.next();
+                end = start + i + c.len_utf8();
+            }
+        }
+    }
+    while let Some(&(i, c)) = chars.peek() {
+        if c == '\u{200d}' {
+            // the joiner glues the next char, if any, onto the cluster
+            chars.next();
+            end = start + i + c.len_utf8();
+            if let Some((i, c)) = chars.next() {
+                end = start + i + c.len_utf8();
+            }
+        } else if is_extend(c) {
+            chars.next();
+            end = start + i + c.len_utf8();
+        } else {
+            break;
+        }
+    }
+    end
+}
+
+/// Splits `s` into grapheme clusters, see `next_grapheme`.
+pub fn graphemes(s: &str) -> Vec<&str> {
+    let mut out = vec![];
+    let mut start = 0;
+    while start < s.len() {
+        let end = next_grapheme(s, start);
+        out.push(&s[start..end]);
+        start = end;
+    }
+    out
+}
+
+fn is_control(c: char) -> bool {
+    c.is_control() && c != '\u{200d}'
+}
+
+fn is_regional_indicator(c: char) -> bool {
+    ('\u{1f1e6}'..='\u{1f1ff}').contains(&c)
+}
+
+fn is_extend(c: char) -> bool {
+    matches!(c,
+        '\u{0300}'..='\u{036f}'
+        | '\u{0483}'..='\u{0489}'
+        | '\u{0591}'..='\u{05bd}'
+        | '\u{0610}'..='\u{061a}'
+        | '\u{064b}'..='\u{065f}'
+        | '\u{0900}'..='\u{0903}'
+        | '\u{093a}'..='\u{094f}'
+        | '\u{0e31}' | '\u{0e34}'..='\u{0e3a}' | '\u{0e47}'..='\u{0e4e}'
+        | '\u{1ab0}'..='\u{1aff}'
+        | '\u{1dc0}'..='\u{1dff}'
+        | '\u{20d0}'..='\u{20ff}'
+        | '\u{302a}'..='\u{302f}'
+        | '\u{3099}'..='\u{309a}'
+        | '\u{fe00}'..='\u{fe0f}'
+        | '\u{fe20}'..='\u{fe2f}'
+        | '\u{1f3fb}'..='\u{1f3ff}'
+        | '\u{e0020}'..='\u{e007f}'
+        | '\u{e0100}'..='\u{e01ef}')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{graphemes, slice};
+    use crate::datamodel::{List, StringBuffer, StringValue, Value};
+    use crate::machine::Vm;
+    use crate::operation::Operation;
+    use crate::test_util::{function, string};
+    use crate::VmError;
+
+    #[test]
+    fn grapheme_clusters() {
+        let s = "e\u{301}a\r\n\u{1f1eb}\u{1f1f7}\u{1f469}\u{200d}\u{1f4bb}\u{1f44d}\u{1f3fd}!";
+        assert_eq!(graphemes(s), vec![
+            "e\u{301}", "a", "\r\n", "\u{1f1eb}\u{1f1f7}",
+            "\u{1f469}\u{200d}\u{1f4bb}", "\u{1f44d}\u{1f3fd}", "!",
+        ]);
+        assert_eq!(StringValue::from_string(s.to_string()).char_count(), 13);
+        let buffer = StringBuffer::from_string(s.to_string());
+        assert_eq!(buffer.char_count(), 13);
+        assert_eq!(buffer.grapheme_count(), 7);
+    }
+
+    #[test]
+    fn char_at_checks_boundaries() {
+        let value = StringValue::from_string("héllo".to_string());
+        let buffer = StringBuffer::from_string("héllo".to_string());
+        assert!(matches!(value.get_char_at(1), Ok('é')));
+        assert!(matches!(buffer.get_char_at(3), Ok('l')));
+        assert!(matches!(value.get_char_at(2), Err(VmError::CharBoundary(2))));
+        assert!(matches!(buffer.get_char_at(2), Err(VmError::CharBoundary(2))));
+        assert!(matches!(value.get_char_at(6), Err(VmError::IndexRead(6))));
+        assert!(matches!(buffer.get_char_at(-1), Err(VmError::IndexRead(-1))));
+
+        // STR_CHAR_AT fails the same way as SEQ_GET
+        for op in [Operation::StringGetCharAt, Operation::SeqGet] {
+            let f = Value::Function(function(List::from_vec(vec![]), &[
+                Operation::FrameLocalLoad(1),
+                Operation::FrameLocalLoad(2),
+                op,
+                Operation::Return,
+            ]));
+            let mut vm = Vm::new();
+            let at = |vm: &mut Vm, i| vm.call(&f, &[string("héllo"), Value::Integer(i)]);
+            assert!(matches!(at(&mut vm, 1), Ok(Value::Char('é'))));
+            assert!(matches!(at(&mut vm, 2), Err(VmError::CharBoundary(2))));
+            assert!(matches!(at(&mut vm, 6), Err(VmError::IndexRead(6))));
+        }
+    }
+
+    #[test]
+    fn slicing_checks_boundaries() {
+        assert!(matches!(slice("héllo", 1, 3), Ok("é")));
+        assert!(matches!(slice("héllo", 2, 3), Err(VmError::CharBoundary(2))));
+        assert!(matches!(slice("héllo", 3, 9), Err(VmError::SliceRead(3, 9))));
+
+        let f = Value::Function(function(List::from_vec(vec![]), &[
+            Operation::FrameLocalLoad(1),
+            Operation::LiteralInteger(1),
+            Operation::LiteralInteger(3),
+            Operation::SeqGetSlice,
+            Operation::FrameLocalStore(2),
+            Operation::FrameLocalLoad(1),
+            Operation::LiteralInteger(0),
+            Operation::SeqGet,
+            Operation::CharToInt,
+            Operation::LiteralInteger(1),
+            Operation::Add,
+            Operation::IntToChar,
+            Operation::FrameLocalStore(3),
+            Operation::ListCreate,
+            Operation::FrameStackCopy,
+            Operation::FrameLocalLoad(3),
+            Operation::ListPush,
+            Operation::FrameStackCopy,
+            Operation::FrameLocalLoad(2),
+            Operation::ListPush,
+            Operation::Return,
+        ]));
+        let out = Vm::new().call(&f, &[string("héllo")]).unwrap();
+        assert_eq!(out.to_string(), "['i', \"é\"]");
+    }
+
+    #[test]
+    fn next_char_loop() {
+        // collects the code points of local 1 into a list, one STR_NEXT_CHAR
+        // per iteration
+        let f = Value::Function(function(List::from_vec(vec![]), &[
+            Operation::ListCreate,
+            Operation::FrameLocalStore(2),
+            Operation::LiteralInteger(0),
+            Operation::FrameLocalStore(3),
+            // loop: 4
+            Operation::FrameLocalLoad(1),
+            Operation::FrameLocalLoad(3),
+            Operation::StringNextChar,
+            Operation::FrameStackCopy,
+            Operation::JumpNeg(16),
+            Operation::FrameLocalStore(3),
+            Operation::CharToInt,
+            Operation::FrameLocalStore(4),
+            Operation::FrameLocalLoad(2),
+            Operation::FrameLocalLoad(4),
+            Operation::ListPush,
+            Operation::Jump(4),
+            // end: 16
+            Operation::FrameLocalLoad(2),
+            Operation::Return,
+        ]));
+        let out = Vm::new().call(&f, &[string("a\u{e9}\u{1f600}")]).unwrap();
+        assert_eq!(out.to_string(), "[97, 233, 128512]");
+    }
+}